@@ -0,0 +1,375 @@
+use tree_iterators_rs::prelude::BinaryTreeNode;
+
+use crate::{
+    compiler::{ExpressionBranch, ExpressionLeaf, ExpressionTreeNode, Operator, Statement},
+    object::Object,
+    tokenizer::{Position, Span},
+    value::Value,
+};
+
+/// Serializes parsed statements to JSON for external tools. The source is needed
+/// for the names of declared variables. The schema is:
+///
+/// - A program is an array of statements.
+/// - `{"type": "expression" | "print", "expression": <expression>}`
+/// - `{"type": "if", "condition": <expression>, "then": <statement> | null, "else": <statement> | null}`,
+///   where an empty statement is null.
+/// - `{"type": "while", "condition": <expression>, "body": <statement> | null}`
+/// - `{"type": "block", "statements": [<statement>]}`
+/// - `{"type": "break"}`
+/// - `{"type": "function", "name": "f", "params": ["a"], "body": [<statement>]}`
+/// - `{"type": "return", "value": <expression> | null}`
+/// - `{"type": "for", "initializer": <statement> | null, "condition": <expression> | null,
+///   "increment": <expression> | null, "body": <statement> | null}`
+/// - `{"type": "var", "declarations": [{"name": "a", "span": <span>, "initializer": <expression> | null}]}`
+/// - `{"type": "binary", "operator": "add", "left": <expression>, "right": <expression>, "span": <span>}`
+/// - `{"type": "unary", "operator": "negate" | "not", "operand": <expression>, "span": <span>}`
+/// - `{"type": "literal", "value": 1 | "text" | true | null, "span": <span>}`
+/// - `{"type": "variable", "name": "a", "span": <span>}`
+/// - `{"type": "call", "callee": <expression>, "arguments": [<expression>], "span": <span>}`
+/// - A span is `{"start": <position>, "end": <position>}`, where the end is exclusive and
+///   a position is `{"line": 0, "column": 1, "byte": 0}`. Lines count from 0 and columns from 1.
+pub (crate) fn to_json(source: &str, statements: &[Statement]) -> String {
+    let mut json = String::from("[");
+    for (i, statement) in statements.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write_statement(&mut json, source, statement);
+    }
+    json.push(']');
+    json
+}
+
+fn write_statement(json: &mut String, source: &str, statement: &Statement) {
+    match statement {
+        Statement::Expression { expr, .. } => {
+            json.push_str("{\"type\":\"expression\",\"expression\":");
+            write_expression(json, source, expr);
+            json.push('}');
+        }
+        Statement::Print(expr) => {
+            json.push_str("{\"type\":\"print\",\"expression\":");
+            write_expression(json, source, expr);
+            json.push('}');
+        }
+        Statement::Var(declarations) => {
+            json.push_str("{\"type\":\"var\",\"declarations\":[");
+            for (i, declaration) in declarations.iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                json.push_str("{\"name\":");
+                write_string(json, &source[declaration.name().range()]);
+                json.push_str(",\"span\":");
+                write_span(json, declaration.name().span());
+                json.push_str(",\"initializer\":");
+                match declaration.initializer() {
+                    None => json.push_str("null"),
+                    Some(initializer) => write_expression(json, source, initializer),
+                }
+                json.push('}');
+            }
+            json.push_str("]}");
+        }
+        Statement::If { condition, then_branch, else_branch } => {
+            json.push_str("{\"type\":\"if\",\"condition\":");
+            write_expression(json, source, condition);
+            for (key, branch) in [("then", then_branch), ("else", else_branch)] {
+                json.push_str(&format!(",\"{}\":", key));
+                match branch {
+                    None => json.push_str("null"),
+                    Some(branch) => write_statement(json, source, branch),
+                }
+            }
+            json.push('}');
+        }
+        Statement::While { condition, body } => {
+            json.push_str("{\"type\":\"while\",\"condition\":");
+            write_expression(json, source, condition);
+            json.push_str(",\"body\":");
+            match body {
+                None => json.push_str("null"),
+                Some(body) => write_statement(json, source, body),
+            }
+            json.push('}');
+        }
+        Statement::Break { .. } => json.push_str("{\"type\":\"break\"}"),
+        Statement::Function(declaration) => {
+            json.push_str("{\"type\":\"function\",\"name\":");
+            write_string(json, &source[declaration.name().range()]);
+            json.push_str(",\"params\":[");
+            for (i, param) in declaration.params().iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                write_string(json, &source[param.range()]);
+            }
+            json.push_str("],\"body\":[");
+            for (i, statement) in declaration.body().iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                write_statement(json, source, statement);
+            }
+            json.push_str("]}");
+        }
+        Statement::Return { value, .. } => {
+            json.push_str("{\"type\":\"return\",\"value\":");
+            match value {
+                None => json.push_str("null"),
+                Some(value) => write_expression(json, source, value),
+            }
+            json.push('}');
+        }
+        Statement::Block(statements) => {
+            json.push_str("{\"type\":\"block\",\"statements\":[");
+            for (i, statement) in statements.iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                write_statement(json, source, statement);
+            }
+            json.push_str("]}");
+        }
+        Statement::Try { body, error_name, handler } => {
+            json.push_str("{\"type\":\"try\",\"body\":[");
+            for (i, statement) in body.iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                write_statement(json, source, statement);
+            }
+            json.push_str("],\"name\":");
+            write_string(json, &source[error_name.range()]);
+            json.push_str(",\"handler\":[");
+            for (i, statement) in handler.iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                write_statement(json, source, statement);
+            }
+            json.push_str("]}");
+        }
+        Statement::For { initializer, condition, increment, body } => {
+            json.push_str("{\"type\":\"for\",\"initializer\":");
+            match initializer {
+                None => json.push_str("null"),
+                Some(initializer) => write_statement(json, source, initializer),
+            }
+            for (key, clause) in [("condition", condition), ("increment", increment)] {
+                json.push_str(&format!(",\"{}\":", key));
+                match clause {
+                    None => json.push_str("null"),
+                    Some(clause) => write_expression(json, source, clause),
+                }
+            }
+            json.push_str(",\"body\":");
+            match body {
+                None => json.push_str("null"),
+                Some(body) => write_statement(json, source, body),
+            }
+            json.push('}');
+        }
+    }
+}
+
+fn write_expression(json: &mut String, source: &str, expr: &BinaryTreeNode<ExpressionTreeNode>) {
+    match &expr.value {
+        ExpressionTreeNode::Branch(ExpressionBranch::Operator(op), span) => {
+            match (&expr.left, &expr.right) {
+                (Some(left), Some(right)) => {
+                    json.push_str("{\"type\":\"binary\",\"operator\":");
+                    write_string(json, operator_name(op));
+                    json.push_str(",\"left\":");
+                    write_expression(json, source, left);
+                    json.push_str(",\"right\":");
+                    write_expression(json, source, right);
+                }
+                (_, operand) => {
+                    json.push_str("{\"type\":\"unary\",\"operator\":");
+                    write_string(json, operator_name(op));
+                    json.push_str(",\"operand\":");
+                    match operand {
+                        None => json.push_str("null"),
+                        Some(operand) => write_expression(json, source, operand),
+                    }
+                }
+            }
+            json.push_str(",\"span\":");
+            write_span(json, *span);
+            json.push('}');
+        }
+        ExpressionTreeNode::Branch(ExpressionBranch::Call { .. }, span) => {
+            json.push_str("{\"type\":\"call\",\"callee\":");
+            write_expression(json, source, expr.left.as_ref().expect("every call to have a callee"));
+            json.push_str(",\"arguments\":[");
+            // Each argument node holds one argument and links to the next.
+            let mut argument = expr.right.as_deref();
+            let mut i = 0;
+            while let Some(node) = argument {
+                if i > 0 {
+                    json.push(',');
+                }
+                write_expression(json, source, node.left.as_ref().expect("every argument to hold an expression"));
+                argument = node.right.as_deref();
+                i += 1;
+            }
+            json.push_str("],\"span\":");
+            write_span(json, *span);
+            json.push('}');
+        }
+        ExpressionTreeNode::Branch(ExpressionBranch::Argument, _) => unreachable!("arguments to be written by their call"),
+        ExpressionTreeNode::Leaf(leaf, span) => {
+            match leaf {
+                ExpressionLeaf::Value(value) => {
+                    json.push_str("{\"type\":\"literal\",\"value\":");
+                    write_value(json, value);
+                }
+                ExpressionLeaf::Variable(name) => {
+                    json.push_str("{\"type\":\"variable\",\"name\":");
+                    write_string(json, &source[name.range()]);
+                }
+                ExpressionLeaf::Native(name) => {
+                    json.push_str("{\"type\":\"native\",\"name\":");
+                    write_string(json, name);
+                }
+                // This isn't produced by a successful parse, but it still gets a node so nothing is dropped.
+                ExpressionLeaf::Error(_) => json.push_str("{\"type\":\"error\""),
+            }
+            json.push_str(",\"span\":");
+            write_span(json, *span);
+            json.push('}');
+        }
+    }
+}
+
+/// The schema's name for each operator. These are part of the format, so they
+/// don't change when the operators are renamed in the compiler.
+fn operator_name(op: &Operator) -> &'static str {
+    match op {
+        Operator::Assignment => "assign",
+        Operator::Or => "or",
+        Operator::Coalesce => "coalesce",
+        Operator::And => "and",
+        Operator::Equal => "equal",
+        Operator::NotEqual => "not_equal",
+        Operator::Not => "not",
+        Operator::Greater => "greater",
+        Operator::GreaterEqual => "greater_equal",
+        Operator::Less => "less",
+        Operator::LessEqual => "less_equal",
+        Operator::Divide => "divide",
+        Operator::Multiply => "multiply",
+        Operator::Modulo => "modulo",
+        Operator::Add => "add",
+        Operator::Subtract => "subtract",
+        Operator::SignFlip => "negate",
+    }
+}
+
+fn write_value(json: &mut String, value: &Value) {
+    match value {
+        Value::Nil => json.push_str("null"),
+        Value::Boolean(bool) => json.push_str(&bool.to_string()),
+        Value::Number(num) => json.push_str(&num.to_string()),
+        Value::Object(obj) => {
+            match &**obj {
+                Object::String(string) => write_string(json, string),
+                // Natives and functions are only created after parsing, so no literal holds one.
+                Object::Native(_) | Object::Function(_) => write_string(json, &obj.to_string()),
+            }
+        }
+    }
+}
+
+fn write_span(json: &mut String, span: Span) {
+    json.push_str("{\"start\":");
+    write_position(json, span.start());
+    json.push_str(",\"end\":");
+    write_position(json, span.end());
+    json.push('}');
+}
+
+fn write_position(json: &mut String, position: Position) {
+    json.push_str(&format!(
+        "{{\"line\":{},\"column\":{},\"byte\":{}}}",
+        position.line(),
+        position.col(),
+        position.byte()
+    ));
+}
+
+fn write_string(json: &mut String, string: &str) {
+    json.push('"');
+    for ch in string.chars() {
+        match ch {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => json.push(ch),
+        }
+    }
+    json.push('"');
+}
+
+#[test]
+fn expressions_serialize_with_spans() {
+    let source = "1 + 2 * 3;";
+    let statements = crate::compiler::parse(source, Default::default()).expect("program to parse");
+    let span = |start: usize, end: usize| format!(
+        "{{\"start\":{{\"line\":0,\"column\":{},\"byte\":{}}},\"end\":{{\"line\":0,\"column\":{},\"byte\":{}}}}}",
+        start + 1, start, end + 1, end,
+    );
+    let literal = |value: usize, start: usize| format!(
+        "{{\"type\":\"literal\",\"value\":{},\"span\":{}}}",
+        value,
+        span(start, start + 1),
+    );
+
+    let expected = format!(
+        "[{{\"type\":\"expression\",\"expression\":{{\"type\":\"binary\",\"operator\":\"add\",\"left\":{},\"right\":{{\"type\":\"binary\",\"operator\":\"multiply\",\"left\":{},\"right\":{},\"span\":{}}},\"span\":{}}}}}]",
+        literal(1, 0),
+        literal(2, 4),
+        literal(3, 8),
+        span(4, 9),
+        span(0, 9),
+    );
+    assert_eq!(expected, to_json(source, &statements));
+}
+
+#[test]
+fn statements_serialize_by_type() {
+    let source = "var a = -\"x\\n\", b; print !a;";
+    let statements = crate::compiler::parse(source, Default::default()).expect("program to parse");
+    let json = to_json(source, &statements);
+
+    assert!(json.starts_with("[{\"type\":\"var\",\"declarations\":[{\"name\":\"a\","), "{}", json);
+    assert!(json.contains("{\"type\":\"unary\",\"operator\":\"negate\",\"operand\":{\"type\":\"literal\",\"value\":\"x\\n\","));
+    assert!(json.contains("{\"name\":\"b\",\"span\":{\"start\":{\"line\":0,\"column\":17,\"byte\":16},\"end\":{\"line\":0,\"column\":18,\"byte\":17}},\"initializer\":null}"));
+    assert!(json.contains("{\"type\":\"print\",\"expression\":{\"type\":\"unary\",\"operator\":\"not\",\"operand\":{\"type\":\"variable\",\"name\":\"a\","));
+}
+
+#[test]
+fn if_statements_serialize_their_branches() {
+    let source = "if (a) print 1;";
+    let statements = crate::compiler::parse(source, Default::default()).expect("program to parse");
+    let json = to_json(source, &statements);
+
+    assert!(json.starts_with("[{\"type\":\"if\",\"condition\":{\"type\":\"variable\",\"name\":\"a\","), "{}", json);
+    assert!(json.contains(",\"then\":{\"type\":\"print\",\"expression\":{\"type\":\"literal\",\"value\":1,"));
+    assert!(json.ends_with(",\"else\":null}]"));
+}
+
+#[test]
+fn functions_serialize_their_params_and_body() {
+    let source = "fun add(a, b) { return a + b; } fun nothing() { return; }";
+    let statements = crate::compiler::parse(source, Default::default()).expect("program to parse");
+    let json = to_json(source, &statements);
+
+    assert!(json.starts_with("[{\"type\":\"function\",\"name\":\"add\",\"params\":[\"a\",\"b\"],\"body\":[{\"type\":\"return\",\"value\":{\"type\":\"binary\","), "{}", json);
+    assert!(json.ends_with("{\"type\":\"function\",\"name\":\"nothing\",\"params\":[],\"body\":[{\"type\":\"return\",\"value\":null}]}]"), "{}", json);
+}
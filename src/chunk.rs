@@ -1,9 +1,7 @@
-use std::usize;
-
 use crate::{fixed_vec::FixedVec, value::Value};
 
 #[repr(u8)]
-#[derive(Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 pub (crate) enum OpCode {
     #[default]
     Unknown = u8::MAX,
@@ -45,11 +43,101 @@ pub (crate) enum OpCode {
     Class = 34,
     Inherit = 35,
     Method = 36,
+    NotEqual = 37,
+    GreaterEqual = 38,
+    LessEqual = 39,
+    Modulo = 40,
+    /// Jumps when the value on top of the stack isn't nil, leaving it in place.
+    JumpIfNotNil = 41,
+    /// Starts a try statement's body. A runtime error raised before the matching
+    /// PopHandler unwinds to where this was and jumps to the handler.
+    PushHandler = 42,
+    PopHandler = 43,
+    /// A call whose result is returned straight away. A function called this way
+    /// reuses the running function's frame instead of pushing one of its own.
+    TailCall = 44,
 }
 
 impl OpCode {
-    pub (crate) fn max() -> u8 {
-        OpCode::Method as u8
+    /// The number of operand bytes that follow this opcode in the bytecode.
+    pub (crate) fn operand_width(&self) -> usize {
+        match self {
+            OpCode::Constant
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::Call
+            | OpCode::TailCall => 1,
+            // Jump distances are 16 bits, stored high byte first.
+            OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::JumpIfNotNil
+            | OpCode::PushHandler
+            | OpCode::Loop => 2,
+            _ => 0,
+        }
+    }
+
+    /// How many values the opcode pops off the stack and then pushes onto it,
+    /// or None if that depends on its operands.
+    pub (crate) fn stack_effect(&self) -> Option<(usize, usize)> {
+        let effect = match self {
+            OpCode::Constant
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal
+            | OpCode::GetGlobal
+            | OpCode::GetUpValue
+            | OpCode::Class => (0, 1),
+            OpCode::Pop
+            | OpCode::DefineGlobal
+            | OpCode::Print
+            | OpCode::CloseUpValue
+            | OpCode::Method => (1, 0),
+            // These peek at the value on top of the stack, leaving it in place.
+            OpCode::SetLocal
+            | OpCode::SetGlobal
+            | OpCode::SetUpValue
+            | OpCode::JumpIfFalse
+            | OpCode::JumpIfNotNil => (1, 1),
+            OpCode::GetProperty
+            | OpCode::Not
+            | OpCode::Negate => (1, 1),
+            OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Inherit
+            | OpCode::Equal
+            | OpCode::NotEqual
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo => (2, 1),
+            OpCode::Jump
+            | OpCode::Loop
+            | OpCode::PushHandler
+            | OpCode::PopHandler
+            | OpCode::Return => (0, 0),
+            OpCode::Call
+            | OpCode::TailCall
+            | OpCode::Invoke
+            | OpCode::SuperInvoke
+            | OpCode::Closure
+            | OpCode::Unknown => return None,
+        };
+        Some(effect)
+    }
+
+    /// Whether this opcode's operand is an index into the constant pool.
+    pub (crate) fn has_constant_operand(&self) -> bool {
+        matches!(self, OpCode::Constant | OpCode::DefineGlobal | OpCode::GetGlobal | OpCode::SetGlobal)
     }
     fn as_str(&self) -> &str {
         match self {
@@ -76,13 +164,18 @@ impl OpCode {
             OpCode::Subtract => "Subtract",
             OpCode::Multiply => "Multiply",
             OpCode::Divide => "Divide",
+            OpCode::Modulo => "Modulo",
             OpCode::Not => "Not",
             OpCode::Negate => "Negate",
             OpCode::Print => "Print",
             OpCode::Jump => "Jump",
             OpCode::JumpIfFalse => "JumpIfFalse",
+            OpCode::JumpIfNotNil => "JumpIfNotNil",
+            OpCode::PushHandler => "PushHandler",
+            OpCode::PopHandler => "PopHandler",
             OpCode::Loop => "Loop",
             OpCode::Call => "Call",
+            OpCode::TailCall => "TailCall",
             OpCode::Invoke => "Invoke",
             OpCode::SuperInvoke => "SuperInvoke",
             OpCode::Closure => "Closure",
@@ -91,6 +184,9 @@ impl OpCode {
             OpCode::Class => "Class",
             OpCode::Inherit => "Inherit",
             OpCode::Method => "Method",
+            OpCode::NotEqual => "NotEqual",
+            OpCode::GreaterEqual => "GreaterEqual",
+            OpCode::LessEqual => "LessEqual",
         }
     }
 }
@@ -137,6 +233,14 @@ impl TryFrom<u8> for OpCode {
             34 => Ok(OpCode::Class),
             35 => Ok(OpCode::Inherit),
             36 => Ok(OpCode::Method),
+            37 => Ok(OpCode::NotEqual),
+            38 => Ok(OpCode::GreaterEqual),
+            39 => Ok(OpCode::LessEqual),
+            40 => Ok(OpCode::Modulo),
+            41 => Ok(OpCode::JumpIfNotNil),
+            42 => Ok(OpCode::PushHandler),
+            43 => Ok(OpCode::PopHandler),
+            44 => Ok(OpCode::TailCall),
             other => Err(format!("Received invalid opcode: {}", other)),
         }
     }
@@ -149,19 +253,61 @@ pub (crate) struct Chunk {
 }
 
 impl Chunk {
-    pub (crate) fn disassemble_code<const N: usize>(code: &Vec<Chunk>, constants: &FixedVec<Value, N>, name: &str) {
-        println!("== {} ==", name);
+    pub (crate) fn disassemble_code<const N: usize>(code: &[Chunk], constants: &FixedVec<Value, N>, name: &str) {
+        print!("{}", Chunk::disassemble(code, constants, name));
+    }
+
+    /// Disassembles the code under a header with its name. Each function the code loads as a
+    /// constant follows under a `== fn name ==` header of its own, indented one level deeper
+    /// than the code that declares it, so nested functions are listed inside their parents.
+    pub (crate) fn disassemble<const N: usize>(code: &[Chunk], constants: &FixedVec<Value, N>, name: &str) -> String {
+        let mut listing = String::new();
+        Chunk::disassemble_nested(&mut listing, code, constants, name, 0);
+        listing
+    }
+
+    fn disassemble_nested<const N: usize>(listing: &mut String, code: &[Chunk], constants: &FixedVec<Value, N>, name: &str, depth: usize) {
+        let indent = "    ".repeat(depth);
+        listing.push_str(&format!("{}== {} ==\n", indent, name));
 
+        let mut functions = Vec::new();
         let mut offset = 0;
         while offset < code.len() {
-            offset = Chunk::disassemble_instruction(code, offset, constants);
+            let (line, next) = Chunk::format_instruction(code, offset, constants);
+            listing.push_str(&format!("{}{}\n", indent, line));
+            if let Ok(Instruction { op: OpCode::Constant, operands: Operands::Byte(index), .. }) = Instruction::decode_at(code, offset) {
+                functions.extend(constants.get(index as usize).and_then(Value::as_function));
+            }
+            offset = next;
+        }
+
+        for function in functions {
+            let header = format!("fn {}", function.name);
+            Chunk::disassemble_nested(listing, &function.chunks, constants, &header, depth + 1);
         }
     }
 
-    pub (crate) fn disassemble_instruction<const N: usize>(code: &Vec<Chunk>, index: usize, constants: &FixedVec<Value, N>) -> usize {
-        let mut print_val = String::new();
-        let result;
+    /// Formats the raw bytes of the code like a classic hex dump: 
+    /// an offset followed by up to 16 bytes per row.
+    pub (crate) fn hex_dump(code: &[Chunk]) -> String {
+        let mut dump = String::new();
+        for (row, bytes) in code.chunks(16).enumerate() {
+            dump.push_str(&format!("{:08x} ", row * 16));
+            for (i, chunk) in bytes.iter().enumerate() {
+                // Split each row into two groups of 8 for readability.
+                if i == 8 {
+                    dump.push(' ');
+                }
+                dump.push_str(&format!(" {:02x}", chunk.op));
+            }
+            dump.push('\n');
+        }
+        dump
+    }
 
+    /// Formats the instruction at the index as a disassembly line, along with the index of the next instruction.
+    pub (crate) fn format_instruction<const N: usize>(code: &[Chunk], index: usize, constants: &FixedVec<Value, N>) -> (String, usize) {
+        let mut print_val = String::new();
         if index > 0 && code[index].line == code[index - 1].line {
             print_val.push_str("   | ");
         } else {
@@ -175,172 +321,217 @@ impl Chunk {
       
       
 
-        let instruction = OpCode::try_from(code[index].op).expect("OpCode to be valid");
-        match instruction {
-            OpCode::Constant => {
-                let constant = code[index + 1].op;
-                print_val.push_str("CONSTANT ");
-                print_val.push_str(&constant.to_string());
+        let instruction = Instruction::decode_at(code, index).expect("instruction to be valid");
+        print_val.push_str(instruction.op.as_str());
+        if let Operands::Short(distance) = instruction.operands {
+            print_val.push(' ');
+            print_val.push_str(&distance.to_string());
+            if let Some(target) = instruction.jump_target() {
+                print_val.push_str(" -> ");
+                print_val.push_str(&target.to_string());
+            }
+        }
+        if let Operands::Byte(operand) = instruction.operands {
+            print_val.push(' ');
+            print_val.push_str(&operand.to_string());
+            if instruction.op.has_constant_operand() {
                 print_val.push(' ');
                 print_val.push_str(
                     &constants.get(
-                        constant as usize
+                        operand as usize
                     ).expect("value to be defined.")
                         .to_string()
                 );
-                result = index + 2;
-            },
-            OpCode::Nil => {
-                print_val.push_str("Nil");
-                result = index + 1;
-            },
-            OpCode::True => {
-                print_val.push_str("True");
-                result = index + 1;
-            },
-            OpCode::False => {
-                print_val.push_str("False");
-                result = index + 1;
-            },
-            OpCode::Pop => {
-                print_val.push_str("Pop");
-                result = index + 1;
-            },
-            OpCode::GetLocal => {
-                print_val.push_str("GetLocal");
-                result = index + 1;
-            },
-            OpCode::SetLocal => {
-                print_val.push_str("SetLocal");
-                result = index + 1;
-            },
-            OpCode::GetGlobal => {
-                print_val.push_str("GetGlobal");
-                result = index + 1;
-            },
-            OpCode::DefineGlobal => {
-                print_val.push_str("DefineGlobal");
-                result = index + 1;
-            },
-            OpCode::SetGlobal => {
-                print_val.push_str("SetGlobal");
-                result = index + 1;
-            },
-            OpCode::GetUpValue => {
-                print_val.push_str("GetUpValue");
-                result = index + 1;
-            },
-            OpCode::SetUpValue => {
-                print_val.push_str("SetUpValue");
-                result = index + 1;
-            },
-            OpCode::GetProperty => {
-                print_val.push_str("GetProperty");
-                result = index + 1;
-            },
-            OpCode::SetProperty => {
-                print_val.push_str("SetProperty");
-                result = index + 1;
-            },
-            OpCode::GetSuper => {
-                print_val.push_str("GetSuper");
-                result = index + 1;
-            },
-            OpCode::Equal => {
-                print_val.push_str("Equal");
-                result = index + 1;
-            },
-            OpCode::Greater => {
-                print_val.push_str("Greater");
-                result = index + 1;
-            },
-            OpCode::Less => {
-                print_val.push_str("Less");
-                result = index + 1;
-            },
-            OpCode::Add => {
-                print_val.push_str("Add");
-                result = index + 1;
-            },
-            OpCode::Subtract => {
-                print_val.push_str("Subtract");
-                result = index + 1;
-            },
-            OpCode::Multiply => {
-                print_val.push_str("Multiply");
-                result = index + 1;
-            },
-            OpCode::Divide => {
-                print_val.push_str("Divide");
-                result = index + 1;
-            },
-            OpCode::Not => {
-                print_val.push_str("Not");
-                result = index + 1;
-            },
-            OpCode::Negate => {
-                print_val.push_str("Negate");
-                result = index + 1;
-            },
-            OpCode::Print => {
-                print_val.push_str("Print");
-                result = index + 1;
-            },
-            OpCode::Jump => {
-                print_val.push_str("Jump");
-                result = index + 1;
-            },
-            OpCode::JumpIfFalse => {
-                print_val.push_str("JumpIfFalse");
-                result = index + 1;
-            },
-            OpCode::Loop => {
-                print_val.push_str("Loop");
-                result = index + 1;
-            },
-            OpCode::Call => {
-                print_val.push_str("Call");
-                result = index + 1;
-            },
-            OpCode::Invoke => {
-                print_val.push_str("Invoke");
-                result = index + 1;
-            },
-            OpCode::SuperInvoke => {
-                print_val.push_str("SuperInvoke");
-                result = index + 1;
-            },
-            OpCode::Closure => {
-                print_val.push_str("Closure");
-                result = index + 1;
-            },
-            OpCode::CloseUpValue => {
-                print_val.push_str("CloseUpValue");
-                result = index + 1;
-            },
-            OpCode::Return => {
-                print_val.push_str("RETURN");
-                result = index + 1;
-            },
-            OpCode::Class => { 
-                print_val.push_str("Class"); 
-                result = index + 1; 
-            },
-            OpCode::Inherit => { 
-                print_val.push_str("Inherit"); 
-                result = index + 1; 
-            },
-            OpCode::Method => { 
-                print_val.push_str("Method"); 
-                result = index + 1; 
-            },
-            OpCode::Unknown => {
-                print_val.push_str("Unknown");
-                result = index + 1;
             }
         }
 
-        println!("{}", print_val);
-        result
+        (print_val, index + instruction.len())
+    }
+
+    /// Decodes the code into its instructions, in order. Decoding stops
+    /// after the first invalid opcode or truncated operand.
+    pub (crate) fn decode(code: &[Chunk]) -> Decoder<'_> {
+        Decoder { code, offset: 0, failed: false }
+    }
+}
+
+/// One decoded instruction and where it starts in the code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub (crate) struct Instruction {
+    pub (crate) op: OpCode,
+    pub (crate) operands: Operands,
+    pub (crate) offset: usize,
+}
+
+/// The operand bytes that follow an opcode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub (crate) enum Operands {
+    None,
+    Byte(u8),
+    Short(u16),
+}
+
+impl Instruction {
+    fn decode_at(code: &[Chunk], offset: usize) -> Result<Instruction, String> {
+        let op = OpCode::try_from(code[offset].op)?;
+        let missing = || format!("{} at offset {} is missing its operand", op.as_str(), offset);
+        let operands = match op.operand_width() {
+            0 => Operands::None,
+            1 => {
+                match code.get(offset + 1) {
+                    None => return Err(missing()),
+                    Some(chunk) => Operands::Byte(chunk.op),
+                }
+            }
+            _ => {
+                match (code.get(offset + 1), code.get(offset + 2)) {
+                    (Some(high), Some(low)) => Operands::Short(u16::from_be_bytes([high.op, low.op])),
+                    _ => return Err(missing()),
+                }
+            }
+        };
+
+        Ok(Instruction { op, operands, offset })
+    }
+
+    /// The number of bytes the instruction takes up, including its opcode.
+    pub (crate) fn len(&self) -> usize {
+        1 + self.op.operand_width()
+    }
+
+    /// Where a jump goes. Distances are counted from the end of the jump instruction.
+    /// None for instructions that aren't jumps, or a loop that would go before the start of the code.
+    pub (crate) fn jump_target(&self) -> Option<usize> {
+        let end = self.offset + self.len();
+        match (self.op, self.operands) {
+            (OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfNotNil | OpCode::PushHandler, Operands::Short(distance)) => {
+                Some(end + distance as usize)
+            }
+            (OpCode::Loop, Operands::Short(distance)) => end.checked_sub(distance as usize),
+            _ => None,
+        }
+    }
+
+    /// Like OpCode::stack_effect, but also knows the effects that depend on operands.
+    pub (crate) fn stack_effect(&self) -> Option<(usize, usize)> {
+        match (self.op, self.operands) {
+            // The callee and its arguments are replaced by the result.
+            (OpCode::Call | OpCode::TailCall, Operands::Byte(arg_count)) => Some((arg_count as usize + 1, 1)),
+            (op, _) => op.stack_effect(),
+        }
+    }
+}
+
+pub (crate) struct Decoder<'c> {
+    code: &'c [Chunk],
+    offset: usize,
+    failed: bool,
+}
+
+impl<'c> Iterator for Decoder<'c> {
+    type Item = Result<Instruction, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.offset >= self.code.len() {
+            return None;
+        }
+
+        let result = Instruction::decode_at(self.code, self.offset);
+        match &result {
+            Ok(instruction) => self.offset += instruction.len(),
+            Err(_) => self.failed = true,
+        }
+        Some(result)
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn hex_dump_rows() {
+    let code: Vec<Chunk> = (0..20u8)
+        .map(|op| Chunk { line: 0, op })
+        .collect();
+
+    assert_eq!(
+        "00000000  00 01 02 03 04 05 06 07  08 09 0a 0b 0c 0d 0e 0f\n\
+         00000010  10 11 12 13\n",
+        Chunk::hex_dump(&code)
+    );
+}
+
+#[test]
+fn hex_dump_program() {
+    let program = crate::compiler::compile("print 1 + 2;").expect("program to compile");
+    assert_eq!("00000000  00 00 00 01 12 18 21\n", Chunk::hex_dump(&program.code));
+}
+
+#[test]
+fn disassembly_lists_functions_under_their_parents() {
+    let program = crate::compiler::compile("
+        fun outer() {
+            fun inner() { return 1; }
+            return inner;
+        }
+        print outer()();
+    ").expect("program to compile");
+    let listing = Chunk::disassemble(&program.code, &program.values, "script");
+    let headers: Vec<&str> = listing.lines().filter(|line| line.trim_start().starts_with("==")).collect();
+    assert_eq!(vec!["== script ==", "    == fn outer ==", "        == fn inner =="], headers);
+
+    let inner = listing.find("        == fn inner ==").expect("inner to be listed");
+    assert_eq!(
+        "        == fn inner ==\n        0003 Constant 0 1\n           | Return\n           | Nil\n           | Return\n",
+        &listing[inner..]
+    );
+}
+
+#[test]
+fn decode_program() {
+    let program = crate::compiler::compile("var a = 1 + 2;").expect("program to compile");
+    let decoded: Vec<Instruction> = Chunk::decode(&program.code)
+        .collect::<Result<_, _>>()
+        .expect("compiled code to decode");
+
+    assert_eq!(
+        vec![
+            Instruction { op: OpCode::Constant, operands: Operands::Byte(1), offset: 0 },
+            Instruction { op: OpCode::Constant, operands: Operands::Byte(2), offset: 2 },
+            Instruction { op: OpCode::Add, operands: Operands::None, offset: 4 },
+            Instruction { op: OpCode::DefineGlobal, operands: Operands::Byte(0), offset: 5 },
+            Instruction { op: OpCode::Return, operands: Operands::None, offset: 7 },
+        ],
+        decoded
+    );
+}
+
+#[test]
+fn decode_jumps() {
+    let chunk = |op: u8| Chunk { line: 0, op };
+    let code = [
+        chunk(OpCode::JumpIfFalse as u8), chunk(1), chunk(2),
+        chunk(OpCode::Loop as u8), chunk(0), chunk(6),
+    ];
+    let decoded: Vec<Instruction> = Chunk::decode(&code)
+        .collect::<Result<_, _>>()
+        .expect("jumps to decode");
+
+    assert_eq!(Operands::Short(258), decoded[0].operands);
+    assert_eq!(Some(261), decoded[0].jump_target());
+    assert_eq!(Some(0), decoded[1].jump_target());
+    assert!(Chunk::decode(&code[..2]).next().expect("one result").is_err());
+}
+
+#[test]
+fn decode_stops_at_invalid_code() {
+    let code = [
+        Chunk { line: 0, op: OpCode::Nil as u8 },
+        Chunk { line: 0, op: 200 },
+        Chunk { line: 0, op: OpCode::Nil as u8 },
+    ];
+    let decoded: Vec<_> = Chunk::decode(&code).collect();
+    assert_eq!(2, decoded.len());
+    assert!(decoded[0].is_ok());
+    assert!(decoded[1].is_err());
+
+    let truncated = [Chunk { line: 0, op: OpCode::Constant as u8 }];
+    assert!(Chunk::decode(&truncated).next().expect("one result").is_err());
+}
@@ -0,0 +1,392 @@
+use std::{
+    env::args, 
+    fs::OpenOptions, 
+    process::ExitCode,
+    io::{
+        stdout, 
+        stdin, 
+        BufRead,
+        Read,
+        Write
+    }
+};
+
+use crate::{
+    vm::{run, CoercionPolicy, LineEnding, LoxError, VMOptions},
+    repl::Repl,
+    chunk::Chunk,
+    compiler::{compile_with_options, parse, CompileOptions},
+    value::NumberFormat,
+    explain, tokenizer, ast_json,
+};
+
+/// What to do with each program the CLI is given.
+#[derive(Clone, Copy)]
+enum Mode {
+    Run,
+    Dump,
+    DumpHex,
+    DumpTokens,
+    AstJson,
+}
+
+/// Everything the command line asked for.
+struct Cli {
+    options: VMOptions,
+    mode: Mode,
+    /// The script to run. The REPL starts when there is none.
+    path: Option<String>,
+    /// A program given on the command line, run in place of a script.
+    eval: Option<String>,
+    /// An error code to explain instead of running anything.
+    explain: Option<String>,
+    repl: ReplStyle,
+}
+
+/// How the REPL greets the user and asks for input.
+struct ReplStyle {
+    prompt: String,
+    /// Shown instead of the prompt while an unfinished program is waiting for more lines.
+    continuation_prompt: String,
+    banner: bool,
+}
+
+impl Default for ReplStyle {
+    fn default() -> Self {
+        Self {
+            prompt: "> ".to_string(),
+            continuation_prompt: "... ".to_string(),
+            banner: true,
+        }
+    }
+}
+
+/// Separates flags from the script path. Only one script is supported, so any
+/// other positional argument is an error rather than being silently dropped.
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Cli, String> {
+    let mut cli = Cli {
+        options: VMOptions::default(),
+        mode: Mode::Run,
+        path: None,
+        eval: None,
+        explain: None,
+        repl: ReplStyle::default(),
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--verbose-errors" => cli.options.verbose_errors = true,
+            "--dump" => cli.mode = Mode::Dump,
+            "--dump-hex" => cli.mode = Mode::DumpHex,
+            "--dump-tokens" => cli.mode = Mode::DumpTokens,
+            "--ast-json" => cli.mode = Mode::AstJson,
+            "--profile" => cli.options.profile = true,
+            "--time-lines" => cli.options.time_lines = true,
+            "--deny-warnings" => cli.options.deny_warnings = true,
+            "--implicit-semicolons" => cli.options.implicit_semicolons = true,
+            // Strict is the default now, but scripts that ask for it still work.
+            "--strict-arithmetic" => cli.options.coercion = CoercionPolicy::Strict,
+            "--quiet" => cli.options.quiet = true,
+            "--stats" => cli.options.stats = true,
+            "--sandbox" => cli.options.sandbox = true,
+            "--explain" => {
+                match args.next() {
+                    Some(code) => cli.explain = Some(code),
+                    None => return Err("--explain expects an error code, like E0001".to_string()),
+                }
+            }
+            "--coercion" => {
+                match args.next().as_deref() {
+                    Some("strict") => cli.options.coercion = CoercionPolicy::Strict,
+                    Some("concat") => cli.options.coercion = CoercionPolicy::StringConcat,
+                    Some("numeric") => cli.options.coercion = CoercionPolicy::Numeric,
+                    _ => return Err("--coercion expects strict, concat or numeric".to_string()),
+                }
+            }
+            "--line-ending" => {
+                match args.next().as_deref() {
+                    Some("lf") => cli.options.line_ending = LineEnding::Lf,
+                    Some("crlf") => cli.options.line_ending = LineEnding::CrLf,
+                    _ => return Err("--line-ending expects lf or crlf".to_string()),
+                }
+            }
+            "--number-format" => {
+                match args.next().as_deref() {
+                    Some("general") => cli.options.number_format = NumberFormat::General,
+                    Some("scientific") => cli.options.number_format = NumberFormat::Scientific,
+                    Some(fixed) => match fixed.strip_prefix("fixed:").and_then(|digits| digits.parse().ok()) {
+                        Some(digits) => cli.options.number_format = NumberFormat::Fixed(digits),
+                        None => return Err("--number-format expects general, scientific or fixed:<digits>".to_string()),
+                    },
+                    None => return Err("--number-format expects general, scientific or fixed:<digits>".to_string()),
+                }
+            }
+            "--max-string-length" => {
+                match args.next().and_then(|max| max.parse().ok()) {
+                    Some(max) => cli.options.max_string_length = Some(max),
+                    None => return Err("--max-string-length expects a length in bytes".to_string()),
+                }
+            }
+            "-e" | "--eval" | "--run-string" => {
+                match args.next() {
+                    Some(program) => cli.eval = Some(program),
+                    None => return Err(format!("{} expects a program to run, like 'print 1 + 2;'", arg)),
+                }
+            }
+            "--no-banner" => cli.repl.banner = false,
+            "--prompt" => {
+                match args.next() {
+                    Some(prompt) => cli.repl.prompt = prompt,
+                    None => return Err("--prompt expects the text to prompt with".to_string()),
+                }
+            }
+            "--continuation-prompt" => {
+                match args.next() {
+                    Some(prompt) => cli.repl.continuation_prompt = prompt,
+                    None => return Err("--continuation-prompt expects the text to prompt with".to_string()),
+                }
+            }
+            flag if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+            _ => {
+                if let Some(path) = &cli.path {
+                    return Err(format!("Only one script can be run at a time, but found both '{}' and '{}'", path, arg));
+                }
+                cli.path = Some(arg);
+            }
+        }
+    }
+
+    if let (Some(_), Some(path)) = (&cli.eval, &cli.path) {
+        return Err(format!("A program passed with -e can't be run along with the script '{}'", path));
+    }
+
+    Ok(cli)
+}
+
+/// The rslox command line: runs a script, a program given with -e, or the REPL.
+pub fn main() -> ExitCode {
+    let mut args = args();
+    // program location. Throw it away.
+    args.next();
+
+    let Cli { options, mode, path, eval, explain, repl } = match parse_args(args) {
+        Ok(cli) => cli,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            // The exit code sysexits.h uses for bad command line usage.
+            return ExitCode::from(64);
+        }
+    };
+
+    if let Some(code) = explain {
+        match explain::explain(&code) {
+            Some(explanation) => println!("{}", explanation),
+            None => println!("There is no error code '{}'. Codes look like E0001.", code),
+        }
+        return ExitCode::SUCCESS;
+    }
+    
+    let code = match (eval, path) {
+        (Some(code), _) => code,
+        (None, Some(arg)) => {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(false)
+                .open(arg)
+                .expect("Failed to open file.");
+
+            let mut code = String::new();
+            file.read_to_string(&mut code).expect("Failed to read file.");
+            code
+        }
+        (None, None) => {
+            run_repl(stdin().lock(), &mut stdout(), mode, options, &repl)
+                .expect("Failed to use stdin and stdout");
+            return ExitCode::SUCCESS;
+        }
+    };
+
+    match mode {
+        Mode::Run => run(&code, options, Box::new(stdout())),
+        Mode::Dump => print_disassembly(&code, options),
+        Mode::DumpHex => print_hex_dump(&code, options),
+        Mode::DumpTokens => print_tokens(&code),
+        Mode::AstJson => print_ast_json(&code, options),
+    }
+}
+
+/// Reads and evaluates lines until an empty line or the end of input. A program that is cut
+/// off, like an unclosed string, keeps reading lines with the continuation prompt until it is
+/// complete. An empty line gives up on it and reports its errors.
+fn run_repl<R: BufRead, W: Write>(mut input: R, output: &mut W, mode: Mode, options: VMOptions, style: &ReplStyle) -> std::io::Result<()> {
+    if style.banner {
+        writeln!(output, "rslox {}", env!("CARGO_PKG_VERSION"))?;
+    }
+
+    // Keep one REPL for the whole session so globals outlive the line that defined them.
+    let mut repl = Repl::new(options);
+    let mut code = String::new();
+    loop {
+        let prompt = if code.is_empty() { &style.prompt } else { &style.continuation_prompt };
+        output.write_all(prompt.as_bytes())?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+
+        let blank = line.trim().is_empty();
+        if blank && code.is_empty() {
+            break;
+        }
+        code.push_str(&line);
+
+        match mode {
+            Mode::Run => {
+                match repl.eval_line(&code) {
+                    Ok(None) => {}
+                    Ok(Some(value)) => writeln!(output, "{}", value.format(options.number_format))?,
+                    // Nothing ran, so the whole program can be evaluated again once it is finished.
+                    Err(errs) if !blank && errs.iter().all(LoxError::is_incomplete_input) => continue,
+                    Err(errs) => {
+                        for err in errs {
+                            writeln!(output, "{}", err)?;
+                        }
+                    }
+                }
+            }
+            Mode::Dump => { print_disassembly(&code, options); }
+            Mode::DumpHex => { print_hex_dump(&code, options); }
+            Mode::DumpTokens => { print_tokens(&code); }
+            Mode::AstJson => { print_ast_json(&code, options); }
+        };
+        code.clear();
+    }
+
+    Ok(())
+}
+
+/// The compiler settings the command line asked for, for the modes that compile without running.
+fn compile_options(options: VMOptions) -> CompileOptions {
+    CompileOptions {
+        implicit_semicolons: options.implicit_semicolons,
+        ..Default::default()
+    }
+}
+
+/// Prints the disassembly of the program and every function in it instead of running it.
+fn print_disassembly(program: &str, options: VMOptions) -> ExitCode {
+    match compile_with_options(program, compile_options(options)) {
+        Err(errs) => {
+            for err in errs {
+                println!("{}", err);
+            }
+            ExitCode::from(65)
+        }
+        Ok(program) => {
+            Chunk::disassemble_code(&program.code, &program.values, "script");
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+/// Prints the program's bytecode as a hex dump instead of running it.
+fn print_hex_dump(program: &str, options: VMOptions) -> ExitCode {
+    match compile_with_options(program, compile_options(options)) {
+        Err(errs) => {
+            for err in errs {
+                println!("{}", err);
+            }
+            ExitCode::from(65)
+        }
+        Ok(program) => {
+            print!("{}", Chunk::hex_dump(&program.code));
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+/// Prints every token in the program instead of compiling it.
+fn print_tokens(program: &str) -> ExitCode {
+    print!("{}", tokenizer::dump_tokens(program));
+    ExitCode::SUCCESS
+}
+
+/// Prints the program's syntax tree as JSON instead of compiling it.
+fn print_ast_json(program: &str, options: VMOptions) -> ExitCode {
+    match parse(program, compile_options(options)) {
+        Err(errs) => {
+            for err in errs {
+                println!("{}", err);
+            }
+            ExitCode::from(65)
+        }
+        Ok(statements) => {
+            println!("{}", ast_json::to_json(program, &statements));
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+#[test]
+fn parse_args_rejects_extra_scripts() {
+    let args = |args: &[&str]| parse_args(args.iter().map(|arg| arg.to_string()));
+
+    let cli = args(&["--profile", "a.lox", "--deny-warnings"]).expect("flags around a script to parse");
+    assert_eq!(Some("a.lox".to_string()), cli.path);
+    assert!(cli.options.profile && cli.options.deny_warnings);
+
+    let err = args(&["a.lox", "b.lox"]).err().expect("a second script to be rejected");
+    assert!(err.contains("a.lox") && err.contains("b.lox"));
+
+    assert!(args(&["--bogus", "a.lox"]).is_err());
+    assert!(args(&["--max-string-length"]).is_err());
+    assert!(args(&[]).expect("no arguments to start the repl").path.is_none());
+
+    let cli = args(&["-e", "print 1;", "--quiet"]).expect("a program on the command line to parse");
+    assert_eq!(Some("print 1;".to_string()), cli.eval);
+    assert!(cli.path.is_none());
+    assert!(args(&["-e"]).is_err());
+    assert!(args(&["--eval", "print 1;", "a.lox"]).is_err());
+
+    let cli = args(&["--no-banner", "--prompt", "lox> "]).expect("repl flags to parse");
+    assert!(!cli.repl.banner);
+    assert_eq!("lox> ", cli.repl.prompt);
+    assert!(args(&["--prompt"]).is_err());
+
+    let cli = args(&["--coercion", "numeric"]).expect("a coercion policy to parse");
+    assert_eq!(CoercionPolicy::Numeric, cli.options.coercion);
+    assert!(args(&["--coercion", "loose"]).is_err());
+
+    let cli = args(&["--line-ending", "crlf"]).expect("a line ending to parse");
+    assert_eq!(LineEnding::CrLf, cli.options.line_ending);
+    assert!(args(&["--line-ending", "cr"]).is_err());
+
+    let cli = args(&["--number-format", "fixed:3"]).expect("a fixed number format to parse");
+    assert_eq!(NumberFormat::Fixed(3), cli.options.number_format);
+    let cli = args(&["--number-format", "scientific"]).expect("a number format to parse");
+    assert_eq!(NumberFormat::Scientific, cli.options.number_format);
+    assert!(args(&["--number-format", "fixed:"]).is_err());
+    assert!(args(&["--number-format"]).is_err());
+}
+
+#[test]
+fn repl_uses_the_configured_prompts() {
+    let style = ReplStyle {
+        prompt: "lox> ".to_string(),
+        continuation_prompt: "...> ".to_string(),
+        banner: false,
+    };
+
+    let mut output = Vec::new();
+    run_repl("1 + 2;\n\"a\n b\";\n".as_bytes(), &mut output, Mode::Run, VMOptions::default(), &style)
+        .expect("writing to a vec to succeed");
+    assert_eq!("lox> 3\nlox> ...> a\n b\nlox> ", String::from_utf8(output).unwrap());
+
+    let mut output = Vec::new();
+    run_repl("\n".as_bytes(), &mut output, Mode::Run, VMOptions::default(), &ReplStyle::default())
+        .expect("writing to a vec to succeed");
+    assert_eq!(format!("rslox {}\n> ", env!("CARGO_PKG_VERSION")), String::from_utf8(output).unwrap());
+}
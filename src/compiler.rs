@@ -1,82 +1,213 @@
-use std::{error::Error, fmt::{Debug, Display}, vec::IntoIter, iter::Peekable, collections::HashSet, rc::Rc};
+use std::{error::Error, fmt::{Debug, Display}, vec::IntoIter, iter::Peekable, rc::Rc, cell::RefCell};
 
-use tree_iterators_rs::prelude::{BinaryTreeNode, OwnedBinaryTreeNode};
+use tree_iterators_rs::prelude::{BinaryTreeNode, OwnedBinaryTreeNode, BorrowedBinaryTreeNode};
 
 use crate::{
     tokenizer::{
         Tokenizer, 
-        LoxToken, LoxParseErr, Position, TokenKind
+        LoxToken, LoxParseErr, Position, Span, TokenKind, unescape
     }, 
-    chunk::{Chunk, OpCode}, value::Value, object::Object, fixed_vec::FixedVec, vm::STACK_MAX
+    chunk::{Chunk, OpCode}, value::Value, object::{Function, Object}, fixed_vec::FixedVec, vm::STACK_MAX,
+    interner::Interner,
 };
 
-pub (crate) fn compile(source: &str) -> Result<(Vec<Chunk>, FixedVec<Value, STACK_MAX>), Vec<CompileErr>> {
-    let token_stream = Tokenizer::new(source);
-    let mut errs = None;
+/// The most locals that can be in scope at once. Each one takes a slot on the value stack.
+const LOCALS_MAX: usize = STACK_MAX;
+
+/// The methods numbers have, and the natives they are calls to. A method takes no
+/// arguments of its own, so `(2.9).floor()` is `floor(2.9)`.
+const NUMBER_METHODS: &[(&str, &str)] = &[
+    ("abs", "abs"),
+    ("floor", "floor"),
+    ("sqrt", "sqrt"),
+];
+
+/// The output of a successful compilation.
+#[derive(Debug)]
+pub (crate) struct Program {
+    pub (crate) code: Vec<Chunk>,
+    pub (crate) values: FixedVec<Value, STACK_MAX>,
+    /// Problems that are likely mistakes but did not prevent compilation.
+    pub (crate) warnings: Vec<CompileErr>,
+}
+
+impl Program {
+    /// The sizes reported by `--stats`. Strings are counted from the interner
+    /// the program was compiled with, and the code includes every function's.
+    pub (crate) fn stats(&self, interner: &Interner) -> CompileStats {
+        let functions: Vec<&Function> = self.values.iter().filter_map(Value::as_function).collect();
+        CompileStats {
+            code_bytes: self.code.len() + functions.iter().map(|function| function.chunks.len()).sum::<usize>(),
+            constants: self.values.len(),
+            interned_strings: interner.len(),
+            functions: functions.len(),
+        }
+    }
+}
+
+/// How large a compiled program is, and how close it is to the compiler's limits.
+#[derive(Debug, PartialEq)]
+pub (crate) struct CompileStats {
+    pub (crate) code_bytes: usize,
+    pub (crate) constants: usize,
+    pub (crate) interned_strings: usize,
+    pub (crate) functions: usize,
+}
+
+impl CompileStats {
+    pub (crate) fn report(&self) -> String {
+        let mut report = String::from("== compile stats ==\n");
+        report.push_str(&format!("{:<18}{}\n", "bytecode bytes", self.code_bytes));
+        report.push_str(&format!("{:<18}{} of {}\n", "constants", self.constants, STACK_MAX));
+        report.push_str(&format!("{:<18}{}\n", "interned strings", self.interned_strings));
+        report.push_str(&format!("{:<18}{}\n", "functions", self.functions));
+        report
+    }
+}
+
+/// Settings that change which programs the compiler accepts.
+#[derive(Clone, Copy, Debug, Default)]
+pub (crate) struct CompileOptions {
+    /// Let a line break end a statement that is otherwise complete, so semicolons are optional.
+    pub (crate) implicit_semicolons: bool,
+    /// Leave the value of a final expression statement on the stack instead of popping it.
+    pub (crate) keep_final_expression: bool,
+}
+
+#[cfg(test)]
+pub (crate) fn compile(source: &str) -> Result<Program, Vec<CompileErr>> {
+    compile_with_options(source, CompileOptions::default())
+}
+
+pub (crate) fn compile_with_options(source: &str, options: CompileOptions) -> Result<Program, Vec<CompileErr>> {
+    compile_with_interner(source, options, Rc::new(RefCell::new(Interner::new())))
+}
+
+/// Compiles the program, taking its strings from an interner shared with the VM that will run it.
+pub (crate) fn compile_with_interner(source: &str, options: CompileOptions, interner: Rc<RefCell<Interner>>) -> Result<Program, Vec<CompileErr>> {
+    let (tokens, lex_errs) = lex(source);
+    let compiler = Compiler::new(FunctionType::Script, source, tokens, options)
+        .with_interner(interner);
+    with_lexical_errors(compiler.compile(), lex_errs)
+}
+
+/// Parses the program into its statements without compiling them to bytecode.
+pub (crate) fn parse(source: &str, options: CompileOptions) -> Result<Vec<Statement>, Vec<CompileErr>> {
+    let (tokens, lex_errs) = lex(source);
+    let compiler = Compiler::new(FunctionType::Script, source, tokens, options);
+    with_lexical_errors(compiler.parse(), lex_errs)
+}
+
+fn lex(source: &str) -> (Vec<LoxToken>, Vec<LoxParseErr>) {
+    let mut lex_errs = Vec::new();
     let mut tokens = Vec::new();
-    for token_result in token_stream {
+    for token_result in Tokenizer::new(source) {
         match token_result {
-            Err(err) => {
-                match &mut errs {
-                    None => errs = Some(Vec::new()),
-                    Some(errs) => errs.push(err),
-                }
-            }
+            Err(err) => lex_errs.push(err),
             Ok(token) => tokens.push(token),
         }
     }
-    
-    if let Some(errs) = errs {
-        // Found an error. Don't go any further.
-        return Err(errs.into_iter().map(|err| {
-            CompileErr {
-                kind: CompileErrKind::Parse(err),
-                location: err.get_start()
-            }
-        }).collect());
+    (tokens, lex_errs)
+}
+
+/// Combines lexical errors with the result of parsing the tokens around them.
+/// The tokenizer skips past anything it can't lex, so the remaining
+/// tokens are still worth parsing for syntax errors.
+fn with_lexical_errors<T>(parsed: Result<T, Vec<CompileErr>>, lex_errs: Vec<LoxParseErr>) -> Result<T, Vec<CompileErr>> {
+    if lex_errs.is_empty() {
+        return parsed;
     }
 
-    let compiler = Compiler::new(FunctionType::Script, None, source, tokens);
-    compiler.compile()
+    let mut errs: Vec<CompileErr> = lex_errs.iter().map(|err| {
+        CompileErr {
+            kind: CompileErrKind::Parse(*err),
+            location: err.get_start()
+        }
+    }).collect();
+
+    if let Err(syntax_errs) = parsed {
+        // Syntax errors on the same lines as a lexical error are most
+        // likely fallout from it, so only report the lexical one.
+        errs.extend(syntax_errs.into_iter().filter(|syntax_err| {
+            let line = syntax_err.location.line();
+            !lex_errs.iter().any(|lex_err| {
+                (lex_err.get_start().line()..=lex_err.get_end().line()).contains(&line)
+            })
+        }));
+    }
+
+    errs.sort_by_key(|err| err.location.byte());
+    Err(errs)
 }
 
 struct Compiler<'c> {
-    enclosing: Option<&'c Self>,
     f_type: FunctionType,
-    state: CompilerState,
 
     chunks: Vec<Chunk>,
     errs: Vec<CompileErr>,
+    warnings: Vec<CompileErr>,
     values: FixedVec<Value, STACK_MAX>,
 
     source_code: &'c str,
+    interner: Rc<RefCell<Interner>>,
     tokens: Option<Peekable<IntoIter<LoxToken>>>,
+    options: CompileOptions,
+    statement_ends: Vec<StatementEnd>,
+    /// The locals in scope, in the order of their stack slots.
+    locals: Vec<Local>,
+    /// How many blocks deep the code being emitted is. Zero is the global scope.
+    scope_depth: usize,
+    /// The loops enclosing the code being emitted, innermost last.
+    loops: Vec<LoopContext>,
+    /// How many try statements' bodies the code being emitted is in.
+    try_depth: usize,
 
     previous: Option<LoxToken>,
     current: Option<LoxToken>,
 }
 
 impl<'c> Compiler<'c> {
-    fn new(f_type: FunctionType, enclosing: Option<&'c Self>, source_code: &'c str, tokens: Vec<LoxToken>) -> Self {
+    fn new(f_type: FunctionType, source_code: &'c str, tokens: Vec<LoxToken>, options: CompileOptions) -> Self {
+        // Punctuation compiles to nothing and most other tokens to a byte or two, so the
+        // token count is a close estimate that saves growing the code one reallocation at a time.
+        let chunk_capacity = tokens.len();
         Self {
-            enclosing: enclosing, 
             f_type,
             previous: None,
             current: None,
             tokens: Some(tokens.into_iter().peekable()),
-            source_code: source_code,
-            chunks: Vec::new(),
+            source_code,
+            interner: Rc::new(RefCell::new(Interner::new())),
+            options,
+            statement_ends: Vec::new(),
+            locals: Vec::new(),
+            scope_depth: 0,
+            loops: Vec::new(),
+            try_depth: 0,
+            chunks: Vec::with_capacity(chunk_capacity),
             errs: Vec::new(),
-            state: CompilerState::Declaration,
+            warnings: Vec::new(),
             values: FixedVec::<_, STACK_MAX>::new(),
         }
     }
 
-    fn compile(mut self) -> Result<(Vec<Chunk>, FixedVec<Value, STACK_MAX>), Vec<CompileErr>> {
-        while let Some(_) = self.tokens
+    fn with_interner(mut self, interner: Rc<RefCell<Interner>>) -> Self {
+        self.interner = interner;
+        self
+    }
+
+    /// A string object for this text, shared with any equal string already interned.
+    fn string_value(&self, string: &str) -> Value {
+        let string = self.interner.borrow_mut().intern(string);
+        Value::Object(Rc::new(Object::String(string)))
+    }
+
+    fn compile(mut self) -> Result<Program, Vec<CompileErr>> {
+        while self.tokens
                 .as_mut()
                 .expect("tokenizer to be yielded to active Compiler")
                 .peek()
+                .is_some()
         {
             match self.declaration() {
                 Ok(()) => {},
@@ -85,11 +216,43 @@ impl<'c> Compiler<'c> {
         }
 
         self.chunks.push(self.chunk(OpCode::Return as u8));
+
+        if self.errs.is_empty() {
+            if let Err(err) = verify_stack(&self.chunks, &self.statement_ends, 0) {
+                self.errs.push(err);
+            }
+        }
     
-        if self.errs.len() > 0 {
+        if !self.errs.is_empty() {
+            Err(self.errs)
+        } else {
+            Ok(Program {
+                code: self.chunks,
+                values: self.values,
+                warnings: self.warnings,
+            })
+        }
+    }
+
+    fn parse(mut self) -> Result<Vec<Statement>, Vec<CompileErr>> {
+        let mut statements = Vec::new();
+        while self.tokens
+                .as_mut()
+                .expect("tokenizer to be yielded to active Compiler")
+                .peek()
+                .is_some()
+        {
+            match self.parse_statement() {
+                Ok(Some(statement)) => statements.push(statement),
+                Ok(None) => {},
+                Err(()) => self.panic_mode_recovery(),
+            }
+        }
+
+        if !self.errs.is_empty() {
             Err(self.errs)
         } else {
-            Ok((self.chunks, self.values))
+            Ok(statements)
         }
     }
 
@@ -107,70 +270,876 @@ impl<'c> Compiler<'c> {
     }
 
     fn statement(&mut self) -> Result<(), ()> {
-        if self.match_token(TokenKind::Class) {
+        match self.parse_statement()? {
+            None => Ok(()),
+            Some(statement) => {
+                let is_final = self.tokens
+                    .as_mut()
+                    .expect("tokenizer to be in active compiler")
+                    .peek()
+                    .is_none();
+                self.emit_statement(statement, is_final)
+            }
+        }
+    }
+
+    /// Parses one statement without emitting any code for it. Empty statements give None.
+    fn parse_statement(&mut self) -> Result<Option<Statement>, ()> {
+        if self.match_token(TokenKind::RightBrace) {
+            // There is no block for this brace to close, so the program 
+            // was already complete and everything from here on is stray.
+            self.errs.push(self.error_at_current(CompileErrKind::TrailingTokens));
+            Err(())
+        } else if self.match_token(TokenKind::Semicolon) {
+            // An empty statement. There is nothing to compile.
+            Ok(None)
+        } else if self.match_token(TokenKind::Class) {
             todo!();
         } else if self.match_token(TokenKind::Fun) {
-            todo!();
+            self.function_declaration().map(Some)
+        } else if self.match_token(TokenKind::Return) {
+            let location = self.current
+                .expect("return we just matched to be the current token")
+                .get_start();
+            let next = self.tokens
+                .as_mut()
+                .expect("tokenizer to be in active compiler")
+                .peek()
+                .map(|token| token.kind());
+            let value = if next == Some(TokenKind::Semicolon) || (self.options.implicit_semicolons && self.at_line_break()) {
+                None
+            } else {
+                Some(self.parse_expression()?)
+            };
+            self.end_statement()?;
+            Ok(Some(Statement::Return { value, location }))
         } else if self.match_token(TokenKind::Print) {
-            self.expression_statement()?;
-            self.chunks.push(self.chunk(OpCode::Print as u8));
-            if !self.match_token(TokenKind::Semicolon) {
-                self.errs.push(
-                    self.error(CompileErrKind::MissingSemicolon)
-                );
-                return Err(());
-            }
-            return Ok(());
+            let expr = self.parse_expression()?;
+            self.end_statement()?;
+            Ok(Some(Statement::Print(expr)))
+        } else if self.match_token(TokenKind::Var) {
+            self.var_declaration().map(Some)
         } else if self.match_token(TokenKind::If) {
-            todo!();
+            self.if_statement().map(Some)
+        } else if self.match_token(TokenKind::While) {
+            self.consume(TokenKind::LeftParen)?;
+            let condition = self.parse_expression()?;
+            self.consume(TokenKind::RightParen)?;
+            let body = self.parse_statement()?.map(Box::new);
+            Ok(Some(Statement::While { condition, body }))
+        } else if self.match_token(TokenKind::For) {
+            self.for_statement().map(Some)
+        } else if self.match_token(TokenKind::LeftBrace) {
+            self.block().map(Some)
+        } else if self.match_token(TokenKind::Try) {
+            self.try_statement().map(Some)
+        } else if self.match_token(TokenKind::Break) {
+            let location = self.current
+                .expect("break we just matched to be the current token")
+                .get_start();
+            self.end_statement()?;
+            Ok(Some(Statement::Break { location }))
         } else {
-            self.expression_statement()?;
-            if !self.match_token(TokenKind::Semicolon) {
-                self.errs.push(
-                    self.error(CompileErrKind::MissingSemicolon)
-                );
-                return Err(());
+            let location = self.peek_location();
+            let expr = self.parse_expression()?;
+            self.end_statement()?;
+            Ok(Some(Statement::Expression { expr, location }))
+        }
+    }
+
+    /// Emits the code for a parsed statement. Only the program's final statement can keep its value.
+    fn emit_statement(&mut self, statement: Statement, is_final: bool) -> Result<(), ()> {
+        match statement {
+            Statement::Print(expr) => {
+                self.emit_expression(expr)?;
+                self.chunks.push(self.chunk(OpCode::Print as u8));
             }
+            Statement::Var(declarations) => {
+                for declaration in declarations {
+                    if self.scope_depth > 0 {
+                        // A local's value stays on the stack, in the slot it was declared in.
+                        self.declare_local(declaration.name)?;
+                        let initialized = match declaration.initializer {
+                            Some(initializer) => self.emit_expression(initializer),
+                            None => {
+                                self.emit_constant(Value::Nil);
+                                Ok(())
+                            }
+                        };
+                        // Even a broken initializer finishes the declaration, so later reads aren't reported too.
+                        self.locals
+                            .last_mut()
+                            .expect("the local we just declared to be in scope")
+                            .initialized = true;
+                        initialized?;
+                        continue;
+                    }
+
+                    let name = self.string_value(&self.source_code[declaration.name.range()]);
+                    let name_index = self.make_constant(name)?;
+
+                    match declaration.initializer {
+                        Some(initializer) => self.emit_expression(initializer)?,
+                        None => self.emit_constant(Value::Nil),
+                    }
+
+                    self.chunks.push(self.chunk(OpCode::DefineGlobal as u8));
+                    self.chunks.push(self.chunk(name_index));
+                }
+            }
+            Statement::Expression { expr, location } => {
+                let has_side_effects = Self::has_side_effects(&expr);
+                self.emit_expression(expr)?;
+
+                if self.options.keep_final_expression && is_final {
+                    self.mark_statement_end(1);
+                    return Ok(());
+                }
+
+                if !has_side_effects {
+                    self.warnings.push(CompileErr {
+                        kind: CompileErrKind::UnusedExpressionResult,
+                        location,
+                    });
+                }
+                self.chunks.push(self.chunk(OpCode::Pop as u8));
+            }
+            Statement::If { condition, then_branch, else_branch } => {
+                self.emit_expression(condition)?;
+                // JumpIfFalse leaves the condition on the stack, so each branch pops it.
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.chunks.push(self.chunk(OpCode::Pop as u8));
+                if let Some(then_branch) = then_branch {
+                    self.emit_body(*then_branch)?;
+                }
+
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump)?;
+                self.chunks.push(self.chunk(OpCode::Pop as u8));
+                if let Some(else_branch) = else_branch {
+                    self.emit_body(*else_branch)?;
+                }
+                self.patch_jump(else_jump)?;
+            }
+            Statement::While { condition, body } => {
+                let loop_start = self.chunks.len();
+                self.emit_expression(condition)?;
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.chunks.push(self.chunk(OpCode::Pop as u8));
+                let break_jumps = self.emit_loop_body(body)?;
+                self.emit_loop(loop_start)?;
+
+                self.patch_jump(exit_jump)?;
+                self.chunks.push(self.chunk(OpCode::Pop as u8));
+                // A break has already popped the condition, so it skips the Pop above.
+                for break_jump in break_jumps {
+                    self.patch_jump(break_jump)?;
+                }
+            }
+            Statement::For { initializer, condition, increment, body } => {
+                // A variable declared by the initializer is local to the loop.
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.emit_statement(*initializer, false)?;
+                }
+
+                let loop_start = self.chunks.len();
+                // Without a condition, the loop only ends by leaving its body.
+                let exit_jump = match condition {
+                    None => None,
+                    Some(condition) => {
+                        self.emit_expression(condition)?;
+                        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        self.chunks.push(self.chunk(OpCode::Pop as u8));
+                        Some(exit_jump)
+                    }
+                };
+
+                let break_jumps = self.emit_loop_body(body)?;
+                // The increment comes after the body, so skipping the rest of an
+                // iteration means jumping here rather than back to the condition.
+                if let Some(increment) = increment {
+                    self.emit_expression(increment)?;
+                    self.chunks.push(self.chunk(OpCode::Pop as u8));
+                }
+                self.emit_loop(loop_start)?;
+
+                if let Some(exit_jump) = exit_jump {
+                    self.patch_jump(exit_jump)?;
+                    self.chunks.push(self.chunk(OpCode::Pop as u8));
+                }
+                for break_jump in break_jumps {
+                    self.patch_jump(break_jump)?;
+                }
+                self.end_scope();
+            }
+            Statement::Function(declaration) => {
+                let name = declaration.name;
+                if self.scope_depth > 0 {
+                    self.declare_local(name)?;
+                    let function = self.emit_function(declaration);
+                    self.locals
+                        .last_mut()
+                        .expect("the local we just declared to be in scope")
+                        .initialized = true;
+                    self.emit_constant(function?);
+                } else {
+                    let function = self.emit_function(declaration)?;
+                    let name = self.string_value(&self.source_code[name.range()]);
+                    let name_index = self.make_constant(name)?;
+                    self.emit_constant(function);
+                    self.chunks.push(self.chunk(OpCode::DefineGlobal as u8));
+                    self.chunks.push(self.chunk(name_index));
+                }
+            }
+            Statement::Return { value, location } => {
+                if matches!(self.f_type, FunctionType::Script) {
+                    self.errs.push(CompileErr { kind: CompileErrKind::ReturnOutsideFunction, location });
+                    return Err(());
+                }
+
+                match value {
+                    Some(value) => {
+                        // Inside a try, the frame has to outlive the call so the handler can catch its errors.
+                        let is_tail_call = self.try_depth == 0
+                            && matches!(value.value, ExpressionTreeNode::Branch(ExpressionBranch::Call { .. }, _));
+                        self.emit_expression(value)?;
+                        if is_tail_call {
+                            let call = self.chunks.len() - 2;
+                            self.chunks[call].op = OpCode::TailCall as u8;
+                        }
+                    }
+                    None => self.emit_constant(Value::Nil),
+                }
+                self.chunks.push(self.chunk(OpCode::Return as u8));
+            }
+            Statement::Break { location } => {
+                let Some(innermost) = self.loops.last() else {
+                    self.errs.push(CompileErr { kind: CompileErrKind::BreakOutsideLoop, location });
+                    return Err(());
+                };
+
+                // Locals declared inside the loop are popped here without closing their scopes,
+                // since any code after the break is still inside them.
+                let loop_depth = innermost.scope_depth;
+                // The handlers of try statements inside the loop are left behind too.
+                for _ in innermost.try_depth..self.try_depth {
+                    self.chunks.push(self.chunk(OpCode::PopHandler as u8));
+                }
+                let inner_locals = self.locals
+                    .iter()
+                    .rev()
+                    .take_while(|local| local.depth > loop_depth)
+                    .count();
+                for _ in 0..inner_locals {
+                    self.chunks.push(self.chunk(OpCode::Pop as u8));
+                }
+
+                let break_jump = self.emit_jump(OpCode::Jump);
+                self.loops
+                    .last_mut()
+                    .expect("the loop we just found to still enclose the break")
+                    .break_jumps
+                    .push(break_jump);
+            }
+            Statement::Try { body, error_name, handler } => {
+                // A runtime error in the body unwinds the stack back to how it is here,
+                // then jumps to the handler with the error's message pushed.
+                let handler_jump = self.emit_jump(OpCode::PushHandler);
+                self.try_depth += 1;
+                let mut result = self.emit_statement(Statement::Block(body), false);
+                self.try_depth -= 1;
+                self.chunks.push(self.chunk(OpCode::PopHandler as u8));
+                let end_jump = self.emit_jump(OpCode::Jump);
+
+                self.patch_jump(handler_jump)?;
+                // The message takes the slot of a local named by the catch clause.
+                self.begin_scope();
+                result = result.and(self.declare_local(error_name));
+                if let Some(local) = self.locals.last_mut() {
+                    local.initialized = true;
+                }
+                for statement in handler {
+                    result = result.and(self.emit_statement(statement, false));
+                }
+                self.end_scope();
+                self.patch_jump(end_jump)?;
+                result?;
+            }
+            Statement::Block(statements) => {
+                self.begin_scope();
+                // Keep going after an error, so the scope is still closed and later errors are found.
+                let mut result = Ok(());
+                for statement in statements {
+                    result = result.and(self.emit_statement(statement, false));
+                }
+                self.end_scope();
+                result?;
+            }
+        }
+
+        self.mark_statement_end(0);
+        Ok(())
+    }
+
+    /// Emits a function's body into code of its own and returns the function. The function
+    /// being called takes slot 0, under its own name so it can call itself, and its
+    /// parameters take the slots after it.
+    fn emit_function(&mut self, declaration: FunctionDeclaration) -> Result<Value, ()> {
+        let FunctionDeclaration { name, params, body } = declaration;
+        let enclosing = self.enter_function(FunctionType::Function);
+        self.locals.push(Local { name, depth: 0, initialized: true, is_captured: false });
+        self.begin_scope();
+        // Keep going after an error, so the enclosing function's state is always restored.
+        let mut result = Ok(());
+        for param in params.iter() {
+            result = result.and(self.declare_local(*param));
+        }
+        for local in self.locals.iter_mut() {
+            local.initialized = true;
+        }
+
+        let start_depth = self.locals.len();
+        for statement in body {
+            result = result.and(self.emit_statement(statement, false));
+        }
+        // Falling off the end of the body returns nil.
+        self.emit_constant(Value::Nil);
+        self.chunks.push(self.chunk(OpCode::Return as u8));
+
+        let (chunks, statement_ends) = self.leave_function(enclosing);
+        result?;
+        if let Err(err) = verify_stack(&chunks, &statement_ends, start_depth) {
+            self.errs.push(err);
+            return Err(());
+        }
+
+        let function = Function {
+            // More than 255 parameters is a compile error, so this fits in any program that runs.
+            arity: params.len() as u8,
+            chunks: chunks.into(),
+            name: self.source_code[name.range()].to_string(),
+            ..Function::new()
+        };
+        Ok(Value::Object(Rc::new(Object::Function(function))))
+    }
+
+    /// Sets aside the state of the code being emitted, so a function's body can be emitted from scratch.
+    fn enter_function(&mut self, f_type: FunctionType) -> FunctionState {
+        FunctionState {
+            f_type: std::mem::replace(&mut self.f_type, f_type),
+            chunks: std::mem::take(&mut self.chunks),
+            statement_ends: std::mem::take(&mut self.statement_ends),
+            locals: std::mem::take(&mut self.locals),
+            scope_depth: std::mem::replace(&mut self.scope_depth, 0),
+            loops: std::mem::take(&mut self.loops),
+            try_depth: std::mem::replace(&mut self.try_depth, 0),
+        }
+    }
+
+    /// Goes back to emitting the code that enter_function set aside, returning the function's code.
+    fn leave_function(&mut self, enclosing: FunctionState) -> (Vec<Chunk>, Vec<StatementEnd>) {
+        self.f_type = enclosing.f_type;
+        self.locals = enclosing.locals;
+        self.scope_depth = enclosing.scope_depth;
+        self.loops = enclosing.loops;
+        self.try_depth = enclosing.try_depth;
+        let chunks = std::mem::replace(&mut self.chunks, enclosing.chunks);
+        let statement_ends = std::mem::replace(&mut self.statement_ends, enclosing.statement_ends);
+        (chunks, statement_ends)
+    }
+
+    /// Emits the body of an if, while, or for. Inside a block the body gets its own scope,
+    /// so a var declared as the whole body can't leave its value on the stack.
+    fn emit_body(&mut self, body: Statement) -> Result<(), ()> {
+        if self.scope_depth == 0 {
+            return self.emit_statement(body, false);
+        }
+
+        self.begin_scope();
+        let result = self.emit_statement(body, false);
+        self.end_scope();
+        result
+    }
+
+    /// Emits the body of a loop, returning the jumps its breaks need pointed at the loop's exit.
+    fn emit_loop_body(&mut self, body: Option<Box<Statement>>) -> Result<Vec<usize>, ()> {
+        self.loops.push(LoopContext { scope_depth: self.scope_depth, try_depth: self.try_depth, break_jumps: Vec::new() });
+        let result = match body {
+            None => Ok(()),
+            Some(body) => self.emit_body(*body),
+        };
+        let context = self.loops.pop().expect("the loop's context to still be there");
+        result.map(|()| context.break_jumps)
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope_depth += 1;
+    }
+
+    /// Closes the innermost scope, popping each of its locals off the stack.
+    fn end_scope(&mut self) {
+        self.scope_depth -= 1;
+        while self.locals.last().is_some_and(|local| local.depth > self.scope_depth) {
+            self.locals.pop();
             self.chunks.push(self.chunk(OpCode::Pop as u8));
+        }
+    }
+
+    /// Adds a local to the innermost scope. It can't be read until it is marked initialized.
+    fn declare_local(&mut self, name: LoxToken) -> Result<(), ()> {
+        let text = &self.source_code[name.range()];
+        let redeclared = self.locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth == self.scope_depth)
+            .any(|local| &self.source_code[local.name.range()] == text);
+        if redeclared {
+            self.errs.push(CompileErr { kind: CompileErrKind::DuplicateLocal, location: name.get_start() });
+            return Err(());
+        }
+
+        if self.locals.len() == LOCALS_MAX {
+            self.errs.push(CompileErr { kind: CompileErrKind::TooManyLocals, location: name.get_start() });
+            return Err(());
+        }
+
+        self.locals.push(Local {
+            name,
+            depth: self.scope_depth,
+            initialized: false,
+            is_captured: false,
+        });
+        Ok(())
+    }
+
+    /// The stack slot of the innermost local with this name, or None if it is a global.
+    fn resolve_local(&mut self, name: LoxToken) -> Result<Option<u8>, ()> {
+        let text = &self.source_code[name.range()];
+        let found = self.locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| &self.source_code[local.name.range()] == text);
+
+        match found {
+            None => Ok(None),
+            Some((_, local)) if !local.initialized => {
+                self.errs.push(CompileErr { kind: CompileErrKind::LocalInOwnInitializer, location: name.get_start() });
+                Err(())
+            }
+            Some((slot, _)) => Ok(Some(slot as u8)),
+        }
+    }
+
+    /// Emits a jump with a placeholder distance, returning where the distance goes so it can be patched.
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.chunks.push(self.chunk(op as u8));
+        self.chunks.push(self.chunk(u8::MAX));
+        self.chunks.push(self.chunk(u8::MAX));
+        self.chunks.len() - 2
+    }
+
+    /// Emits a jump back to `loop_start`.
+    fn emit_loop(&mut self, loop_start: usize) -> Result<(), ()> {
+        self.chunks.push(self.chunk(OpCode::Loop as u8));
+        // The distance is counted from the end of the loop instruction, after its two operand bytes.
+        let distance = self.chunks.len() + 2 - loop_start;
+        let Ok(distance) = u16::try_from(distance) else {
+            self.errs.push(self.error(CompileErrKind::JumpTooLarge));
+            return Err(());
+        };
+
+        let [high, low] = distance.to_be_bytes();
+        self.chunks.push(self.chunk(high));
+        self.chunks.push(self.chunk(low));
+        Ok(())
+    }
+
+    /// Points the jump whose distance is at `operand` to the next instruction to be emitted.
+    fn patch_jump(&mut self, operand: usize) -> Result<(), ()> {
+        // The distance is counted from the end of the jump instruction.
+        let distance = self.chunks.len() - operand - 2;
+        let Ok(distance) = u16::try_from(distance) else {
+            self.errs.push(self.error(CompileErrKind::JumpTooLarge));
+            return Err(());
+        };
+
+        let [high, low] = distance.to_be_bytes();
+        self.chunks[operand].op = high;
+        self.chunks[operand + 1].op = low;
+        Ok(())
+    }
+
+    /// Records that the current statement's code ends here, leaving `depth` values on the stack
+    /// above the locals in scope. This is how scopes are checked to pop every local they declare.
+    fn mark_statement_end(&mut self, depth: usize) {
+        self.statement_ends.push(StatementEnd {
+            offset: self.chunks.len(),
+            depth: depth + self.locals.len(),
+            location: self.location(),
+        });
+    }
+
+    /// Whether evaluating the expression can do anything besides produce its value.
+    fn has_side_effects(expr: &BinaryTreeNode<ExpressionTreeNode>) -> bool {
+        expr.dfs_preorder_iter().any(|node| {
+            matches!(
+                node,
+                ExpressionTreeNode::Branch(ExpressionBranch::Call { .. }, _)
+                | ExpressionTreeNode::Branch(ExpressionBranch::Operator(Operator::Assignment), _)
+            )
+        })
+    }
+
+    /// Parses the remainder of a var declaration after 'var' has been consumed.
+    /// Multiple comma-separated variables can be declared in one statement, and
+    /// each one without an initializer is defined as nil.
+    fn var_declaration(&mut self) -> Result<Statement, ()> {
+        let mut declarations = Vec::new();
+        loop {
+            self.consume(TokenKind::Identifier)?;
+
+            let name = self.current.expect("identifier we just matched to be the current token");
+            let initializer = if self.match_token(TokenKind::Equal) {
+                Some(self.parse_expression()?)
+            } else {
+                None
+            };
+            declarations.push(VarDeclaration { name, initializer });
+
+            if !self.match_token(TokenKind::Comma) {
+                break;
+            }
+        }
+
+        self.end_statement()?;
+        Ok(Statement::Var(declarations))
+    }
+
+    /// Parses the rest of a function declaration after 'fun' has been consumed.
+    fn function_declaration(&mut self) -> Result<Statement, ()> {
+        self.consume(TokenKind::Identifier)?;
+        let name = self.current.expect("identifier we just matched to be the current token");
+
+        self.consume(TokenKind::LeftParen)?;
+        let mut params = Vec::new();
+        if !self.match_token(TokenKind::RightParen) {
+            loop {
+                if params.len() == u8::MAX as usize {
+                    let location = self.peek_location();
+                    self.errs.push(CompileErr { kind: CompileErrKind::TooManyParameters, location });
+                }
+                self.consume(TokenKind::Identifier)?;
+                params.push(self.current.expect("identifier we just matched to be the current token"));
+                if !self.match_token(TokenKind::Comma) {
+                    break;
+                }
+            }
+            self.consume(TokenKind::RightParen)?;
+        }
+
+        self.consume(TokenKind::LeftBrace)?;
+        let Statement::Block(body) = self.block()? else {
+            unreachable!("block() to only parse blocks");
+        };
+        Ok(Statement::Function(FunctionDeclaration { name, params, body }))
+    }
+
+    /// Parses the rest of a try statement after 'try' has been consumed.
+    fn try_statement(&mut self) -> Result<Statement, ()> {
+        self.consume(TokenKind::LeftBrace)?;
+        let Statement::Block(body) = self.block()? else {
+            unreachable!("block() to only parse blocks");
+        };
+        self.consume(TokenKind::Catch)?;
+        self.consume(TokenKind::LeftParen)?;
+        self.consume(TokenKind::Identifier)?;
+        let error_name = self.current.expect("identifier we just matched to be the current token");
+        self.consume(TokenKind::RightParen)?;
+        self.consume(TokenKind::LeftBrace)?;
+        let Statement::Block(handler) = self.block()? else {
+            unreachable!("block() to only parse blocks");
+        };
+        Ok(Statement::Try { body, error_name, handler })
+    }
+
+    /// Parses the rest of an if statement. The then branch is parsed before looking for
+    /// an else, so a dangling else binds to the nearest if.
+    fn if_statement(&mut self) -> Result<Statement, ()> {
+        self.consume(TokenKind::LeftParen)?;
+        let condition = self.parse_expression()?;
+        self.consume(TokenKind::RightParen)?;
+
+        let then_branch = self.parse_statement()?.map(Box::new);
+        let else_branch = if self.match_token(TokenKind::Else) {
+            self.parse_statement()?.map(Box::new)
+        } else {
+            None
+        };
+
+        Ok(Statement::If { condition, then_branch, else_branch })
+    }
+
+    /// Parses the rest of a for statement. Each of its three clauses may be left out.
+    fn for_statement(&mut self) -> Result<Statement, ()> {
+        self.consume(TokenKind::LeftParen)?;
+        let initializer = if self.match_token(TokenKind::Semicolon) {
+            None
+        } else if self.match_token(TokenKind::Var) {
+            Some(Box::new(self.var_declaration()?))
+        } else {
+            let location = self.peek_location();
+            let expr = self.parse_expression()?;
+            self.consume(TokenKind::Semicolon)?;
+            Some(Box::new(Statement::Expression { expr, location }))
+        };
+
+        let condition = if self.match_token(TokenKind::Semicolon) {
+            None
+        } else {
+            let condition = self.parse_expression()?;
+            self.consume(TokenKind::Semicolon)?;
+            Some(condition)
+        };
+
+        let increment = if self.match_token(TokenKind::RightParen) {
+            None
+        } else {
+            let increment = self.parse_expression()?;
+            self.consume(TokenKind::RightParen)?;
+            Some(increment)
+        };
+
+        let body = self.parse_statement()?.map(Box::new);
+        Ok(Statement::For { initializer, condition, increment, body })
+    }
+
+    /// Parses the rest of a block after its opening brace. A statement that fails to parse
+    /// is skipped up to the next statement, so the rest of the block is still checked.
+    fn block(&mut self) -> Result<Statement, ()> {
+        let mut statements = Vec::new();
+        let mut had_err = false;
+        loop {
+            let next = self.tokens
+                .as_mut()
+                .expect("tokenizer to be in active compiler")
+                .peek()
+                .map(|token| token.kind());
+            if matches!(next, None | Some(TokenKind::RightBrace)) {
+                break;
+            }
+
+            match self.parse_statement() {
+                Ok(Some(statement)) => statements.push(statement),
+                Ok(None) => {},
+                Err(()) => {
+                    had_err = true;
+                    self.match_tokens_while(|token| {
+                        token.kind() != TokenKind::RightBrace && !starts_statement(token.kind())
+                    });
+                }
+            }
+        }
+
+        self.consume(TokenKind::RightBrace)?;
+        if had_err {
+            Err(())
+        } else {
+            Ok(Statement::Block(statements))
+        }
+    }
+
+    /// Consumes the next token if it has the given kind, and reports it as unexpected otherwise.
+    fn consume(&mut self, kind: TokenKind) -> Result<(), ()> {
+        if self.match_token(kind) {
             return Ok(());
         }
+
+        let err = self.unexpected(vec![kind]);
+        self.errs.push(CompileErr {
+            location: err.location,
+            kind: CompileErrKind::UnexpectedToken(err),
+        });
+        Err(())
     }
 
-    fn expression_statement(&mut self) -> Result<(), ()> {
+    /// Parses an expression, reporting any syntax errors inside it.
+    fn parse_expression(&mut self) -> Result<BinaryTreeNode<ExpressionTreeNode>, ()> {
         let expr = self.expression();
+        let mut had_err = false;
+        for node in expr.dfs_preorder_iter() {
+            if let ExpressionTreeNode::Leaf(ExpressionLeaf::Error(err), _) = node {
+                self.errs.push(CompileErr {
+                    location: err.location,
+                    kind: CompileErrKind::UnexpectedToken(err.clone()),
+                });
+                had_err = true;
+            }
+        }
 
+        if !had_err && self.at_missing_operator() {
+            let location = match self.current {
+                None => Position::default(),
+                Some(token) => token.get_end(),
+            };
+            self.errs.push(CompileErr { kind: CompileErrKind::MissingOperator, location });
+            had_err = true;
+        }
+
+        if had_err {
+            Err(())
+        } else {
+            Ok(expr)
+        }
+    }
+
+    /// Whether the expression just parsed ended with an operand and another operand follows
+    /// it, like `1 2`. The expression stopped there because nothing joins the two.
+    fn at_missing_operator(&mut self) -> bool {
+        let ends_operand = match self.current {
+            None => false,
+            Some(token) => matches!(
+                token.kind(),
+                TokenKind::Number | TokenKind::String | TokenKind::Identifier
+                | TokenKind::True | TokenKind::False | TokenKind::Nil | TokenKind::RightParen
+            ),
+        };
+        if !ends_operand || (self.options.implicit_semicolons && self.at_line_break()) {
+            return false;
+        }
+
+        let next = self.tokens
+            .as_mut()
+            .expect("tokenizer to be in active compiler")
+            .peek()
+            .map(|token| token.kind());
+        matches!(
+            next,
+            Some(TokenKind::Number | TokenKind::String | TokenKind::Identifier
+                | TokenKind::True | TokenKind::False | TokenKind::Nil | TokenKind::LeftParen)
+        )
+    }
+
+    fn emit_expression(&mut self, expr: BinaryTreeNode<ExpressionTreeNode>) -> Result<(), ()> {
+        // Short circuits jump over their right operand, and assignments never evaluate
+        // their target, so neither can be emitted by walking the tree in postorder.
+        let out_of_order = expr.dfs_preorder_iter().any(|node| {
+            matches!(
+                node,
+                ExpressionTreeNode::Branch(
+                    ExpressionBranch::Operator(Operator::And | Operator::Or | Operator::Coalesce | Operator::Assignment),
+                    _
+                )
+            )
+        });
+        if !out_of_order {
+            return self.emit_postorder(expr);
+        }
+
+        let BinaryTreeNode { value, left, right } = expr;
+        match value {
+            ExpressionTreeNode::Branch(ExpressionBranch::Operator(Operator::And), _) => {
+                let left = self.emit_operand(left);
+                // A falsey left operand is the result, so it skips the right operand.
+                let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.chunks.push(self.chunk(OpCode::Pop as u8));
+                let right = self.emit_operand(right);
+                self.patch_jump(end_jump)?;
+                left.and(right)
+            }
+            ExpressionTreeNode::Branch(ExpressionBranch::Operator(Operator::Assignment), _) => {
+                let value = self.emit_operand(right);
+                let target = left.expect("assignment() to only build assignments with a target");
+                let ExpressionTreeNode::Leaf(ExpressionLeaf::Variable(name), _) = target.value else {
+                    unreachable!("assignment() to reject targets other than variables");
+                };
+                let set = self.emit_variable(name, OpCode::SetLocal, OpCode::SetGlobal);
+                value.and(set)
+            }
+            ExpressionTreeNode::Branch(ExpressionBranch::Operator(Operator::Or), _) => {
+                let left = self.emit_operand(left);
+                // A truthy left operand is the result, so it jumps over the right operand.
+                let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                let end_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(else_jump)?;
+                self.chunks.push(self.chunk(OpCode::Pop as u8));
+                let right = self.emit_operand(right);
+                self.patch_jump(end_jump)?;
+                left.and(right)
+            }
+            ExpressionTreeNode::Branch(ExpressionBranch::Operator(Operator::Coalesce), _) => {
+                let left = self.emit_operand(left);
+                // A left operand other than nil is the result, so it skips the right operand.
+                let end_jump = self.emit_jump(OpCode::JumpIfNotNil);
+                self.chunks.push(self.chunk(OpCode::Pop as u8));
+                let right = self.emit_operand(right);
+                self.patch_jump(end_jump)?;
+                left.and(right)
+            }
+            ExpressionTreeNode::Branch(branch, _) => {
+                // A short circuit further down needs its own jumps,
+                // so each operand is emitted separately.
+                let left = self.emit_operand(left);
+                let right = self.emit_operand(right);
+                self.emit_branch(branch);
+                left.and(right)
+            }
+            ExpressionTreeNode::Leaf(..) => unreachable!("a leaf to have no operators"),
+        }
+    }
+
+    /// Emits a read or write of the variable with this name, using the local opcode
+    /// if it resolves to a local and the global one otherwise.
+    fn emit_variable(&mut self, name: LoxToken, local_op: OpCode, global_op: OpCode) -> Result<(), ()> {
+        if let Some(slot) = self.resolve_local(name)? {
+            self.chunks.push(self.chunk(local_op as u8));
+            self.chunks.push(self.chunk(slot));
+            return Ok(());
+        }
+
+        self.emit_global(&self.source_code[name.range()], global_op)
+    }
+
+    fn emit_global(&mut self, name: &str, op: OpCode) -> Result<(), ()> {
+        let name = self.string_value(name);
+        let name_index = self.make_constant(name)?;
+        self.chunks.push(self.chunk(op as u8));
+        self.chunks.push(self.chunk(name_index));
+        Ok(())
+    }
+
+    fn emit_operand(&mut self, operand: Option<Box<BinaryTreeNode<ExpressionTreeNode>>>) -> Result<(), ()> {
+        match operand {
+            None => Ok(()),
+            Some(operand) => self.emit_expression(*operand),
+        }
+    }
+
+    /// Emits an expression without short circuits by walking its tree in postorder.
+    fn emit_postorder(&mut self, expr: BinaryTreeNode<ExpressionTreeNode>) -> Result<(), ()> {
         let mut had_err = false;
         for node in expr.dfs_postorder() {
             match node {
-                ExpressionTreeNode::Branch(branch) => {
-                    match branch {
-                        ExpressionBranch::Operator(op) => {
-                            for code in op.to_bytecodes() {
-                                self.chunks.push(self.chunk(code as u8));
-                            }
-                        }
-                    }
-                }
-                ExpressionTreeNode::Leaf(leaf) => {
+                ExpressionTreeNode::Branch(branch, _) => self.emit_branch(branch),
+                ExpressionTreeNode::Leaf(leaf, _) => {
                     match leaf {
                         ExpressionLeaf::Value(value) => {
-                            self.chunks.push(self.chunk(OpCode::Constant as u8));
-                            if self.values.len() == self.values.capacity() {
-                                self.errs.push(self.error(CompileErrKind::TooManyValues))
-                            } else {
-                                self.chunks.push(self.chunk(self.values.len() as u8));
-                                // Already checked the u8::MAX condition, so this should be infallible
-                                self.values.push(value)
-                                    .expect("fixed vec to not overflow after checking condition");
+                            self.emit_constant(value);
+                        }
+                        ExpressionLeaf::Variable(name) => {
+                            if self.emit_variable(name, OpCode::GetLocal, OpCode::GetGlobal).is_err() {
+                                had_err = true;
                             }
                         }
-                        ExpressionLeaf::Error(err) => {
-                            self.errs.push(self.error(CompileErrKind::UnexpectedToken(err)));
-                            had_err = true;
+                        ExpressionLeaf::Native(name) => {
+                            if self.emit_global(name, OpCode::GetGlobal).is_err() {
+                                had_err = true;
+                            }
                         }
-                        other => {
-                            println!("{:?}", other);
-                            todo!();
+                        ExpressionLeaf::Error(_) => {
+                            // parse_expression has already reported it.
+                            had_err = true;
                         }
                     }
                 }
@@ -178,31 +1147,74 @@ impl<'c> Compiler<'c> {
         }
         
         if had_err {
-            return Err(());
+            Err(())
         } else {
-            return Ok(());
+            Ok(())
+        }
+    }
+
+    /// Emits what a branch does once its children have been emitted. Arguments
+    /// only hold their expressions, so they emit nothing of their own.
+    fn emit_branch(&mut self, branch: ExpressionBranch) {
+        match branch {
+            ExpressionBranch::Operator(op) => {
+                for code in op.to_bytecodes() {
+                    self.chunks.push(self.chunk(code as u8));
+                }
+            }
+            ExpressionBranch::Call { arg_count } => {
+                self.chunks.push(self.chunk(OpCode::Call as u8));
+                self.chunks.push(self.chunk(arg_count));
+            }
+            ExpressionBranch::Argument => {}
         }
     }
 
     fn expression(&mut self) -> BinaryTreeNode<ExpressionTreeNode> {
         self.assignment()
-        // TODO
     }
 
+    /// Assignment is right associative, so `a = b = c` assigns c to b and then to a.
+    /// The target is parsed as an ordinary expression, then checked once the '=' is found.
     fn assignment(&mut self) -> BinaryTreeNode<ExpressionTreeNode> {
-        self.or()
-        // TODO
+        let target = self.or();
+        if !self.match_token(TokenKind::Equal) {
+            return target;
+        }
+
+        let equals = self.current.expect("the '=' we just matched to be the current token");
+        let value = self.assignment();
+        match target.value {
+            ExpressionTreeNode::Leaf(ExpressionLeaf::Variable(_), _) => {
+                Self::binary_node(Operator::Assignment, target, value)
+            }
+            _ => {
+                self.errs.push(CompileErr {
+                    kind: CompileErrKind::InvalidAssignmentTarget,
+                    location: equals.get_start(),
+                });
+                value
+            }
+        }
     }
 
     fn or(&mut self) -> BinaryTreeNode<ExpressionTreeNode> {
-        let mut current = self.and();
+        let mut current = self.coalesce();
 
         while self.match_token(TokenKind::Or) {
-            current = BinaryTreeNode { 
-                value: ExpressionTreeNode::Branch(ExpressionBranch::Operator(Operator::Or)),
-                left: Some(Box::new(current)),
-                right: Some(Box::new(self.and())),
-            }
+            let right = self.coalesce();
+            current = Self::binary_node(Operator::Or, current, right);
+        }
+
+        current
+    }
+
+    fn coalesce(&mut self) -> BinaryTreeNode<ExpressionTreeNode> {
+        let mut current = self.and();
+
+        while self.match_token(TokenKind::QuestionQuestion) {
+            let right = self.and();
+            current = Self::binary_node(Operator::Coalesce, current, right);
         }
 
         current
@@ -212,13 +1224,8 @@ impl<'c> Compiler<'c> {
         let mut current = self.equality();
 
         while self.match_token(TokenKind::And) {
-            current = BinaryTreeNode { 
-                value: ExpressionTreeNode::Branch(
-                    ExpressionBranch::Operator(Operator::And)
-                ),
-                left: Some(Box::new(current)),
-                right: Some(Box::new(self.equality())),
-            }
+            let right = self.equality();
+            current = Self::binary_node(Operator::And, current, right);
         }
 
         current
@@ -229,29 +1236,11 @@ impl<'c> Compiler<'c> {
 
         loop {
             if self.match_token(TokenKind::EqualEqual) {
-                current = BinaryTreeNode { 
-                    value: ExpressionTreeNode::Branch(
-                        ExpressionBranch::Operator(Operator::Equal)
-                    ),
-                    left: Some(Box::new(current)),
-                    right: Some(Box::new(self.comparison())),
-                };
+                let right = self.comparison();
+                current = Self::binary_node(Operator::Equal, current, right);
             } else if self.match_token(TokenKind::BangEqual) {
-                current = BinaryTreeNode { 
-                    value: ExpressionTreeNode::Branch(
-                        ExpressionBranch::Operator(Operator::Equal)
-                    ),
-                    left: Some(Box::new(current)),
-                    right: Some(Box::new(self.comparison())),
-                };
-
-                current = BinaryTreeNode {
-                    value: ExpressionTreeNode::Branch(
-                        ExpressionBranch::Operator(Operator::Not)
-                    ),
-                    left: None,
-                    right: Some(Box::new(current))
-                };
+                let right = self.comparison();
+                current = Self::binary_node(Operator::NotEqual, current, right);
             } else {
                 return current;
             }
@@ -263,53 +1252,17 @@ impl<'c> Compiler<'c> {
 
         loop {
             if self.match_token(TokenKind::Greater) {
-                current = BinaryTreeNode {
-                    value: ExpressionTreeNode::Branch(
-                        ExpressionBranch::Operator(Operator::Greater)
-                    ),
-                    left: Some(Box::new(current)),
-                    right: Some(Box::new(self.term()))
-                };
+                let right = self.term();
+                current = Self::binary_node(Operator::Greater, current, right);
             } else if self.match_token(TokenKind::GreaterEqual) {
-                current = BinaryTreeNode {
-                    value: ExpressionTreeNode::Branch(
-                        ExpressionBranch::Operator(Operator::Less)
-                    ),
-                    left: Some(Box::new(current)),
-                    right: Some(Box::new(self.term()))
-                };
-
-                current = BinaryTreeNode {
-                    value: ExpressionTreeNode::Branch(
-                        ExpressionBranch::Operator(Operator::Not)
-                    ),
-                    left: None,
-                    right: Some(Box::new(current))
-                };
+                let right = self.term();
+                current = Self::binary_node(Operator::GreaterEqual, current, right);
             } else if self.match_token(TokenKind::Less) {
-                current = BinaryTreeNode {
-                    value: ExpressionTreeNode::Branch(
-                        ExpressionBranch::Operator(Operator::Less)
-                    ),
-                    left: Some(Box::new(current)),
-                    right: Some(Box::new(self.term()))
-                };
+                let right = self.term();
+                current = Self::binary_node(Operator::Less, current, right);
             } else if self.match_token(TokenKind::LessEqual) {
-                current = BinaryTreeNode {
-                    value: ExpressionTreeNode::Branch(
-                        ExpressionBranch::Operator(Operator::Greater)
-                    ),
-                    left: Some(Box::new(current)),
-                    right: Some(Box::new(self.term()))
-                };
-
-                current = BinaryTreeNode {
-                    value: ExpressionTreeNode::Branch(
-                        ExpressionBranch::Operator(Operator::Not)
-                    ),
-                    left: None,
-                    right: Some(Box::new(current))
-                };
+                let right = self.term();
+                current = Self::binary_node(Operator::LessEqual, current, right);
             }
             else {
                 return current;
@@ -322,21 +1275,11 @@ impl<'c> Compiler<'c> {
 
         loop {
             if self.match_token(TokenKind::Minus) {
-                current = BinaryTreeNode {
-                    value: ExpressionTreeNode::Branch(
-                        ExpressionBranch::Operator(Operator::Subtract)
-                    ),
-                    left: Some(Box::new(current)),
-                    right: Some(Box::new(self.factor()))
-                };
+                let right = self.factor();
+                current = Self::binary_node(Operator::Subtract, current, right);
             } else if self.match_token(TokenKind::Plus) {
-                current = BinaryTreeNode {
-                    value: ExpressionTreeNode::Branch(
-                        ExpressionBranch::Operator(Operator::Add)
-                    ),
-                    left: Some(Box::new(current)),
-                    right: Some(Box::new(self.factor()))
-                };
+                let right = self.factor();
+                current = Self::binary_node(Operator::Add, current, right);
             } else {
                 return current;
             }
@@ -348,21 +1291,14 @@ impl<'c> Compiler<'c> {
 
         loop {
             if self.match_token(TokenKind::Star) {
-                current = BinaryTreeNode {
-                    value: ExpressionTreeNode::Branch(
-                        ExpressionBranch::Operator(Operator::Multiply)
-                    ),
-                    left: Some(Box::new(current)),
-                    right: Some(Box::new(self.unary()))
-                };
+                let right = self.unary();
+                current = Self::binary_node(Operator::Multiply, current, right);
             } else if self.match_token(TokenKind::Slash) {
-                current = BinaryTreeNode {
-                    value: ExpressionTreeNode::Branch(
-                        ExpressionBranch::Operator(Operator::Divide)
-                    ),
-                    left: Some(Box::new(current)),
-                    right: Some(Box::new(self.unary()))
-                };
+                let right = self.unary();
+                current = Self::binary_node(Operator::Divide, current, right);
+            } else if self.match_token(TokenKind::Percent) {
+                let right = self.unary();
+                current = Self::binary_node(Operator::Modulo, current, right);
             } else {
                 return current;
             }
@@ -371,133 +1307,271 @@ impl<'c> Compiler<'c> {
 
     fn unary(&mut self) -> BinaryTreeNode<ExpressionTreeNode> {
         if self.match_token(TokenKind::Bang) {
-            return BinaryTreeNode {
-                value: ExpressionTreeNode::Branch(
-                    ExpressionBranch::Operator(Operator::Not)
-                ),
-                left: None,
-                right: Some(Box::new(self.unary()))
-            };
+            let start = self.operator_start();
+            let operand = self.unary();
+            Self::unary_node(Operator::Not, start, operand)
         } else if self.match_token(TokenKind::Minus) {
-            return BinaryTreeNode {
-                value: ExpressionTreeNode::Branch(
-                    ExpressionBranch::Operator(Operator::SignFlip)
-                ),
-                left: None,
-                right: Some(Box::new(self.unary()))
-            };
+            let start = self.operator_start();
+            let operand = self.unary();
+            Self::unary_node(Operator::SignFlip, start, operand)
         } else {
-            return self.call();
+            self.call()
+        }
+    }
+
+    /// Where the operator that was just matched starts.
+    fn operator_start(&self) -> Position {
+        match self.current {
+            None => Position::default(),
+            Some(token) => token.get_start(),
+        }
+    }
+
+    /// Builds a node applying an operator to two operands. It spans from the start of the left operand to the end of the right.
+    fn binary_node(op: Operator, left: BinaryTreeNode<ExpressionTreeNode>, right: BinaryTreeNode<ExpressionTreeNode>) -> BinaryTreeNode<ExpressionTreeNode> {
+        let span = Span::new(left.value.span().start(), right.value.span().end());
+        BinaryTreeNode {
+            value: ExpressionTreeNode::Branch(ExpressionBranch::Operator(op), span),
+            left: Some(Box::new(left)),
+            right: Some(Box::new(right)),
+        }
+    }
+
+    /// Builds a node applying a prefix operator. It spans from the operator to the end of the operand.
+    fn unary_node(op: Operator, start: Position, operand: BinaryTreeNode<ExpressionTreeNode>) -> BinaryTreeNode<ExpressionTreeNode> {
+        let span = Span::new(start, operand.value.span().end());
+        BinaryTreeNode {
+            value: ExpressionTreeNode::Branch(ExpressionBranch::Operator(op), span),
+            left: None,
+            right: Some(Box::new(operand)),
         }
     }
 
     fn call(&mut self) -> BinaryTreeNode<ExpressionTreeNode> {
-        self.primary()
-        // TODO
+        let mut current = self.primary();
+        loop {
+            if self.match_token(TokenKind::LeftParen) {
+                current = self.finish_call(current);
+            } else if self.match_token(TokenKind::Dot) {
+                current = self.method_call(current);
+            } else {
+                break current;
+            }
+        }
+    }
+
+    /// Parses a method call after its '.', and lowers it to a call to the native in
+    /// NUMBER_METHODS that the method stands for, with the receiver as its argument.
+    fn method_call(&mut self, receiver: BinaryTreeNode<ExpressionTreeNode>) -> BinaryTreeNode<ExpressionTreeNode> {
+        if !self.match_token(TokenKind::Identifier) {
+            return Self::error_node(self.unexpected(vec![TokenKind::Identifier]));
+        }
+        let name = self.current.expect("the method name we just matched to be the current token");
+        if !self.match_token(TokenKind::LeftParen) {
+            return Self::error_node(self.unexpected(vec![TokenKind::LeftParen]));
+        }
+        if !self.match_token(TokenKind::RightParen) {
+            return Self::error_node(self.unexpected(vec![TokenKind::RightParen]));
+        }
+
+        let method = &self.source_code[name.range()];
+        let Some(&(_, native)) = NUMBER_METHODS.iter().find(|(known, _)| *known == method) else {
+            self.errs.push(CompileErr { kind: CompileErrKind::UnknownMethod(method.to_string()), location: name.get_start() });
+            return receiver;
+        };
+
+        let end = self.current.expect("the ')' we just matched to be the current token").span().end();
+        let span = Span::new(receiver.value.span().start(), end);
+        let callee = BinaryTreeNode {
+            value: ExpressionTreeNode::Leaf(ExpressionLeaf::Native(native), name.span()),
+            left: None,
+            right: None,
+        };
+        let argument = BinaryTreeNode {
+            value: ExpressionTreeNode::Branch(ExpressionBranch::Argument, receiver.value.span()),
+            left: Some(Box::new(receiver)),
+            right: None,
+        };
+        BinaryTreeNode {
+            value: ExpressionTreeNode::Branch(ExpressionBranch::Call { arg_count: 1 }, span),
+            left: Some(Box::new(callee)),
+            right: Some(Box::new(argument)),
+        }
+    }
+
+    /// Parses the arguments of a call after its '('.
+    fn finish_call(&mut self, callee: BinaryTreeNode<ExpressionTreeNode>) -> BinaryTreeNode<ExpressionTreeNode> {
+        let mut arguments = Vec::new();
+        if !self.match_token(TokenKind::RightParen) {
+            loop {
+                if arguments.len() == u8::MAX as usize {
+                    let location = self.peek_location();
+                    self.errs.push(CompileErr { kind: CompileErrKind::TooManyArguments, location });
+                }
+                arguments.push(self.expression());
+                if !self.match_token(TokenKind::Comma) {
+                    break;
+                }
+            }
+            if !self.match_token(TokenKind::RightParen) {
+                return Self::error_node(self.unexpected(vec![TokenKind::Comma, TokenKind::RightParen]));
+            }
+        }
+
+        let end = self.current.expect("the ')' we just matched to be the current token").span().end();
+        let span = Span::new(callee.value.span().start(), end);
+        let arg_count = arguments.len().min(u8::MAX as usize) as u8;
+        let mut rest = None;
+        for argument in arguments.into_iter().rev() {
+            rest = Some(Box::new(BinaryTreeNode {
+                value: ExpressionTreeNode::Branch(ExpressionBranch::Argument, argument.value.span()),
+                left: Some(Box::new(argument)),
+                right: rest,
+            }));
+        }
+        BinaryTreeNode {
+            value: ExpressionTreeNode::Branch(ExpressionBranch::Call { arg_count }, span),
+            left: Some(Box::new(callee)),
+            right: rest,
+        }
     }
     
     fn primary(&mut self) -> BinaryTreeNode<ExpressionTreeNode> {
         let token = self.next_token();
         match token {
-            None => BinaryTreeNode {
-                value: ExpressionTreeNode::Leaf(
-                    ExpressionLeaf::Error(Unexpected { 
-                        expected: vec![
-                            TokenKind::True,
-                            TokenKind::False,
-                            TokenKind::Number,
-                            TokenKind::String,
-                            TokenKind::Nil,
-                        ], 
-                        actual: TokenKind::EOF, 
-                        location: match self.previous {
-                            None => Position::default(),
-                            Some(token) => token.get_start()
-                        }
-                    })
-                ),
-                left: None,
-                right: None,
-            },
+            None => Self::error_node(Unexpected { 
+                expected: vec![
+                    TokenKind::True,
+                    TokenKind::False,
+                    TokenKind::Number,
+                    TokenKind::String,
+                    TokenKind::Nil,
+                    TokenKind::Identifier,
+                ], 
+                actual: TokenKind::EOF, 
+                location: match self.previous {
+                    None => Position::default(),
+                    Some(token) => token.get_start()
+                }
+            }),
             Some(token) => {
                 match token.kind() {
-                    TokenKind::True => Self::value_node(Value::Boolean(true)),
-                    TokenKind::False => Self::value_node(Value::Boolean(false)),
+                    TokenKind::True => Self::value_node(Value::Boolean(true), token.span()),
+                    TokenKind::False => Self::value_node(Value::Boolean(false), token.span()),
                     TokenKind::Number => Self::value_node(
                         Value::Number(
                             self.source_code[token.range()].parse::<f64>().expect("Number to successfully parse to f64")
-                        )
+                        ),
+                        token.span(),
                     ),
                     TokenKind::String => {
                         let source = &self.source_code[token.range()];
                         // Can't reference the source code because we want to free that string before runtime.
                         // Instead, clone it.
-                        let source = source[1..source.len() - 1].to_string();
-                        Self::value_node(
-                        Value::Object(Rc::new(
-                            Object::String(
-                                source.into()
-                            ))
-                        ))
+                        let source = unescape(&source[1..source.len() - 1])
+                            .expect("string escapes to have been checked by the tokenizer");
+                        Self::value_node(self.string_value(&source), token.span())
                     }
-                    TokenKind::Nil => Self::value_node(Value::Nil),
+                    TokenKind::Nil => Self::value_node(Value::Nil, token.span()),
+                    TokenKind::Identifier => BinaryTreeNode {
+                        value: ExpressionTreeNode::Leaf(ExpressionLeaf::Variable(token), token.span()),
+                        left: None,
+                        right: None,
+                    },
                     TokenKind::LeftParen => {
                         // logical groupings reset to lowest precedence level
-                        let result = self.or();
+                        let result = self.expression();
                         if !self.match_token(TokenKind::RightParen) {
-                            return BinaryTreeNode {
-                                value: ExpressionTreeNode::Leaf(
-                                    ExpressionLeaf::Error(Unexpected { 
-                                        expected: vec![
-                                            TokenKind::RightParen
-                                        ], 
-                                        actual: match self.tokens
-                                                .as_mut()
-                                                .expect("tokenizer to be in active compiler")
-                                                .peek() 
-                                        {
-                                            None => TokenKind::EOF,
-                                            Some(token) => token.kind()
-                                        }, 
-                                        location: match self.previous {
-                                            None => Position::default(),
-                                            Some(token) => token.get_start()
-                                        }
-                                    })
-                                ),
-                                left: None,
-                                right: None,
-                            };
+                            return Self::error_node(Unexpected { 
+                                expected: vec![
+                                    TokenKind::RightParen
+                                ], 
+                                actual: match self.tokens
+                                        .as_mut()
+                                        .expect("tokenizer to be in active compiler")
+                                        .peek() 
+                                {
+                                    None => TokenKind::EOF,
+                                    Some(token) => token.kind()
+                                }, 
+                                location: match self.previous {
+                                    None => Position::default(),
+                                    Some(token) => token.get_start()
+                                }
+                            });
                         }
                         result
                     }
                     other => {
-                        BinaryTreeNode {
-                            value: ExpressionTreeNode::Leaf(
-                                ExpressionLeaf::Error(Unexpected { 
-                                    expected: vec![
-                                        TokenKind::True,
-                                        TokenKind::False,
-                                        TokenKind::Number,
-                                        TokenKind::String,
-                                        TokenKind::Nil,
-                                    ], 
-                                    actual: other, 
-                                    location: match self.previous {
-                                        None => Position::default(),
-                                        Some(token) => token.get_start()
-                                    }
-                                })
-                            ),
-                            left: None,
-                            right: None,
-                        }
+                        Self::error_node(Unexpected { 
+                            expected: vec![
+                                TokenKind::True,
+                                TokenKind::False,
+                                TokenKind::Number,
+                                TokenKind::String,
+                                TokenKind::Nil,
+                                TokenKind::Identifier,
+                            ], 
+                            actual: other, 
+                            location: token.get_start(),
+                        })
                     }
                 }
             }
         }
     }
 
+    /// Emits the bytecode to load a value. nil, true, and false have their own
+    /// opcodes so they never take up one of the constant pool's slots.
+    fn emit_constant(&mut self, value: Value) {
+        match value {
+            Value::Nil => return self.chunks.push(self.chunk(OpCode::Nil as u8)),
+            Value::Boolean(true) => return self.chunks.push(self.chunk(OpCode::True as u8)),
+            Value::Boolean(false) => return self.chunks.push(self.chunk(OpCode::False as u8)),
+            Value::Number(_) | Value::Object(_) => {}
+        }
+
+        self.chunks.push(self.chunk(OpCode::Constant as u8));
+        if let Ok(index) = self.make_constant(value) {
+            self.chunks.push(self.chunk(index));
+        }
+    }
+
+    /// Adds the value to the constant pool and returns its index.
+    fn make_constant(&mut self, value: Value) -> Result<u8, ()> {
+        debug_assert!(
+            !matches!(value, Value::Nil | Value::Boolean(_)), 
+            "nil and boolean values to be emitted with their own opcodes"
+        );
+
+        if self.values.len() == self.values.capacity() {
+            self.errs.push(self.error(CompileErrKind::TooManyValues));
+            return Err(());
+        }
+
+        let index = self.values.len() as u8;
+        // Already checked the u8::MAX condition, so this should be infallible
+        self.values.push(value)
+            .expect("fixed vec to not overflow after checking condition");
+        Ok(index)
+    }
+
+    /// Builds an Unexpected error describing the next token in the stream.
+    fn unexpected(&mut self, expected: Vec<TokenKind>) -> Unexpected {
+        Unexpected {
+            expected,
+            actual: match self.tokens
+                    .as_mut()
+                    .expect("tokenizer to be in active compiler")
+                    .peek()
+            {
+                None => TokenKind::EOF,
+                Some(token) => token.kind()
+            },
+            location: self.peek_location(),
+        }
+    }
+
     fn error_at_current(&self, kind: CompileErrKind) -> CompileErr {
         CompileErr {
             kind,
@@ -521,6 +1595,57 @@ impl<'c> Compiler<'c> {
         }
     }
 
+    /// Consumes the semicolon ending a statement. With implicit semicolons, a line
+    /// break or the end of the program after a complete statement also ends it.
+    fn end_statement(&mut self) -> Result<(), ()> {
+        if self.match_token(TokenKind::Semicolon) {
+            return Ok(());
+        }
+
+        if self.options.implicit_semicolons && self.at_line_break() {
+            return Ok(());
+        }
+
+        self.errs.push(
+            self.error(CompileErrKind::MissingSemicolon)
+        );
+        Err(())
+    }
+
+    /// Whether a line break (or the end of the program) separates
+    /// the last token consumed from the next one.
+    fn at_line_break(&mut self) -> bool {
+        let last_end = match self.current {
+            None => return false,
+            Some(token) => token.range().end,
+        };
+
+        let next_start = self.tokens
+            .as_mut()
+            .expect("tokenizer to be in active compiler")
+            .peek()
+            .map(|token| token.range().start);
+
+        match next_start {
+            None => true,
+            Some(next_start) => self.source_code[last_end..next_start].contains('\n'),
+        }
+    }
+
+    /// The start of the next token, or the current location at the end of the tokens.
+    fn peek_location(&mut self) -> Position {
+        let next = self.tokens
+            .as_mut()
+            .expect("tokenizer to be in active compiler")
+            .peek()
+            .map(|token| token.get_start());
+
+        match next {
+            None => self.location(),
+            Some(location) => location,
+        }
+    }
+
     fn location(&self) -> Position {
         match self.previous {
             None => Position::default(),
@@ -529,20 +1654,8 @@ impl<'c> Compiler<'c> {
     }
 
     fn panic_mode_recovery(&mut self) {
-        self.match_tokens_while(|token| {
-            match token.kind() {
-                // These signal the start of a statement. 
-                // Break the loop if we find one
-                TokenKind::Class
-                | TokenKind::Fun
-                | TokenKind::Var
-                | TokenKind::For
-                | TokenKind::If
-                | TokenKind::Print
-                | TokenKind::Return => false,
-                _ => true,
-            }
-        });
+        // Break the loop once we find the start of a statement.
+        self.match_tokens_while(|token| !starts_statement(token.kind()));
     }
 
     fn match_tokens_while<P: FnMut(&LoxToken) -> bool>(&mut self, mut predicate: P) {
@@ -559,15 +1672,11 @@ impl<'c> Compiler<'c> {
             .expect("tokenizer to be in active compiler");
 
         match tokens.peek() {
-            None => false,
-            Some(token) => {
-                if predicate(token) {
-                    self.next_token().expect("Next to be Some() since we already peeked a Some() variant.");
-                    true
-                } else {
-                    false
-                }
+            Some(token) if predicate(token) => {
+                self.next_token().expect("Next to be Some() since we already peeked a Some() variant.");
+                true
             }
+            _ => false,
         }
     }
 
@@ -581,10 +1690,24 @@ impl<'c> Compiler<'c> {
         self.current
     }
 
-    fn value_node(value: Value) -> BinaryTreeNode<ExpressionTreeNode> {
+    fn value_node(value: Value, span: Span) -> BinaryTreeNode<ExpressionTreeNode> {
+        BinaryTreeNode { 
+            value: ExpressionTreeNode::Leaf(
+                ExpressionLeaf::Value(value),
+                span,
+            ),
+            left: None,
+            right: None
+        }
+    }
+
+    /// Builds a leaf marking a syntax error. It is empty, and sits where the unexpected token was found.
+    fn error_node(err: Unexpected) -> BinaryTreeNode<ExpressionTreeNode> {
+        let span = Span::new(err.location, err.location);
         BinaryTreeNode { 
             value: ExpressionTreeNode::Leaf(
-                ExpressionLeaf::Value(value)
+                ExpressionLeaf::Error(err),
+                span,
             ),
             left: None,
             right: None
@@ -592,58 +1715,257 @@ impl<'c> Compiler<'c> {
     }
 }
 
-enum CompilerState {
-    Panic,
-    Declaration,
+/// Where a statement's code ends, and how many values it leaves on the stack.
+struct StatementEnd {
+    offset: usize,
+    depth: usize,
+    location: Position,
+}
+
+/// Whether a statement can start with this token, so parsing can resume there after an error.
+fn starts_statement(kind: TokenKind) -> bool {
+    matches!(
+        kind,
+        TokenKind::Class
+        | TokenKind::Fun
+        | TokenKind::Var
+        | TokenKind::For
+        | TokenKind::If
+        | TokenKind::While
+        | TokenKind::Print
+        | TokenKind::Return
+        | TokenKind::Break
+        | TokenKind::Try
+    )
+}
+
+/// Simulates the stack depth along every path through the code, checking that it never goes
+/// below empty, that paths joining at an instruction agree on the depth there, and that each
+/// statement leaves the stack as deep as it says it does.
+/// The code starts with `start_depth` values already on the stack, like a function's arguments.
+fn verify_stack(code: &[Chunk], statement_ends: &[StatementEnd], start_depth: usize) -> Result<(), CompileErr> {
+    let mut instructions = vec![None; code.len()];
+    for instruction in Chunk::decode(code) {
+        // Invalid code is reported by the VM when it is loaded.
+        let Ok(instruction) = instruction else { return Ok(()); };
+        instructions[instruction.offset] = Some(instruction);
+    }
+
+    // Statement ends are recorded as the code is emitted, so they are sorted by offset.
+    let ends_at = |offset: usize| {
+        let first = statement_ends.partition_point(|end| end.offset < offset);
+        statement_ends[first..].iter().take_while(move |end| end.offset == offset)
+    };
+    // Errors inside a statement are reported where the statement ends.
+    let location_of = |offset: usize| {
+        let containing = statement_ends.partition_point(|end| end.offset <= offset);
+        statement_ends.get(containing).map(|end| end.location).unwrap_or_default()
+    };
+    let imbalance = |offset: usize| CompileErr {
+        kind: CompileErrKind::StackImbalance { offset },
+        location: location_of(offset),
+    };
+
+    let mut depths = vec![None; code.len() + 1];
+    let mut pending = vec![(0, start_depth)];
+    while let Some((offset, depth)) = pending.pop() {
+        // Jumps past the end of the code are left for the VM to report.
+        let Some(seen) = depths.get_mut(offset) else { continue; };
+        match seen {
+            Some(seen) if *seen == depth => continue,
+            Some(_) => return Err(imbalance(offset)),
+            None => *seen = Some(depth),
+        }
+
+        for end in ends_at(offset) {
+            if depth != end.depth {
+                return Err(CompileErr {
+                    kind: CompileErrKind::StackImbalance { offset: end.offset },
+                    location: end.location,
+                });
+            }
+        }
+
+        let Some(Some(instruction)) = instructions.get(offset) else { continue; };
+        // Nothing past here can be checked without knowing the operands' effects.
+        let Some((pops, pushes)) = instruction.stack_effect() else { continue; };
+        if pops > depth {
+            return Err(imbalance(offset));
+        }
+        let depth = depth - pops + pushes;
+
+        let next = offset + instruction.len();
+        match instruction.op {
+            OpCode::Return => {}
+            OpCode::Jump | OpCode::Loop => pending.extend(instruction.jump_target().map(|target| (target, depth))),
+            OpCode::JumpIfFalse | OpCode::JumpIfNotNil => {
+                pending.extend(instruction.jump_target().map(|target| (target, depth)));
+                pending.push((next, depth));
+            }
+            // The handler starts with the error's message pushed.
+            OpCode::PushHandler => {
+                pending.extend(instruction.jump_target().map(|target| (target, depth + 1)));
+                pending.push((next, depth));
+            }
+            _ => pending.push((next, depth)),
+        }
+    }
+
+    Ok(())
 }
 
 struct Local {
     name: LoxToken,
     depth: usize,
+    /// False while the local's own initializer is compiled, so the initializer can't read it.
+    initialized: bool,
+    #[allow(dead_code)] // Scaffolding for closures, which capture locals into upvalues.
     is_captured: bool,
 }
 
+/// A loop whose body is being emitted.
+struct LoopContext {
+    /// The scope depth outside the body. A break pops every local declared deeper than this.
+    scope_depth: usize,
+    /// The try depth outside the body. A break pops the handler of every try nested deeper than this.
+    try_depth: usize,
+    /// The operands of the jumps emitted for break statements, patched once the loop ends.
+    break_jumps: Vec<usize>,
+}
+
+/// The state belonging to the code being emitted, set aside by enter_function
+/// while a function declared in it is emitted.
+struct FunctionState {
+    f_type: FunctionType,
+    chunks: Vec<Chunk>,
+    statement_ends: Vec<StatementEnd>,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    loops: Vec<LoopContext>,
+    try_depth: usize,
+}
+
 enum FunctionType {
     Function,
+    #[allow(dead_code)] // Scaffolding for classes, which the compiler can't declare yet.
     Initializer,
+    #[allow(dead_code)] // Scaffolding for classes, which the compiler can't declare yet.
     Method,
     Script,
 }
 
-pub (crate) enum ErrKind {
-    Parse(LoxParseErr),
-    Compile(CompileErr),
-}
-
+/// A problem with a program found while compiling it, and where it is.
 #[derive(Clone, Debug)]
-pub (crate) struct CompileErr {
+pub struct CompileErr {
     kind: CompileErrKind,
     location: Position,
 }
 
+impl CompileErr {
+    /// What kind of problem this is.
+    pub fn kind(&self) -> &CompileErrKind {
+        &self.kind
+    }
+
+    /// Where in the source the problem was found.
+    pub fn location(&self) -> Position {
+        self.location
+    }
+
+    /// The token that was out of place, when that is what stopped the program from compiling.
+    pub fn unexpected(&self) -> Option<&Unexpected> {
+        match &self.kind {
+            CompileErrKind::UnexpectedToken(unexpected) => Some(unexpected),
+            _ => None,
+        }
+    }
+
+    /// Whether the program only failed because it ended too early, so more input could fix it.
+    pub (crate) fn is_at_end_of_input(&self) -> bool {
+        match &self.kind {
+            CompileErrKind::Parse(err) => err.is_unterminated(),
+            CompileErrKind::UnexpectedToken(unexpected) => unexpected.actual == TokenKind::EOF,
+            _ => false,
+        }
+    }
+}
+
 impl Error for CompileErr {}
 impl Display for CompileErr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "[{}] Error: {:?}", self.location, self.kind)
+        let severity = if self.kind.is_warning() { "Warning" } else { "Error" };
+        let code = self.kind.as_str();
+        match &self.kind {
+            CompileErrKind::UnexpectedToken(unexpected) => write!(f, "[{}] {}[{}]: {}", self.location, severity, code, unexpected),
+            kind => write!(f, "[{}] {}[{}]: {:?}", self.location, severity, code, kind),
+        }
     }
 }
 
+/// What kind of problem a [`CompileErr`] is. Each kind has a stable code that `--explain` describes.
 #[repr(u8)]
 #[derive(Clone, Debug)]
-pub (crate) enum CompileErrKind {
+pub enum CompileErrKind {
     Parse(LoxParseErr),
     UnexpectedToken(Unexpected),
     TooManyValues,
     MissingSemicolon,
+    /// Tokens were found after a closing brace that had no block to close.
+    TrailingTokens,
+    /// The compiler emitted code that pops more values than it pushed, or leaves values
+    /// behind at the end of a statement. This is a bug in the compiler, not the program.
+    StackImbalance { offset: usize },
+    /// An expression statement computes a value that is thrown away without any side effects.
+    UnusedExpressionResult,
+    /// Two operands are next to each other with no operator between them, like `1 2`.
+    MissingOperator,
+    /// A jump would have to cover more code than its 16 bit distance can reach.
+    JumpTooLarge,
+    /// A local variable was read in its own initializer, like `{ var a = a; }`.
+    LocalInOwnInitializer,
+    /// More locals are in scope at once than the value stack has slots for.
+    TooManyLocals,
+    /// A break statement was found outside of any loop.
+    BreakOutsideLoop,
+    /// The left side of an '=' is something that can't be assigned to, like `a + b = c`.
+    InvalidAssignmentTarget,
+    /// A local variable was declared twice in the same block.
+    DuplicateLocal,
+    /// A call passes more arguments than its one byte operand can count.
+    TooManyArguments,
+    /// A return statement was found outside of any function.
+    ReturnOutsideFunction,
+    /// A function declares more parameters than a call can pass arguments.
+    TooManyParameters,
+    /// A method was called that numbers don't have. The string is its name.
+    UnknownMethod(String),
 }
 
+/// A token that can't appear where it was found.
 #[derive(Clone, Debug)]
-pub (crate) struct Unexpected {
+pub struct Unexpected {
     expected: Vec<TokenKind>,
     actual: TokenKind,
     location: Position,
 }
 
+impl Unexpected {
+    /// The tokens that would have been valid here.
+    pub fn expected(&self) -> &[TokenKind] {
+        &self.expected
+    }
+
+    /// The token that was found instead.
+    pub fn actual(&self) -> TokenKind {
+        self.actual
+    }
+
+    /// Where the unexpected token starts.
+    pub fn location(&self) -> Position {
+        self.location
+    }
+}
+
 impl Display for Unexpected {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut expected_str = String::new();
@@ -656,73 +1978,152 @@ impl Display for Unexpected {
                     expected_str.push_str(", ");
                 }
             }
-            expected_str.push_str(&format!("{:?}", piece));
+            expected_str.push_str(&piece.to_string());
         }
         
-        write!(f, "Unexpected token. Found {:?}, but expected: {}", self.actual, expected_str)
+        write!(f, "Unexpected token. Found {}, but expected: {}", self.actual, expected_str)
     }
 }
 impl Error for Unexpected {}
 
 impl CompileErrKind {
-    fn as_str(&self) -> &'static str {
-        ""
+    /// The stable code for this kind of error. `--explain` describes each one.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CompileErrKind::Parse(_) => "E0001",
+            CompileErrKind::UnexpectedToken(_) => "E0002",
+            CompileErrKind::TooManyValues => "E0003",
+            CompileErrKind::MissingSemicolon => "E0004",
+            CompileErrKind::TrailingTokens => "E0005",
+            CompileErrKind::StackImbalance { .. } => "E0006",
+            CompileErrKind::UnusedExpressionResult => "W0001",
+            CompileErrKind::MissingOperator => "E0007",
+            CompileErrKind::JumpTooLarge => "E0008",
+            CompileErrKind::LocalInOwnInitializer => "E0009",
+            CompileErrKind::TooManyLocals => "E0010",
+            CompileErrKind::BreakOutsideLoop => "E0011",
+            CompileErrKind::InvalidAssignmentTarget => "E0012",
+            CompileErrKind::DuplicateLocal => "E0013",
+            CompileErrKind::TooManyArguments => "E0014",
+            CompileErrKind::ReturnOutsideFunction => "E0015",
+            CompileErrKind::TooManyParameters => "E0016",
+            CompileErrKind::UnknownMethod(_) => "E0017",
+        }
+    }
+
+    /// Warnings are reported, but only stop the program from running when warnings are denied.
+    pub fn is_warning(&self) -> bool {
+        matches!(self, CompileErrKind::UnusedExpressionResult)
     }
 }
 
-#[derive(PartialEq, PartialOrd, Eq, Ord, Clone, Copy, Debug)]
-enum Precedence {
-    None = 0,
-    Assignment = 1,  // =
-    Or = 2,          // or
-    And = 3,         // and
-    Equality = 4,    // == !=
-    Comparison = 5,  // < > <= >=
-    Term = 6,        // + -
-    Factor = 7,      // * /
-    Unary = 8,       // ! -
-    Call = 9,        // . ()
-    Primary = 10
+/// A parsed statement. Empty statements have no node.
+#[derive(Debug)]
+pub enum Statement {
+    /// An expression evaluated for its side effects. The location is where the expression starts.
+    Expression { expr: BinaryTreeNode<ExpressionTreeNode>, location: Position },
+    Print(BinaryTreeNode<ExpressionTreeNode>),
+    /// A var statement, which may declare several variables.
+    Var(Vec<VarDeclaration>),
+    /// An if statement. A branch is None when it is an empty statement or there is no else.
+    If {
+        condition: BinaryTreeNode<ExpressionTreeNode>,
+        then_branch: Option<Box<Statement>>,
+        else_branch: Option<Box<Statement>>,
+    },
+    /// A while loop. The body is None when it is an empty statement.
+    While {
+        condition: BinaryTreeNode<ExpressionTreeNode>,
+        body: Option<Box<Statement>>,
+    },
+    /// Leaves the innermost loop.
+    Break { location: Position },
+    /// A function declaration, which defines a variable holding the function.
+    Function(FunctionDeclaration),
+    /// Returns from the function it is in. A missing value returns nil. The location is where 'return' is.
+    Return { value: Option<BinaryTreeNode<ExpressionTreeNode>>, location: Position },
+    /// A block. Variables declared in it are local to it.
+    Block(Vec<Statement>),
+    /// Runs the body, and if it raises a runtime error, unwinds it and runs the handler
+    /// with the error's message in a local named by the catch clause.
+    Try { body: Vec<Statement>, error_name: LoxToken, handler: Vec<Statement> },
+    /// A for loop. Any of its clauses can be left out, and
+    /// the body is None when it is an empty statement.
+    For {
+        initializer: Option<Box<Statement>>,
+        condition: Option<BinaryTreeNode<ExpressionTreeNode>>,
+        increment: Option<BinaryTreeNode<ExpressionTreeNode>>,
+        body: Option<Box<Statement>>,
+    },
 }
 
-impl TryFrom<u8> for Precedence {
-    type Error = u8;
+/// One variable declared by a var statement.
+#[derive(Debug)]
+pub struct VarDeclaration {
+    name: LoxToken,
+    initializer: Option<BinaryTreeNode<ExpressionTreeNode>>,
+}
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            0 => Ok(Precedence::None),
-            1 => Ok(Precedence::Assignment),
-            2 => Ok(Precedence::Or),
-            3 => Ok(Precedence::And),
-            4 => Ok(Precedence::Equality),
-            5 => Ok(Precedence::Comparison),
-            6 => Ok(Precedence::Term),
-            7 => Ok(Precedence::Factor),
-            8 => Ok(Precedence::Unary),
-            9 => Ok(Precedence::Call),
-            10 => Ok(Precedence::Primary),
-            other => Err(other)
-        }
+impl VarDeclaration {
+    /// The identifier token naming the variable.
+    pub fn name(&self) -> LoxToken {
+        self.name
+    }
+
+    /// The expression after '=', if there is one.
+    pub fn initializer(&self) -> Option<&BinaryTreeNode<ExpressionTreeNode>> {
+        self.initializer.as_ref()
     }
 }
 
-enum Statement {
-    Expression(BinaryTreeNode<ExpressionTreeNode>),
+/// A function declared by a fun statement.
+#[derive(Debug)]
+pub struct FunctionDeclaration {
+    name: LoxToken,
+    params: Vec<LoxToken>,
+    body: Vec<Statement>,
 }
 
-trait BinaryTreeNodeExtensions {
-    fn to_string(&self) -> String;
-    fn to_string_helper(&self) -> (usize, String);
+impl FunctionDeclaration {
+    /// The identifier token naming the function.
+    pub fn name(&self) -> LoxToken {
+        self.name
+    }
+
+    /// The identifier tokens naming the parameters, in order.
+    pub fn params(&self) -> &[LoxToken] {
+        &self.params
+    }
+
+    /// The statements between the body's braces.
+    pub fn body(&self) -> &[Statement] {
+        &self.body
+    }
 }
 
+/// A node in an expression tree, along with the source it was parsed from.
 #[derive(Debug)]
-enum ExpressionTreeNode {
-    Branch(ExpressionBranch),
-    Leaf(ExpressionLeaf)
+pub enum ExpressionTreeNode {
+    Branch(ExpressionBranch, Span),
+    Leaf(ExpressionLeaf, Span)
 }
 
-enum ExpressionBranch {
+impl ExpressionTreeNode {
+    /// The source this node and everything below it was parsed from.
+    pub fn span(&self) -> Span {
+        match self {
+            ExpressionTreeNode::Branch(_, span) | ExpressionTreeNode::Leaf(_, span) => *span,
+        }
+    }
+}
+
+/// What a branch of an expression tree does with its children.
+pub enum ExpressionBranch {
     Operator(Operator),
+    /// A call. The left child is the callee and the right child is the first Argument, if any.
+    Call { arg_count: u8 },
+    /// One argument of a call. The left child is its expression and the right child is the next Argument.
+    Argument,
 }
 
 impl Debug for ExpressionBranch {
@@ -731,29 +2132,43 @@ impl Debug for ExpressionBranch {
             Self::Operator(inner) => {
                 Debug::fmt(inner, f)
             }
+            Self::Call { arg_count } => write!(f, "Call({})", arg_count),
+            Self::Argument => write!(f, "Argument"),
         }
     }
 }
 
+/// An expression tree node without children.
 #[derive(Debug)]
-enum ExpressionLeaf {
+pub enum ExpressionLeaf {
     Value(Value),
-    Call,
+    /// A variable read. The token is its name.
+    Variable(LoxToken),
+    /// A read of the global a native is defined in, which a method call is lowered to.
+    /// Locals are never looked at, so a local with the same name can't stand in for it.
+    Native(&'static str),
     /// This indicates a syntax error
     Error(Unexpected),
 }
 
+/// The operators an expression tree branch can apply.
 #[derive(Debug)]
-enum Operator {
+pub enum Operator {
     Assignment,
     Or,
+    /// `a ?? b`, which is a unless a is nil.
+    Coalesce,
     And,
     Equal,
+    NotEqual,
     Not,
     Greater,
+    GreaterEqual,
     Less,
+    LessEqual,
     Divide,
     Multiply,
+    Modulo,
     Add,
     Subtract,
     SignFlip,
@@ -762,35 +2177,766 @@ enum Operator {
 impl Operator {
     fn to_bytecodes(&self) -> impl Iterator<Item = OpCode> {
         match self {
-            Operator::Assignment => todo!(),
-            Operator::And => todo!(),
-            Operator::Or => todo!(),
+            Operator::Assignment => unreachable!("assignments to be compiled by emit_expression"),
+            Operator::And => unreachable!("and to be compiled with jumps by emit_expression"),
+            Operator::Or => unreachable!("or to be compiled with jumps by emit_expression"),
+            Operator::Coalesce => unreachable!("?? to be compiled with jumps by emit_expression"),
             Operator::Equal => [OpCode::Equal].into_iter(),
+            Operator::NotEqual => [OpCode::NotEqual].into_iter(),
             Operator::Not => [OpCode::Not].into_iter(),
             Operator::Greater => [OpCode::Greater].into_iter(),
+            Operator::GreaterEqual => [OpCode::GreaterEqual].into_iter(),
             Operator::Less => [OpCode::Less].into_iter(),
+            Operator::LessEqual => [OpCode::LessEqual].into_iter(),
             Operator::Divide => [OpCode::Divide].into_iter(),
             Operator::Multiply => [OpCode::Multiply].into_iter(),
+            Operator::Modulo => [OpCode::Modulo].into_iter(),
             Operator::Add => [OpCode::Add].into_iter(),
             Operator::Subtract => [OpCode::Subtract].into_iter(),
             Operator::SignFlip => [OpCode::Negate].into_iter(),
         }
     }
+}
 
-    fn as_str(&self) -> &'static str {
-        match self {
-            Operator::Assignment => "=",
-            Operator::Or => "||",
-            Operator::And => "&&",
-            Operator::Equal => "==",
-            Operator::Not => " !",
-            Operator::Greater => " >",
-            Operator::Less => " <",
-            Operator::Divide => " /",
-            Operator::Multiply => " *",
-            Operator::Add => " +",
-            Operator::Subtract => " -",
-            Operator::SignFlip => " -",
-        }
-    }
-}
\ No newline at end of file
+#[test]
+fn var_declaration_rejects_trailing_comma() {
+    let errs = compile("var a = 1, ;").expect_err("trailing comma to be a compile error");
+    assert_eq!(1, errs.len());
+    match &errs[0].kind {
+        CompileErrKind::UnexpectedToken(err) => {
+            assert_eq!(vec![TokenKind::Identifier], err.expected);
+            assert_eq!(TokenKind::Semicolon, err.actual);
+        }
+        other => panic!("Expected an unexpected token error, but found {:?}", other),
+    }
+}
+
+#[test]
+fn var_declaration_requires_names_and_semicolon() {
+    let errs = compile("var = 1;").expect_err("missing name to be a compile error");
+    assert!(matches!(errs[0].kind, CompileErrKind::UnexpectedToken(_)));
+
+    let errs = compile("var a = 1, b = 2").expect_err("missing semicolon to be a compile error");
+    assert!(matches!(errs[0].kind, CompileErrKind::MissingSemicolon));
+}
+
+#[test]
+fn literals_do_not_use_the_constant_pool() {
+    let mut program = String::new();
+    // More literals than the constant pool could ever hold.
+    for _ in 0..100 {
+        program.push_str("true; false; nil;");
+    }
+    program.push_str("print !nil == true;");
+
+    let Program { code, values, .. } = compile(&program).expect("program to compile");
+    assert_eq!(0, values.len());
+    assert!(code.iter().all(|chunk| chunk.op != OpCode::Constant as u8));
+}
+
+#[test]
+fn trailing_tokens_are_reported_at_the_stray_token() {
+    let errs = compile("print 1; } extra").expect_err("stray brace to be a compile error");
+    assert_eq!(1, errs.len());
+    assert!(matches!(errs[0].kind, CompileErrKind::TrailingTokens));
+    assert_eq!(0, errs[0].location.line());
+    assert_eq!(10, errs[0].location.col());
+}
+
+#[test]
+fn unexpected_tokens_are_reported_at_the_unexpected_token() {
+    let errs = compile("print 1;\nprint 2 + ;").expect_err("missing operand to be a compile error");
+    assert_eq!(1, errs.len());
+    assert!(matches!(errs[0].kind, CompileErrKind::UnexpectedToken(_)));
+    assert_eq!(1, errs[0].location.line());
+    assert_eq!(11, errs[0].location.col());
+}
+
+#[test]
+fn unused_expression_results_are_warnings() {
+    let program = compile("print 1;\n1 + 2;\n-3;").expect("warnings to not fail compilation");
+    assert_eq!(2, program.warnings.len());
+    assert!(program.warnings.iter().all(|warning| {
+        matches!(warning.kind, CompileErrKind::UnusedExpressionResult)
+    }));
+    assert_eq!(1, program.warnings[0].location.line());
+    assert_eq!(2, program.warnings[1].location.line());
+    assert!(program.warnings[0].to_string().contains("Warning"));
+
+    let program = compile("print 1 + 2; var a = 1;").expect("program to compile");
+    assert!(program.warnings.is_empty());
+}
+
+#[test]
+fn side_effects_are_not_unused_results() {
+    let call = BinaryTreeNode {
+        value: ExpressionTreeNode::Branch(ExpressionBranch::Call { arg_count: 0 }, Span::default()),
+        left: None,
+        right: None,
+    };
+    assert!(Compiler::has_side_effects(&call));
+
+    let assignment = BinaryTreeNode {
+        value: ExpressionTreeNode::Branch(ExpressionBranch::Operator(Operator::Assignment), Span::default()),
+        left: None,
+        right: Some(Box::new(Compiler::value_node(Value::Number(1.0), Span::default()))),
+    };
+    assert!(Compiler::has_side_effects(&assignment));
+
+    assert!(!Compiler::has_side_effects(&Compiler::value_node(Value::Number(1.0), Span::default())));
+}
+
+#[test]
+fn lexical_and_syntax_errors_are_both_reported() {
+    let errs = compile("var a = 1 @ 2;\nprint 1;\nvar = 3;").expect_err("program to be invalid");
+    assert_eq!(2, errs.len());
+
+    assert!(matches!(errs[0].kind, CompileErrKind::Parse(_)));
+    assert_eq!(0, errs[0].location.line());
+
+    assert!(matches!(errs[1].kind, CompileErrKind::UnexpectedToken(_)));
+    assert_eq!(2, errs[1].location.line());
+}
+
+#[test]
+fn unterminated_strings_do_not_cascade() {
+    let errs = compile("print 1;\nprint \"abc;\nprint 2").expect_err("program to be invalid");
+    assert_eq!(1, errs.len());
+    assert!(matches!(errs[0].kind, CompileErrKind::Parse(_)));
+}
+
+#[test]
+fn combined_comparisons_emit_a_single_opcode() {
+    let cases = [
+        ("1 != 2;", OpCode::NotEqual),
+        ("1 >= 2;", OpCode::GreaterEqual),
+        ("1 <= 2;", OpCode::LessEqual),
+    ];
+    for (source, expected) in cases {
+        let program = compile(source).expect("comparison to compile");
+        let ops: Vec<u8> = program.code.iter().map(|chunk| chunk.op).collect();
+        assert_eq!(
+            vec![OpCode::Constant as u8, 0, OpCode::Constant as u8, 1, expected as u8, OpCode::Pop as u8, OpCode::Return as u8],
+            ops
+        );
+    }
+}
+
+#[test]
+fn implicit_semicolons_end_complete_statements() {
+    let options = CompileOptions { implicit_semicolons: true, ..Default::default() };
+    let terminated = [
+        "print 1\nprint 2",
+        "var a = 1, b\nprint 3",
+        "print 1 // comment\nprint 2;",
+        "print 1; print 2",
+    ];
+    for source in terminated {
+        assert!(compile_with_options(source, options).is_ok(), "{} to compile", source);
+    }
+
+    // Without the option, semicolons are still required.
+    assert!(compile("print 1\nprint 2").is_err());
+}
+
+#[test]
+fn implicit_semicolons_do_not_split_expressions() {
+    let options = CompileOptions { implicit_semicolons: true, ..Default::default() };
+    let program = compile_with_options("print 1 +\n2\nprint (3\n* 4)", options).expect("program to compile");
+    let prints = program.code.iter().filter(|chunk| chunk.op == OpCode::Print as u8).count();
+    assert_eq!(2, prints);
+
+    // A line break never ends a statement on a single line.
+    let errs = compile_with_options("print 1 print 2", options).expect_err("statements on one line to need a semicolon");
+    assert!(matches!(errs[0].kind, CompileErrKind::MissingSemicolon));
+}
+
+#[test]
+fn empty_statements_compile_to_nothing() {
+    let program = compile(";;;").expect("empty statements to compile");
+    let ops: Vec<u8> = program.code.iter().map(|chunk| chunk.op).collect();
+    assert_eq!(vec![OpCode::Return as u8], ops);
+    assert!(program.warnings.is_empty());
+
+    assert!(compile("print 1;; print 2;").is_ok());
+}
+
+#[test]
+fn unexpected_exposes_what_was_expected() {
+    let errs = compile("print 1;\nvar = 1;").expect_err("missing name to be a compile error");
+    let unexpected = match &errs[0].kind {
+        CompileErrKind::UnexpectedToken(unexpected) => unexpected,
+        other => panic!("Expected an unexpected token error, but found {:?}", other),
+    };
+
+    assert_eq!(&[TokenKind::Identifier], unexpected.expected());
+    assert_eq!(TokenKind::Equal, unexpected.actual());
+    assert_eq!(1, unexpected.location().line());
+    assert_eq!(5, unexpected.location().col());
+}
+
+#[test]
+fn parse_builds_statements_without_compiling() {
+    let statements = parse("print 1;\n;\nvar a = 2, b;\n1 + 2;", CompileOptions::default())
+        .expect("program to parse");
+    assert_eq!(3, statements.len());
+    assert!(matches!(statements[0], Statement::Print(_)));
+    assert!(matches!(statements[2], Statement::Expression { .. }));
+
+    let Statement::Var(declarations) = &statements[1] else {
+        panic!("Expected a var statement");
+    };
+    let names: Vec<&str> = declarations.iter()
+        .map(|declaration| &"print 1;\n;\nvar a = 2, b;\n1 + 2;"[declaration.name().range()])
+        .collect();
+    assert_eq!(vec!["a", "b"], names);
+    assert!(declarations[0].initializer().is_some());
+    assert!(declarations[1].initializer().is_none());
+
+    let errs = parse("print 1 +;", CompileOptions::default()).expect_err("missing operand to be an error");
+    assert!(matches!(errs[0].kind, CompileErrKind::UnexpectedToken(_)));
+}
+
+#[test]
+fn stack_verifier_accepts_compiled_code() {
+    let program = compile("print 1 + 2; var a = -3, b; 4 == 5;").expect("program to compile");
+    assert_eq!(1, program.warnings.len());
+
+    let options = CompileOptions { keep_final_expression: true, ..Default::default() };
+    assert!(compile_with_options("print 1; 2 * 3;", options).is_ok());
+}
+
+#[test]
+fn stack_verifier_rejects_imbalanced_code() {
+    let chunk = |op: OpCode| Chunk { line: 0, op: op as u8 };
+    let end = |offset: usize| StatementEnd { offset, depth: 0, location: Position::default() };
+
+    // A statement that forgets its Pop.
+    let leftover = [chunk(OpCode::Nil), chunk(OpCode::Nil), chunk(OpCode::Pop), chunk(OpCode::Return)];
+    let err = verify_stack(&leftover, &[end(3)], 0).expect_err("leftover value to be an imbalance");
+    assert!(matches!(err.kind, CompileErrKind::StackImbalance { offset: 3 }));
+
+    // Popping more than was pushed.
+    let underflow = [chunk(OpCode::Nil), chunk(OpCode::Add), chunk(OpCode::Pop), chunk(OpCode::Return)];
+    let err = verify_stack(&underflow, &[end(3)], 0).expect_err("underflow to be an imbalance");
+    assert!(matches!(err.kind, CompileErrKind::StackImbalance { offset: 1 }));
+
+    let balanced = [chunk(OpCode::Nil), chunk(OpCode::Nil), chunk(OpCode::Add), chunk(OpCode::Pop), chunk(OpCode::Return)];
+    assert!(verify_stack(&balanced, &[end(4)], 0).is_ok());
+}
+
+#[test]
+fn unexpected_tokens_are_shown_as_written() {
+    let errs = compile("var == 1;").expect_err("missing name to be a compile error");
+    let CompileErrKind::UnexpectedToken(unexpected) = &errs[0].kind else {
+        panic!("Expected an unexpected token error");
+    };
+    let message = unexpected.to_string();
+    assert_eq!("Unexpected token. Found '==', but expected: identifier", message);
+    assert!(!errs[0].to_string().contains("EqualEqual"));
+}
+
+#[test]
+fn large_programs_compile_statement_by_statement() {
+    // No constants, so every statement compiles to the same bytes no matter where it is.
+    let statement = "print !true == (false != nil) == !(nil == true);\n";
+    let single = compile(statement).expect("program to compile");
+    let repeated = compile(&statement.repeat(1000)).expect("program to compile");
+
+    let ops = |code: &[Chunk]| code.iter().map(|chunk| chunk.op).collect::<Vec<_>>();
+    let single = ops(&single.code);
+    let (statement_code, end) = single.split_at(single.len() - 1);
+    let mut expected = statement_code.repeat(1000);
+    expected.extend_from_slice(end);
+    assert_eq!(expected, ops(&repeated.code));
+}
+
+#[test]
+fn adjacent_operands_are_missing_an_operator() {
+    for (source, column) in [("1 2;", 2), ("\"a\" \"b\";", 4), ("print (1) 2;", 10)] {
+        let errs = compile(source).expect_err("adjacent operands to be a compile error");
+        assert_eq!(1, errs.len(), "{}", source);
+        assert!(matches!(errs[0].kind, CompileErrKind::MissingOperator), "{}", source);
+        assert_eq!(column, errs[0].location.col(), "{}", source);
+    }
+
+    let options = CompileOptions { implicit_semicolons: true, ..Default::default() };
+    assert!(compile_with_options("print 1\n2", options).is_ok());
+}
+
+/// Decodes compiled code, along with just its opcodes for comparing against an expected sequence.
+#[cfg(test)]
+fn decode_ops(code: &[Chunk]) -> (Vec<crate::chunk::Instruction>, Vec<OpCode>) {
+    let decoded: Vec<crate::chunk::Instruction> = Chunk::decode(code)
+        .collect::<Result<_, _>>()
+        .expect("compiled code to decode");
+    let ops = decoded.iter().map(|instruction| instruction.op).collect();
+    (decoded, ops)
+}
+
+#[test]
+fn if_statements_compile_to_patched_jumps() {
+    let program = compile("if (true) print 1; else print 2;").expect("program to compile");
+    let (decoded, ops) = decode_ops(&program.code);
+    assert_eq!(vec![
+        OpCode::True,
+        OpCode::JumpIfFalse,
+        OpCode::Pop,
+        OpCode::Constant,
+        OpCode::Print,
+        OpCode::Jump,
+        OpCode::Pop,
+        OpCode::Constant,
+        OpCode::Print,
+        OpCode::Return,
+    ], ops);
+
+    // The condition is false: skip to the else branch's Pop.
+    assert_eq!(Some(decoded[6].offset), decoded[1].jump_target());
+    // The then branch is done: skip over the else branch.
+    assert_eq!(Some(decoded[9].offset), decoded[5].jump_target());
+
+    assert!(compile("if (true) ; else ;").is_ok());
+    assert!(compile("if true print 1;").is_err());
+    assert!(compile("if (true print 1;").is_err());
+}
+
+#[test]
+fn jumps_that_are_too_far_are_reported() {
+    let source = "";
+    let mut compiler = Compiler::new(FunctionType::Script, source, Vec::new(), CompileOptions::default());
+    let operand = compiler.emit_jump(OpCode::Jump);
+    for _ in 0..u16::MAX {
+        compiler.chunks.push(Chunk { line: 0, op: OpCode::Nil as u8 });
+    }
+    assert!(compiler.patch_jump(operand).is_ok());
+    assert_eq!([u8::MAX, u8::MAX], [compiler.chunks[operand].op, compiler.chunks[operand + 1].op]);
+
+    compiler.chunks.push(Chunk { line: 0, op: OpCode::Nil as u8 });
+    assert!(compiler.patch_jump(operand).is_err());
+    assert!(matches!(compiler.errs[0].kind, CompileErrKind::JumpTooLarge));
+}
+
+#[test]
+fn stack_verifier_follows_jumps() {
+    let chunk = |op: u8| Chunk { line: 0, op };
+
+    // Only one path pushes nil, so the two disagree on the depth where they join.
+    let code = [
+        chunk(OpCode::True as u8),
+        chunk(OpCode::JumpIfFalse as u8), chunk(0), chunk(1),
+        chunk(OpCode::Nil as u8),
+        chunk(OpCode::Pop as u8),
+        chunk(OpCode::Return as u8),
+    ];
+    let err = verify_stack(&code, &[], 0).expect_err("paths to disagree");
+    assert!(matches!(err.kind, CompileErrKind::StackImbalance { offset: 5 }));
+
+    assert!(compile("if (true) if (false) print 1; else 2 + 3; print 4;").is_ok());
+}
+
+#[test]
+fn while_loops_jump_back_to_their_condition() {
+    let program = compile("var i = 0; while (i < 3) var i = i + 1;").expect("program to compile");
+    let (decoded, _) = decode_ops(&program.code);
+
+    let condition = decoded.iter().find(|instruction| instruction.op == OpCode::GetGlobal).expect("the condition to read i");
+    let exit = decoded.iter().find(|instruction| instruction.op == OpCode::JumpIfFalse).expect("the loop to have an exit");
+    let back = decoded.iter().find(|instruction| instruction.op == OpCode::Loop).expect("the loop to jump back");
+    assert_eq!(Some(condition.offset), back.jump_target());
+    // The exit lands on the Pop of the condition, just after the loop instruction.
+    assert_eq!(Some(back.offset + back.len()), exit.jump_target());
+
+    assert!(compile("while (false) ;").is_ok());
+    assert!(compile("while false print 1;").is_err());
+}
+
+#[test]
+fn loops_that_are_too_far_are_reported() {
+    let source = "";
+    let mut compiler = Compiler::new(FunctionType::Script, source, Vec::new(), CompileOptions::default());
+    for _ in 0..u16::MAX - 3 {
+        compiler.chunks.push(Chunk { line: 0, op: OpCode::Nil as u8 });
+    }
+    assert!(compiler.emit_loop(0).is_ok());
+
+    compiler.chunks.push(Chunk { line: 0, op: OpCode::Nil as u8 });
+    assert!(compiler.emit_loop(0).is_err());
+    assert!(matches!(compiler.errs[0].kind, CompileErrKind::JumpTooLarge));
+}
+
+#[test]
+fn for_loops_run_their_increment_before_looping() {
+    let program = compile("for (var i = 0; i < 3; 1 + 2) print i;").expect("program to compile");
+    let (decoded, ops) = decode_ops(&program.code);
+    assert_eq!(vec![
+        // var i = 0;
+        OpCode::Constant,
+        // i < 3
+        OpCode::GetLocal, OpCode::Constant, OpCode::Less, OpCode::JumpIfFalse, OpCode::Pop,
+        // print i;
+        OpCode::GetLocal, OpCode::Print,
+        // 1 + 2
+        OpCode::Constant, OpCode::Constant, OpCode::Add, OpCode::Pop,
+        OpCode::Loop,
+        // The condition, then i as the loop's scope ends.
+        OpCode::Pop, OpCode::Pop,
+        OpCode::Return,
+    ], ops);
+    assert_eq!(Some(decoded[1].offset), decoded[12].jump_target());
+    assert_eq!(Some(decoded[13].offset), decoded[4].jump_target());
+
+    // Every clause is optional.
+    let program = compile("for (;;) ;").expect("an empty for loop to compile");
+    let ops: Vec<u8> = program.code.iter().map(|chunk| chunk.op).collect();
+    assert_eq!(vec![OpCode::Loop as u8, 0, 3, OpCode::Return as u8], ops);
+
+    assert!(compile("for (1 + 2; false;) print 1;").is_ok());
+    assert!(compile("for (var i = 0; i < 3) print i;").is_err());
+}
+
+#[test]
+fn and_skips_its_right_operand_with_a_jump() {
+    let program = compile("print true and 1 == 2;").expect("program to compile");
+    let (decoded, ops) = decode_ops(&program.code);
+    assert_eq!(vec![
+        OpCode::True,
+        OpCode::JumpIfFalse,
+        OpCode::Pop,
+        OpCode::Constant, OpCode::Constant, OpCode::Equal,
+        OpCode::Print,
+        OpCode::Return,
+    ], ops);
+    assert_eq!(Some(decoded[6].offset), decoded[1].jump_target());
+
+    // Operators around a short circuit still apply to its result.
+    assert!(compile("!(true and false) == (1 < 2 and 3 > 4);").is_ok());
+}
+
+#[test]
+fn or_jumps_over_its_right_operand() {
+    let program = compile("print false or 1;").expect("program to compile");
+    let (decoded, ops) = decode_ops(&program.code);
+    assert_eq!(vec![
+        OpCode::False,
+        OpCode::JumpIfFalse,
+        OpCode::Jump,
+        OpCode::Pop,
+        OpCode::Constant,
+        OpCode::Print,
+        OpCode::Return,
+    ], ops);
+    assert_eq!(Some(decoded[3].offset), decoded[1].jump_target());
+    assert_eq!(Some(decoded[5].offset), decoded[2].jump_target());
+}
+
+#[test]
+fn coalesce_jumps_over_its_right_operand() {
+    let program = compile("print nil ?? 1;").expect("program to compile");
+    let (decoded, ops) = decode_ops(&program.code);
+    assert_eq!(vec![
+        OpCode::Nil,
+        OpCode::JumpIfNotNil,
+        OpCode::Pop,
+        OpCode::Constant,
+        OpCode::Print,
+        OpCode::Return,
+    ], ops);
+    assert_eq!(Some(decoded[4].offset), decoded[1].jump_target());
+
+    // A single '?' isn't an operator.
+    assert!(compile("print nil ? 1;").is_err());
+}
+
+#[test]
+fn try_jumps_to_its_handler_with_the_message_pushed() {
+    let program = compile("try { print 1; } catch (e) { print e; }").expect("program to compile");
+    let (decoded, ops) = decode_ops(&program.code);
+    assert_eq!(vec![
+        OpCode::PushHandler,
+        OpCode::Constant, OpCode::Print,
+        OpCode::PopHandler,
+        OpCode::Jump,
+        // The message is the catch clause's local.
+        OpCode::GetLocal, OpCode::Print,
+        OpCode::Pop,
+        OpCode::Return,
+    ], ops);
+    assert_eq!(Some(decoded[5].offset), decoded[0].jump_target());
+    assert_eq!(Some(decoded[8].offset), decoded[4].jump_target());
+
+    assert!(compile("try { } catch { }").is_err());
+    assert!(compile("try { }").is_err());
+}
+
+#[test]
+fn blocks_pop_their_locals_when_they_end() {
+    let program = compile("{ var a = 1; { var a = 2; var b = a; } print a; }").expect("program to compile");
+    let (decoded, ops) = decode_ops(&program.code);
+    assert_eq!(vec![
+        OpCode::Constant,
+        OpCode::Constant,
+        OpCode::GetLocal,
+        // The inner a and b.
+        OpCode::Pop, OpCode::Pop,
+        OpCode::GetLocal,
+        OpCode::Print,
+        // The outer a.
+        OpCode::Pop,
+        OpCode::Return,
+    ], ops);
+
+    // The inner a shadows the outer one until its block ends.
+    assert_eq!(crate::chunk::Operands::Byte(1), decoded[2].operands);
+    assert_eq!(crate::chunk::Operands::Byte(0), decoded[5].operands);
+}
+
+#[test]
+fn locals_cannot_be_read_in_their_own_initializer() {
+    for source in ["{ var a = a; }", "var a = 1; { var a = a + 1; }", "{ var a = 1; { var a = -a; } }"] {
+        let errs = compile(source).expect_err("reading a local in its own initializer to be an error");
+        assert!(matches!(errs[0].kind, CompileErrKind::LocalInOwnInitializer), "{}", source);
+    }
+
+    let errs = compile("{ var a = a; print a; }").expect_err("the initializer to be an error");
+    assert_eq!(1, errs.len());
+
+    assert!(compile("{ var a = 1; { var b = a; } }").is_ok());
+    assert!(compile("{ var a = 1, b = a; }").is_ok());
+}
+
+#[test]
+fn locals_can_only_be_declared_once_per_block() {
+    for source in ["{ var a; var a; }", "{ var a, b, a; }"] {
+        let errs = compile(source).expect_err("a second declaration in the same block to be an error");
+        assert!(matches!(errs[0].kind, CompileErrKind::DuplicateLocal), "{}", source);
+    }
+
+    // Shadowing an outer block's local, or a global, is fine.
+    assert!(compile("{ var a; { var a; } }").is_ok());
+    assert!(compile("var a; var a; { var a; }").is_ok());
+}
+
+#[test]
+fn scope_exits_must_pop_every_local() {
+    let chunk = |op: OpCode| Chunk { line: 0, op: op as u8 };
+    let end = |offset: usize, depth: usize| StatementEnd { offset, depth, location: Position::default() };
+
+    // Two locals are declared, but the scope only pops one of them.
+    let code = [chunk(OpCode::Nil), chunk(OpCode::Nil), chunk(OpCode::Pop), chunk(OpCode::Return)];
+    let err = verify_stack(&code, &[end(1, 1), end(2, 2), end(3, 0)], 0).expect_err("a local to be left behind");
+    assert!(matches!(err.kind, CompileErrKind::StackImbalance { offset: 3 }));
+
+    let program = "{ var a; { var b; if (a) { var c = b; print c; } else print b; } { var d; } print a; } print 1;";
+    assert!(compile(program).is_ok());
+    assert!(compile("for (var i = 0; false;) { var j = i; if (j) var k = j; }").is_ok());
+}
+
+#[test]
+fn blocks_are_limited_to_the_locals_the_stack_can_hold() {
+    let block = |count: usize| {
+        let declarations: String = (0..count).map(|i| format!("var l{};", i)).collect();
+        format!("{{{}}}", declarations)
+    };
+
+    assert!(compile(&block(LOCALS_MAX)).is_ok());
+    let errs = compile(&block(LOCALS_MAX + 1)).expect_err("one local too many to be an error");
+    assert!(matches!(errs[0].kind, CompileErrKind::TooManyLocals));
+}
+
+#[test]
+fn unclosed_blocks_are_reported_at_the_end_of_input() {
+    let errs = compile("{ print 1;").expect_err("an unclosed block to be an error");
+    assert_eq!(1, errs.len());
+    assert!(errs[0].is_at_end_of_input());
+
+    // An error inside a block doesn't hide the ones after it.
+    let errs = compile("{ print; print 1 } print 2;").expect_err("the block to have errors");
+    assert_eq!(2, errs.len());
+}
+
+#[test]
+fn for_loops_without_a_condition_only_end_by_breaking() {
+    let program = compile("for (;;) { print 1; break; }").expect("program to compile");
+    let (decoded, ops) = decode_ops(&program.code);
+    // No condition means no exit test, so nothing is left on the stack to pop.
+    assert_eq!(vec![
+        OpCode::Constant, OpCode::Print,
+        OpCode::Jump,
+        OpCode::Loop,
+        OpCode::Return,
+    ], ops);
+    assert_eq!(Some(decoded[4].offset), decoded[2].jump_target());
+    assert_eq!(Some(0), decoded[3].jump_target());
+
+    assert!(compile("for (var i = 0;;) { if (i > 2) break; print i; }").is_ok());
+}
+
+#[test]
+fn breaks_pop_the_locals_declared_inside_the_loop() {
+    let program = compile("{ var outer; while (true) { var a; { var b; break; } } }").expect("program to compile");
+    let (_, ops) = decode_ops(&program.code);
+    let break_at = ops.iter().position(|op| *op == OpCode::Jump).expect("the break to jump");
+    // b and a, but not outer, which is declared outside the loop.
+    assert_eq!(&[OpCode::Nil, OpCode::Pop, OpCode::Pop], &ops[break_at - 3..break_at]);
+
+    let errs = compile("if (true) break;").expect_err("a break outside a loop to be an error");
+    assert!(matches!(errs[0].kind, CompileErrKind::BreakOutsideLoop));
+    assert!(compile("while (true) { while (false) break; break; }").is_ok());
+}
+
+#[test]
+fn assignments_set_the_variable_they_resolve_to() {
+    let program = compile("var a; { var b; b = a = 1; }").expect("program to compile");
+    let (_, ops) = decode_ops(&program.code);
+    assert_eq!(vec![
+        OpCode::Nil, OpCode::DefineGlobal,
+        OpCode::Nil,
+        // a = 1 runs first and its value is then assigned to b.
+        OpCode::Constant, OpCode::SetGlobal, OpCode::SetLocal,
+        OpCode::Pop,
+        OpCode::Pop,
+        OpCode::Return,
+    ], ops);
+
+    // Assignments have side effects, so their results aren't unused.
+    assert!(program.warnings.is_empty());
+}
+
+#[test]
+fn only_variables_can_be_assigned_to() {
+    for source in ["a + b = c;", "1 = 2;", "-a = 1;", "a = b + c = d;"] {
+        let errs = compile(source).expect_err("an invalid target to be an error");
+        assert!(matches!(errs[0].kind, CompileErrKind::InvalidAssignmentTarget), "{}", source);
+    }
+
+    let errs = compile("a + b = c;").expect_err("an invalid target to be an error");
+    assert_eq!(1, errs.len());
+    assert_eq!(7, errs[0].location.col());
+}
+
+#[test]
+fn print_statements_take_one_semicolon() {
+    let program = compile("print 1 + 2;").expect("program to compile");
+    let (_, ops) = decode_ops(&program.code);
+    assert_eq!(vec![OpCode::Constant, OpCode::Constant, OpCode::Add, OpCode::Print, OpCode::Return], ops);
+
+    let errs = compile("print 1 + 2").expect_err("the semicolon to be required");
+    assert_eq!(1, errs.len());
+    assert!(matches!(errs[0].kind, CompileErrKind::MissingSemicolon));
+}
+
+#[test]
+fn stats_count_bytes_constants_and_strings() {
+    let interner = Rc::new(RefCell::new(Interner::new()));
+    let program = compile_with_interner("print 1 + 2;", CompileOptions::default(), interner.clone())
+        .expect("program to compile");
+    // Two constants with one operand byte each, then Add, Print and Return.
+    assert_eq!(CompileStats { code_bytes: 7, constants: 2, interned_strings: 0, functions: 0 }, program.stats(&interner.borrow()));
+
+    let interner = Rc::new(RefCell::new(Interner::new()));
+    let program = compile_with_interner("var a = \"x\"; print a + \"x\";", CompileOptions::default(), interner.clone())
+        .expect("program to compile");
+    let stats = program.stats(&interner.borrow());
+    assert_eq!(2, stats.interned_strings);
+    assert!(stats.report().contains(&format!("{} of {}", stats.constants, STACK_MAX)));
+
+    // A function's code counts toward the program's.
+    let interner = Rc::new(RefCell::new(Interner::new()));
+    let program = compile_with_interner("fun f() {}", CompileOptions::default(), interner.clone())
+        .expect("program to compile");
+    let stats = program.stats(&interner.borrow());
+    assert_eq!(1, stats.functions);
+    assert_eq!(program.code.len() + 2, stats.code_bytes);
+}
+
+#[test]
+fn calls_push_their_arguments_in_order() {
+    use crate::chunk::Operands;
+
+    let program = compile("print f(1, 2)(3);").expect("program to compile");
+    let (decoded, _) = decode_ops(&program.code);
+    let ops: Vec<(OpCode, Operands)> = decoded.iter()
+        .map(|instruction| (instruction.op, instruction.operands))
+        .collect();
+    assert_eq!(vec![
+        (OpCode::GetGlobal, Operands::Byte(0)),
+        (OpCode::Constant, Operands::Byte(1)),
+        (OpCode::Constant, Operands::Byte(2)),
+        (OpCode::Call, Operands::Byte(2)),
+        (OpCode::Constant, Operands::Byte(3)),
+        (OpCode::Call, Operands::Byte(1)),
+        (OpCode::Print, Operands::None),
+        (OpCode::Return, Operands::None),
+    ], ops);
+
+    let too_many = (0..256).map(|i| i.to_string()).collect::<Vec<_>>().join(", ");
+    let errs = compile(&format!("f({});", too_many)).expect_err("the arguments to not fit in a byte");
+    assert!(errs.iter().any(|err| matches!(err.kind, CompileErrKind::TooManyArguments)));
+    assert!(compile("f(1, 2;").is_err());
+}
+
+#[test]
+fn only_calls_returned_directly_are_tail_calls() {
+    let ops_of = |source: &str| -> Vec<OpCode> {
+        let program = compile(source).expect("program to compile");
+        let function = program.values.iter()
+            .find_map(Value::as_function)
+            .expect("the function to be a constant");
+        decode_ops(&function.chunks).1
+    };
+
+    assert_eq!(vec![OpCode::GetGlobal, OpCode::Constant, OpCode::TailCall, OpCode::Return, OpCode::Nil, OpCode::Return],
+        ops_of("fun f() { return g(1); }"));
+    assert!(!ops_of("fun f() { return 1 + g(1); }").contains(&OpCode::TailCall));
+    assert!(!ops_of("fun f() { try { return g(1); } catch (e) {} }").contains(&OpCode::TailCall));
+}
+
+#[test]
+fn functions_compile_to_their_own_code() {
+    let program = compile("fun add(a, b) { return a + b; }").expect("program to compile");
+    let function = program.values.iter()
+        .find_map(Value::as_function)
+        .expect("the function to be a constant");
+    assert_eq!(2, function.arity);
+    assert_eq!("add", function.name);
+    let (_, ops) = decode_ops(&function.chunks);
+    // The parameters are in slots 1 and 2, after the function itself.
+    assert_eq!(vec![
+        OpCode::GetLocal,
+        OpCode::GetLocal,
+        OpCode::Add,
+        OpCode::Return,
+        OpCode::Nil,
+        OpCode::Return,
+    ], ops);
+    assert_eq!(1, function.chunks[1].op);
+    assert_eq!(2, function.chunks[3].op);
+
+    let errs = compile("return 1;").expect_err("return to need a function");
+    assert!(matches!(errs[0].kind, CompileErrKind::ReturnOutsideFunction));
+    let errs = compile("fun f(a, a) {}").expect_err("parameters to be distinct");
+    assert!(matches!(errs[0].kind, CompileErrKind::DuplicateLocal));
+    // A function has no loop of its own to break out of, even when it is declared in one.
+    let errs = compile("while (true) { fun f() { break; } }").expect_err("break to need a loop in its function");
+    assert!(matches!(errs[0].kind, CompileErrKind::BreakOutsideLoop));
+    let params = (0..256).map(|i| format!("p{}", i)).collect::<Vec<_>>().join(", ");
+    let errs = compile(&format!("fun f({}) {{}}", params)).expect_err("the parameters to not fit in a call");
+    assert!(errs.iter().any(|err| matches!(err.kind, CompileErrKind::TooManyParameters)));
+}
+
+#[test]
+fn number_methods_are_lowered_to_native_calls() {
+    let program = compile("(2.9).floor();").expect("a method call to compile");
+    let (decoded, ops) = decode_ops(&program.code);
+    assert_eq!(vec![
+        OpCode::GetGlobal, OpCode::Constant, OpCode::Call, OpCode::Pop, OpCode::Return,
+    ], ops);
+    assert_eq!(crate::chunk::Operands::Byte(1), decoded[2].operands);
+
+    let errs = compile("(2.9).round();").expect_err("round to not be a method");
+    assert!(matches!(&errs[0].kind, CompileErrKind::UnknownMethod(name) if name == "round"));
+    // Methods take no arguments of their own.
+    let errs = compile("(2.9).floor(1);").expect_err("floor to take no arguments");
+    assert!(matches!(&errs[0].kind, CompileErrKind::UnexpectedToken(_)));
+}
@@ -0,0 +1,238 @@
+/// The long-form explanation of each error code, printed by `--explain`.
+const EXPLANATIONS: &[(&str, &str)] = &[
+    ("E0001", "\
+The source contains text that isn't part of any Lox token, such as a stray
+character, a number ending in a period, an unterminated string, or a bad
+escape in a string.
+
+    var price = 5.;    // error: nothing after the period
+    var price = 5.0;   // ok"),
+    ("E0002", "\
+A token appeared where the grammar doesn't allow it. The message lists the
+tokens that would have been valid instead.
+
+    var = 1;           // error: the variable has no name
+    var a = 1;         // ok"),
+    ("E0003", "\
+The program uses more constants (numbers, strings, and variable names) than
+fit in the constant pool. Split the program up or reuse values.
+
+    var a = \"first\", b = \"second\";  // each string is one constant"),
+    ("E0004", "\
+A statement is missing the semicolon that ends it.
+
+    print \"hello\"      // error
+    print \"hello\";     // ok
+
+Running with --implicit-semicolons lets a line break end the statement instead."),
+    ("E0005", "\
+A closing brace was found with no block open for it to close, so everything
+after it is stray.
+
+    print 1; }         // error
+    print 1;           // ok"),
+    ("E0006", "\
+The compiler produced bytecode that doesn't keep the value stack balanced.
+This is a bug in the interpreter rather than in the program. Please report it
+along with the program that triggered it."),
+    ("E0007", "\
+Two operands are next to each other with nothing between them, so the
+compiler can't tell how they should be combined. An operator is probably
+missing.
+
+    print 1 2;         // error
+    print 1 + 2;       // ok"),
+    ("E0008", "\
+A branch or loop body compiled to more bytecode than a jump can cover. Jumps
+reach at most 65535 bytes, so split the body up, for example into functions."),
+    ("E0009", "\
+A local variable was read in its own initializer. The new variable doesn't
+have a value yet, and it shadows any outer variable with the same name, so
+there is nothing for the initializer to read.
+
+    { var a = a; }         // error
+    { var b = a; }         // ok, if a is declared outside the block"),
+    ("E0010", "\
+A block has more local variables in scope at once than the value stack has
+room for. Move some of them into an inner block or make them globals."),
+    ("E0011", "\
+A break statement was used outside of a loop, so there is no loop for it to
+leave.
+
+    if (done) break;                   // error
+    while (true) { if (done) break; }  // ok"),
+    ("E0012", "\
+The left side of '=' isn't a variable, so there is nothing to assign to.
+
+    a + b = c;         // error
+    a = b + c;         // ok"),
+    ("E0013", "\
+A local variable was declared twice in the same block. Declaring it in an
+inner block instead shadows the outer one until that block ends.
+
+    { var a = 1; var a = 2; }      // error
+    { var a = 1; { var a = 2; } }  // ok"),
+    ("E0014", "\
+A call passed more than 255 arguments. The argument count is stored in a
+single byte, so pass a smaller number of values."),
+    ("E0015", "\
+A return statement was found outside of any function, so there is nothing for
+it to return from.
+
+    return 1;                      // error
+    fun one() { return 1; }        // ok"),
+    ("E0016", "\
+A function declared more than 255 parameters. A call can't pass more than 255
+arguments, so the function could never be called."),
+    ("E0017", "\
+A method was called that numbers don't have. Numbers have abs, floor, and
+sqrt, each of which takes no arguments.
+
+    print (2.9).round();     // error
+    print (2.9).floor();     // ok: 2"),
+    ("W0001", "\
+An expression statement computes a value and then throws it away without
+doing anything else, so it has no effect. This is only a warning unless
+--deny-warnings is used.
+
+    a + 1;             // warning: the sum is discarded
+    print a + 1;       // ok"),
+    ("E0101", "\
+An arithmetic operator was used on something other than numbers. '+' also
+accepts two strings, which it concatenates. With --coercion numeric, strings
+that parse as numbers count as numbers.
+
+    print 1 + true;    // error
+    print 1 + 2;       // ok
+    print \"a\" + \"b\";   // ok"),
+    ("E0102", "\
+A comparison ('<', '<=', '>', '>=') was used on values that aren't both numbers
+or both strings. Strings compare lexicographically.
+
+    print \"a\" < 1;     // error
+    print \"a\" < \"b\";   // ok
+    print 1 < 2;       // ok"),
+    ("E0103", "\
+A boolean operator ('and', 'or') was used on an object such as a string.
+
+    print \"yes\" and true;  // error
+    print true and true;   // ok"),
+    ("E0104", "\
+A boolean operator ('and', 'or') was used on a number.
+
+    print 1 or false;  // error
+    print true or false;   // ok"),
+    ("E0105", "\
+A string concatenation would have created a string longer than the limit set
+with --max-string-length. Raise the limit, or build smaller strings."),
+    ("E0106", "\
+'+' was used on a string and a number, which the strict coercion policy (the
+default) doesn't allow. With --coercion concat, the number is written out and
+concatenated with the string. With --coercion numeric, the string is parsed as
+a number.
+
+    print \"5\" + 3;     // error by default, \"53\" with concat, 8 with numeric
+    print \"5\" + \"3\";   // ok"),
+    ("E0107", "\
+A variable was read or assigned before any var statement defined it. Globals
+are defined when their var statement runs, so it has to run first. Assigning
+to a variable doesn't define it.
+
+    print a;           // error
+    a = 1;             // error
+    var a = 1;
+    print a;           // ok"),
+    ("E0108", "\
+A number was divided by zero with '/' or '%'. Neither produces infinity or NaN,
+so check the divisor first.
+
+    print 1 / 0;       // error
+    print 5 % 0;       // error
+    print 0 / 1;       // ok"),
+    ("E0109", "\
+Something other than a function was called. Only functions, such as the
+native clock, can be called.
+
+    print \"a\"();       // error
+    print clock();     // ok"),
+    ("E0110", "\
+A function was called with a different number of arguments than it takes.
+Some natives take optional arguments, like approxEqual's epsilon.
+
+    print clock(1);    // error
+    print clock();     // ok"),
+    ("E0111", "\
+Calls nested too deeply, usually because a function kept calling itself
+without ever reaching a case that returns. Only 64 calls can be running at
+once, and their arguments, locals and temporary values share a stack of 255
+slots, so deep calls can also run out of slots first.
+
+    fun forever() { return forever(); }
+    forever();         // error"),
+    ("E0112", "\
+A native function was passed an argument of a type it doesn't take.
+
+    print approxEqual(\"a\", 1);   // error
+    print approxEqual(0.3, 0.1 + 0.2);  // ok"),
+    ("E0113", "\
+A string was indexed outside of its characters. Indexes count characters, not
+bytes, from 0, so the last one is one less than the number of characters.
+
+    print charAt(\"héllo\", 5);    // error
+    print charAt(\"héllo\", 1);    // ok: \"é\""),
+    ("E0114", "\
+A format template didn't have one {} placeholder for each argument after it.
+Braces that should be printed are doubled, so they aren't counted.
+
+    print format(\"{} and {}\", 1);      // error
+    print format(\"{{{}}} and {}\", 1, 2);  // ok: \"{1} and 2\""),
+];
+
+/// The explanation for an error code like E0001, if there is one.
+pub (crate) fn explain(code: &str) -> Option<&'static str> {
+    EXPLANATIONS.iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(code))
+        .map(|(_, explanation)| *explanation)
+}
+
+#[test]
+fn every_error_code_is_explained() {
+    use crate::{compiler::CompileErrKind, vm::RunTimeErrKind};
+
+    let codes = [
+        CompileErrKind::TooManyValues.as_str(),
+        CompileErrKind::MissingSemicolon.as_str(),
+        CompileErrKind::TrailingTokens.as_str(),
+        CompileErrKind::StackImbalance { offset: 0 }.as_str(),
+        CompileErrKind::UnusedExpressionResult.as_str(),
+        CompileErrKind::MissingOperator.as_str(),
+        CompileErrKind::JumpTooLarge.as_str(),
+        CompileErrKind::LocalInOwnInitializer.as_str(),
+        CompileErrKind::TooManyLocals.as_str(),
+        CompileErrKind::BreakOutsideLoop.as_str(),
+        CompileErrKind::InvalidAssignmentTarget.as_str(),
+        CompileErrKind::DuplicateLocal.as_str(),
+        CompileErrKind::TooManyArguments.as_str(),
+        CompileErrKind::ReturnOutsideFunction.as_str(),
+        CompileErrKind::TooManyParameters.as_str(),
+        CompileErrKind::UnknownMethod("round".into()).as_str(),
+        RunTimeErrKind::ArithmeticOnNonNumber.as_str(),
+        RunTimeErrKind::StringTooLong.as_str(),
+        RunTimeErrKind::MixedConcatenation.as_str(),
+        RunTimeErrKind::UndefinedVariable("a".into()).as_str(),
+        RunTimeErrKind::DivisionByZero.as_str(),
+        RunTimeErrKind::NotCallable.as_str(),
+        RunTimeErrKind::WrongArgumentCount { min: 0, max: 0, found: 1 }.as_str(),
+        RunTimeErrKind::WrongArgumentType { expected: "a number" }.as_str(),
+        RunTimeErrKind::IndexOutOfBounds { index: 1.0, len: 0 }.as_str(),
+        RunTimeErrKind::FormatArgumentCount { placeholders: 1, arguments: 0 }.as_str(),
+        RunTimeErrKind::StackOverflow.as_str(),
+    ];
+    for code in codes {
+        assert!(explain(code).is_some(), "{} to have an explanation", code);
+    }
+
+    assert!(explain("E0004").expect("E0004 to be explained").contains("semicolon"));
+    assert!(explain("e0004").is_some());
+    assert!(explain("E9999").is_none());
+}
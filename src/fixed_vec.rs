@@ -1,5 +1,6 @@
 use std::{array::from_fn, ops::{Index, IndexMut}};
 
+#[derive(Debug)]
 pub (crate) struct FixedVec<T, const N: usize> {
     arr: [Option<T>; N],
     len: usize,
@@ -27,15 +28,44 @@ impl<T, const N: usize> FixedVec<T, N> {
     pub (crate) fn pop(&mut self) -> Option<T> {
         if self.len > 0 {
             self.len -= 1;
-            Some(std::mem::take(
-                &mut self.arr[self.len as usize]
-            )).unwrap()
+            std::mem::take(
+                &mut self.arr[self.len]
+            )
         } else {
             None
         }
     }
 
+    /// Drops every value past the first len. Does nothing if there are already len or fewer.
+    pub (crate) fn truncate(&mut self, len: usize) {
+        while self.len > len {
+            self.pop();
+        }
+    }
+
+    /// Sets the length without dropping or initializing anything, for hot paths where
+    /// truncate's one pop per value is too slow. Shrinking leaves the values past the new
+    /// length in place until their slots are pushed to again, so only shrink past values
+    /// that don't need dropping promptly: an Rc kept alive this way still counts as a reference.
+    ///
+    /// # Safety
+    ///
+    /// `new_len` must be at most `N`, and every slot in `0..new_len` must hold a value, either
+    /// because it is below the current length or because it was pushed to and has only been
+    /// hidden by an earlier set_len since. The slots are Options today, so breaking this panics
+    /// instead of reading uninitialized memory, but callers must not rely on that.
+    #[allow(dead_code)] // No fast path in the VM uses it yet; its tests pin down the contract for the first one.
+    pub (crate) unsafe fn set_len(&mut self, new_len: usize) {
+        debug_assert!(new_len <= N, "set_len({}) past the capacity of {}", new_len, N);
+        debug_assert!(self.arr[..new_len].iter().all(Option::is_some), "set_len({}) over an empty slot", new_len);
+        self.len = new_len;
+    }
+
     pub (crate) fn get(&self, index: usize) -> Option<&T> {
+        // Slots past the length can still hold values left behind by set_len.
+        if index >= self.len {
+            return None;
+        }
         match self.arr.get(index) {
             None => None,
             Some(val) => {
@@ -44,6 +74,18 @@ impl<T, const N: usize> FixedVec<T, N> {
         }
     }
 
+    pub (crate) fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index >= self.len {
+            return None;
+        }
+        match self.arr.get_mut(index) {
+            None => None,
+            Some(val) => {
+                val.as_mut()
+            }
+        }
+    }
+
     pub (crate) fn len(&self) -> usize {
         self.len
     }
@@ -53,7 +95,14 @@ impl<T, const N: usize> FixedVec<T, N> {
     }
 
     pub (crate) fn iter<'a>(&'a self) -> Iter<'a, T> {
-        Iter { inner: self.arr.iter() }
+        // Only the first len slots are populated.
+        Iter { inner: self.arr[..self.len].iter() }
+    }
+}
+
+impl<T, const N: usize> Default for FixedVec<T, N> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -62,7 +111,7 @@ impl<T, const N: usize> IntoIterator for FixedVec<T, N> {
     type IntoIter = IntoIter<T, N>;
 
     fn into_iter(self) -> Self::IntoIter {
-        IntoIter { inner: self.arr.into_iter() }
+        IntoIter { remaining: self.len, inner: self.arr.into_iter() }
     }
 }
 
@@ -95,9 +144,18 @@ impl<'a, T> Iterator for Iter<'a, T> {
             }
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
 }
 
+impl<'a, T> ExactSizeIterator for Iter<'a, T> {}
+
 pub (crate) struct IntoIter<T, const N: usize> {
+    /// The number of populated slots not yet yielded. 
+    /// The backing array also contains the unpopulated None slots after these.
+    remaining: usize,
     inner: std::array::IntoIter<Option<T>, N>,
 }
 
@@ -105,14 +163,103 @@ impl<T, const N: usize> Iterator for IntoIter<T, N> {
     type Item = T;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.inner.next() {
-            None => None,
-            Some(opt) => opt,
+        if self.remaining == 0 {
+            return None;
         }
+        self.remaining -= 1;
+        self.inner.next().unwrap_or_default()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
     }
 }
 
+impl<T, const N: usize> ExactSizeIterator for IntoIter<T, N> {}
+
 #[derive(Debug)]
 pub (crate) enum FixedVecErr {
     AlreadyFull,
-}
\ No newline at end of file
+}
+
+#[test]
+fn default_is_empty() {
+    let mut vec: FixedVec<usize, 4> = Default::default();
+    assert_eq!(0, vec.len());
+    assert_eq!(4, vec.capacity());
+
+    vec.push(1).expect("empty vec to have room");
+    assert_eq!(Some(&1), vec.get(0));
+}
+
+#[test]
+fn iterators_know_their_length() {
+    let mut vec = FixedVec::<usize, 8>::new();
+    for i in 0..3 {
+        vec.push(i).expect("vec to have room");
+    }
+
+    let mut iter = vec.iter();
+    assert_eq!(3, iter.len());
+    iter.next();
+    assert_eq!(2, iter.len());
+
+    let mut into_iter = vec.into_iter();
+    assert_eq!(3, into_iter.len());
+    assert_eq!(Some(0), into_iter.next());
+    assert_eq!(2, into_iter.len());
+    assert_eq!(vec![1, 2], into_iter.collect::<Vec<_>>());
+}
+
+#[test]
+fn truncate_drops_values_past_the_length() {
+    let mut vec = FixedVec::<usize, 4>::new();
+    for i in 0..4 {
+        vec.push(i).expect("vec to have room");
+    }
+
+    vec.truncate(2);
+    assert_eq!(2, vec.len());
+    assert_eq!(None, vec.get(2));
+    vec.truncate(3);
+    assert_eq!(2, vec.len());
+}
+
+#[test]
+fn set_len_matches_truncate() {
+    let filled = || {
+        let mut vec = FixedVec::<String, 4>::new();
+        for i in 0..4 {
+            vec.push(i.to_string()).expect("vec to have room");
+        }
+        vec
+    };
+    let mut truncated = filled();
+    let mut shortened = filled();
+
+    truncated.truncate(1);
+    // SAFETY: 1 is within the capacity, and slot 0 holds a value.
+    unsafe { shortened.set_len(1) };
+    assert_eq!(truncated.len(), shortened.len());
+    assert_eq!(truncated.get(1), shortened.get(1));
+    assert_eq!(
+        truncated.iter().collect::<Vec<_>>(),
+        shortened.iter().collect::<Vec<_>>()
+    );
+
+    truncated.push("new".to_string()).expect("vec to have room");
+    shortened.push("new".to_string()).expect("vec to have room");
+    assert_eq!(
+        truncated.into_iter().collect::<Vec<_>>(),
+        shortened.into_iter().collect::<Vec<_>>()
+    );
+
+    // Growing back over values that were only hidden brings them back.
+    let mut vec = filled();
+    // SAFETY: every slot up to the old length of 4 still holds its value.
+    unsafe {
+        vec.set_len(2);
+        vec.set_len(4);
+    }
+    assert_eq!(Some(&"3".to_string()), vec.get(3));
+}
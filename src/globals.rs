@@ -0,0 +1,112 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::value::Value;
+
+/// The global variables. Each name is given a slot the first time it is defined, so
+/// GetGlobal can cache the slot it resolved a name to and skip hashing the name again.
+#[derive(Debug, Default)]
+pub (crate) struct Globals {
+    slots: HashMap<Rc<str>, usize>,
+    /// A slot is None once its variable has been removed. Slots aren't reused.
+    values: Vec<Option<Value>>,
+    /// Changes whenever a variable is redefined or removed, which invalidates every cached slot.
+    generation: u64,
+}
+
+impl Globals {
+    pub (crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub (crate) fn get(&self, name: &str) -> Option<&Value> {
+        self.slots.get(name).and_then(|&slot| self.at(slot))
+    }
+
+    pub (crate) fn get_mut(&mut self, name: &str) -> Option<&mut Value> {
+        let slot = *self.slots.get(name)?;
+        self.values[slot].as_mut()
+    }
+
+    #[cfg(test)]
+    pub (crate) fn contains_key(&self, name: &str) -> bool {
+        self.slots.contains_key(name)
+    }
+
+    /// Defines a variable, or redefines it in the slot it already has.
+    pub (crate) fn insert(&mut self, name: Rc<str>, value: Value) {
+        match self.slots.get(&name) {
+            Some(&slot) => {
+                self.values[slot] = Some(value);
+                self.generation += 1;
+            }
+            None => {
+                self.slots.insert(name, self.values.len());
+                self.values.push(Some(value));
+            }
+        }
+    }
+
+    pub (crate) fn remove(&mut self, name: &str) -> Option<Value> {
+        let slot = self.slots.remove(name)?;
+        self.generation += 1;
+        self.values[slot].take()
+    }
+
+    /// The slot a variable is in, if it is defined.
+    pub (crate) fn slot(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    /// The value in a slot, unless its variable has been removed.
+    pub (crate) fn at(&self, slot: usize) -> Option<&Value> {
+        self.values.get(slot).and_then(Option::as_ref)
+    }
+
+    /// A slot cached while this was the generation is still the slot of the same variable.
+    pub (crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub (crate) fn values(&self) -> impl Iterator<Item = &Value> {
+        self.values.iter().flatten()
+    }
+
+    #[cfg(test)]
+    pub (crate) fn iter(&self) -> impl Iterator<Item = (&Rc<str>, &Value)> {
+        self.slots.iter().filter_map(|(name, &slot)| Some((name, self.at(slot)?)))
+    }
+}
+
+#[test]
+fn redefining_keeps_the_slot_but_changes_the_generation() {
+    let mut globals = Globals::new();
+    globals.insert("a".into(), Value::Number(1.0));
+    globals.insert("b".into(), Value::Number(2.0));
+    let slot = globals.slot("a").expect("a to be defined");
+    let generation = globals.generation();
+
+    // Defining another variable leaves cached slots valid.
+    globals.insert("c".into(), Value::Nil);
+    assert_eq!(generation, globals.generation());
+
+    globals.insert("a".into(), Value::Number(3.0));
+    assert_eq!(Some(slot), globals.slot("a"));
+    assert_ne!(generation, globals.generation());
+    assert_eq!(Some(&Value::Number(3.0)), globals.at(slot));
+}
+
+#[test]
+fn removed_variables_leave_their_slot_empty() {
+    let mut globals = Globals::new();
+    globals.insert("a".into(), Value::Number(1.0));
+    let slot = globals.slot("a").expect("a to be defined");
+    let generation = globals.generation();
+
+    assert_eq!(Some(Value::Number(1.0)), globals.remove("a"));
+    assert_ne!(generation, globals.generation());
+    assert!(globals.at(slot).is_none() && globals.get("a").is_none());
+
+    globals.insert("a".into(), Value::Number(2.0));
+    assert_ne!(Some(slot), globals.slot("a"));
+    assert_eq!(1, globals.values().count());
+}
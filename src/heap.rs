@@ -1,11 +1,10 @@
 use std::{
     rc::Rc, 
+    cell::RefCell,
     collections::HashSet
 };
 
-use crate::object::Object;
-
-const NUM_INTERNAL_REFS: usize = 2;
+use crate::{interner::Interner, object::Object, value::Value};
 
 pub (crate) struct ObjectHeap {
     first_obj: Option<Box<LLNode<Object>>>,
@@ -28,10 +27,7 @@ impl ObjectHeap {
                 self.objs.insert(rc.clone());
                 self.first_obj = Some(Box::new(LLNode {
                     item: rc.clone(),
-                    next: match std::mem::take(&mut self.first_obj) {
-                        None => None,
-                        Some(node) => Some(node),
-                    }
+                    next: std::mem::take(&mut self.first_obj),
                 }));
                 self.first_obj
                     .as_ref()
@@ -42,34 +38,76 @@ impl ObjectHeap {
         }
     }
 
-    pub (crate) fn collect_garbage(&mut self) {
+    /// How many objects are on the heap.
+    pub (crate) fn len(&self) -> usize {
+        self.objs.len()
+    }
+
+    /// Frees every object that can't be reached from the roots. The VM passes its
+    /// value stack, constants, and globals.
+    pub (crate) fn collect_garbage(&mut self, roots: &[&Value]) {
+        let marked = Self::mark(roots);
+
         let mut current = &mut self.first_obj;
-        loop {
-            if let Some(current_ref) = &current {
-                // If the only strong pointers are the ones in the heap, clean it up.
-                // This has the side effect of moving us to the next node as well.
-                if Rc::strong_count(&current_ref.item) == NUM_INTERNAL_REFS {
-                    // borrow checker mad, just use unwrap()
-                    *current = std::mem::take(
-                        &mut current
-                                .as_mut()
-                                .unwrap()
-                                .next
-                    )
-                // otherwise, continue the loop
-                } else {
-                    // borrow checker mad, just use unwrap()
-                    current = &mut current
+        while let Some(current_ref) = &current {
+            // Unlink unmarked objects. This has the side effect of moving us to the next node as well.
+            if !marked.contains(&Rc::as_ptr(&current_ref.item)) {
+                self.objs.remove(&*current_ref.item);
+                // borrow checker mad, just use unwrap()
+                *current = std::mem::take(
+                    &mut current
                             .as_mut()
                             .unwrap()
-                            .next;
-                }
-            // If the current is None, we are done with the loop.
+                            .next
+                )
+            // otherwise, continue the loop
             } else {
-                break;
+                // borrow checker mad, just use unwrap()
+                current = &mut current
+                        .as_mut()
+                        .unwrap()
+                        .next;
             }
         }
     }
+
+    /// The addresses of every object reachable from the roots.
+    fn mark(roots: &[&Value]) -> HashSet<*const Object> {
+        let mut marked = HashSet::new();
+        let mut gray: Vec<&Rc<Object>> = roots.iter()
+            .filter_map(|root| match root {
+                Value::Object(obj) => Some(obj),
+                _ => None,
+            })
+            .collect();
+
+        while let Some(obj) = gray.pop() {
+            if !marked.insert(Rc::as_ptr(obj)) {
+                continue;
+            }
+            // Objects that hold other values push them onto `gray` here.
+            match &**obj {
+                // A function's constants are in the pool, which is a root of its own.
+                Object::String(_) | Object::Native(_) | Object::Function(_) => {}
+            }
+        }
+        marked
+    }
+}
+
+/// Makes strings at runtime. They are interned, so they share any equal constant,
+/// and added to the heap, so they can be collected. Natives are given one to make
+/// the strings they return.
+pub (crate) struct Strings<'v> {
+    pub (crate) interner: &'v RefCell<Interner>,
+    pub (crate) heap: &'v mut ObjectHeap,
+}
+
+impl Strings<'_> {
+    pub (crate) fn alloc(&mut self, string: &str) -> Value {
+        let string = self.interner.borrow_mut().intern(string);
+        Value::Object(self.heap.add(Object::String(string)))
+    }
 }
 
 struct LLNode<T>
@@ -78,24 +116,26 @@ struct LLNode<T>
     next: Option<Box<LLNode<T>>>,
 }
 
+#[cfg(test)]
 struct LLIter<'a, T> {
     next: Option<&'a LLNode<T>>,
 }
 
+#[cfg(test)]
 impl<'a, T> Iterator for LLIter<'a, T> {
     type Item = &'a T;
 
     fn next(&mut self) -> Option<Self::Item> {
         match std::mem::take(&mut self.next) {
             None => {
-                return None;
+                None
             }
             Some(node) => {
                 self.next = match node.next.as_ref() {
                     None => None,
                     Some(next) => Some(&**next),
                 };
-                return Some(&node.item);
+                Some(&node.item)
             }
         }
     }
@@ -107,4 +147,27 @@ fn garbage_collection() {
     for _ in 0..100 {
         heap.add(Object::String("test".into()));
     }
+    assert_eq!(1, heap.objs.len());
+}
+
+#[test]
+fn objects_dropped_from_every_root_are_collected() {
+    let mut heap = ObjectHeap::new();
+    let kept = Value::Object(heap.add(Object::String("kept".into())));
+    let dropped = Rc::downgrade(&heap.add(Object::String("dropped".into())));
+
+    // An extra reference outside the roots doesn't keep an object alive.
+    let held = dropped.upgrade().expect("the object to still be on the heap");
+    heap.collect_garbage(&[&kept, &Value::Nil]);
+    assert_eq!(1, heap.objs.len());
+    drop(held);
+    assert!(dropped.upgrade().is_none());
+
+    heap.collect_garbage(&[&kept]);
+    assert_eq!(1, heap.objs.len());
+    assert_eq!(1, LLIter { next: heap.first_obj.as_deref() }.count());
+
+    heap.collect_garbage(&[]);
+    assert!(heap.objs.is_empty());
+    assert!(heap.first_obj.is_none());
 }
\ No newline at end of file
@@ -0,0 +1,42 @@
+use std::{collections::HashMap, rc::Rc};
+
+/// Hands out one shared `Rc<str>` per distinct string. The compiler and the VM
+/// share an interner, so a string made at runtime reuses a matching constant.
+#[derive(Debug, Default)]
+pub (crate) struct Interner {
+    strings: HashMap<Rc<str>, ()>,
+}
+
+impl Interner {
+    pub (crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// The handle for this string, creating it if it hasn't been seen yet.
+    pub (crate) fn intern(&mut self, string: &str) -> Rc<str> {
+        if let Some((interned, ())) = self.strings.get_key_value(string) {
+            return interned.clone();
+        }
+
+        let interned: Rc<str> = string.into();
+        self.strings.insert(interned.clone(), ());
+        interned
+    }
+
+    /// The number of distinct strings interned so far.
+    pub (crate) fn len(&self) -> usize {
+        self.strings.len()
+    }
+}
+
+#[test]
+fn equal_strings_share_one_rc() {
+    let mut interner = Interner::new();
+    let a = interner.intern("lox");
+    let b = interner.intern(&String::from("lox"));
+    let c = interner.intern("other");
+
+    assert!(Rc::ptr_eq(&a, &b));
+    assert!(!Rc::ptr_eq(&a, &c));
+    assert_eq!(2, interner.len());
+}
@@ -0,0 +1,72 @@
+//! A bytecode interpreter for Lox. [`interpret`] runs a program, and
+//! [`interpret_and_call`] runs one and then calls a function it defined.
+//! [`parse`] stops after parsing and returns the program's statements.
+//! Compile errors can be inspected through [`CompileErr::kind`] and
+//! [`CompileErr::unexpected`]; everything else is internal to the interpreter
+//! and the `rslox` command line.
+
+use std::io::{stdout, Write};
+
+mod fixed_vec;
+mod chunk;
+mod tokenizer;
+mod value;
+mod object;
+mod heap;
+mod interner;
+mod globals;
+mod natives;
+mod compiler;
+mod vm;
+mod profiler;
+mod explain;
+mod repl;
+mod ast_json;
+#[cfg(test)]
+mod reference_eval;
+#[doc(hidden)]
+pub mod cli;
+
+pub use compiler::{
+    CompileErr, CompileErrKind, Unexpected,
+    Statement, VarDeclaration, FunctionDeclaration,
+    ExpressionTreeNode, ExpressionBranch, ExpressionLeaf, Operator,
+};
+pub use object::Object;
+pub use tokenizer::{LoxParseErr, LoxToken, Position, Span, TokenKind};
+pub use tree_iterators_rs::prelude::BinaryTreeNode;
+pub use value::Value;
+pub use vm::{LoxError, RunTimeErr, VMErr};
+
+use fixed_vec::FixedVec;
+use vm::VM;
+
+const DEBUG_TRACE_EXECUTION: bool = false;
+const DEBUG_DUMP_INSTRUCTIONS: bool = false;
+
+/// Parses a program without compiling it. Like compiling, every error found is returned.
+pub fn parse(source: &str) -> Result<Vec<Statement>, Vec<CompileErr>> {
+    compiler::parse(source, Default::default())
+}
+
+/// Compiles and runs a program with the default options, printing to stdout.
+/// A program can have several compile errors, so every one is returned.
+pub fn interpret(source: &str) -> Result<(), Vec<LoxError>> {
+    interpret_with_output(source, Box::new(stdout()))
+}
+
+/// Like [`interpret`], but what the program prints is written to `output`.
+pub fn interpret_with_output(source: &str, output: Box<dyn Write>) -> Result<(), Vec<LoxError>> {
+    let mut vm = VM::new(Vec::new(), FixedVec::new())
+        .with_output(output);
+    vm.evaluate(source).map(|_| ())
+}
+
+/// Runs a program, then calls the global function `name` with `args` and returns what it
+/// returns. This is for embedding: the program's top-level code does the setup, and the
+/// host calls its entry point.
+pub fn interpret_and_call(source: &str, name: &str, args: &[Value]) -> Result<Value, Vec<LoxError>> {
+    let mut vm = VM::new(Vec::new(), FixedVec::new());
+    vm.evaluate(source)?;
+    vm.call_global(name, args).map_err(|err| vec![LoxError::Run(err)])
+}
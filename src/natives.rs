@@ -0,0 +1,173 @@
+use std::{ops::RangeInclusive, rc::Rc, sync::OnceLock, time::Instant};
+
+use crate::{heap::Strings, object::NativeFn, value::Value, vm::RunTimeErrKind};
+
+/// A native the VM defines as a global, and the range of argument counts it accepts.
+pub (crate) struct NativeDef {
+    pub (crate) name: &'static str,
+    pub (crate) arity: RangeInclusive<u8>,
+    pub (crate) function: NativeFn,
+}
+
+/// Natives whose result only depends on their arguments.
+pub (crate) const PURE: &[NativeDef] = &[
+    NativeDef { name: "abs", arity: 1..=1, function: abs },
+    NativeDef { name: "approxEqual", arity: 2..=3, function: approx_equal },
+    NativeDef { name: "charAt", arity: 2..=2, function: char_at },
+    NativeDef { name: "codePointAt", arity: 2..=2, function: code_point_at },
+    NativeDef { name: "equalsIgnoreCase", arity: 2..=2, function: equals_ignore_case },
+    NativeDef { name: "floor", arity: 1..=1, function: floor },
+    NativeDef { name: "format", arity: 1..=u8::MAX, function: format },
+    NativeDef { name: "pad", arity: 2..=2, function: pad },
+    NativeDef { name: "same", arity: 2..=2, function: same },
+    NativeDef { name: "sqrt", arity: 1..=1, function: sqrt },
+];
+
+/// Natives that observe the host, which a sandboxed VM leaves undefined. The clock
+/// counts because timing can leak what the host is doing.
+pub (crate) const IMPURE: &[NativeDef] = &[
+    NativeDef { name: "clock", arity: 0..=0, function: clock },
+];
+
+/// The epsilon approxEqual uses when it isn't given one.
+const DEFAULT_EPSILON: f64 = 1e-9;
+
+/// Seconds since the program first read the clock. It never goes backwards, so
+/// the difference between two readings is how long the code between them took.
+pub (crate) fn clock(_args: &[Value], _strings: &mut Strings) -> Result<Value, RunTimeErrKind> {
+    static START: OnceLock<Instant> = OnceLock::new();
+    Ok(Value::Number(START.get_or_init(Instant::now).elapsed().as_secs_f64()))
+}
+
+/// A number without its sign. `(-2).abs()` is a call to this.
+pub (crate) fn abs(args: &[Value], _strings: &mut Strings) -> Result<Value, RunTimeErrKind> {
+    Ok(Value::Number(number_arg(&args[0])?.abs()))
+}
+
+/// The largest whole number no greater than a number. `(2.9).floor()` is a call to this.
+pub (crate) fn floor(args: &[Value], _strings: &mut Strings) -> Result<Value, RunTimeErrKind> {
+    Ok(Value::Number(number_arg(&args[0])?.floor()))
+}
+
+/// The square root of a number, which is NaN for negative numbers. `(16).sqrt()` is a call to this.
+pub (crate) fn sqrt(args: &[Value], _strings: &mut Strings) -> Result<Value, RunTimeErrKind> {
+    Ok(Value::Number(number_arg(&args[0])?.sqrt()))
+}
+
+/// Whether two numbers are within an epsilon of each other, so `0.1 + 0.2` can be compared
+/// with `0.3`. The epsilon is absolute for numbers up to 1 and relative to the larger
+/// number past that. It is optional, and defaults to DEFAULT_EPSILON.
+pub (crate) fn approx_equal(args: &[Value], _strings: &mut Strings) -> Result<Value, RunTimeErrKind> {
+    let a = number_arg(&args[0])?;
+    let b = number_arg(&args[1])?;
+    let epsilon = match args.get(2) {
+        None => DEFAULT_EPSILON,
+        Some(epsilon) => number_arg(epsilon)?,
+    };
+    let scale = a.abs().max(b.abs()).max(1.0);
+    Ok(Value::Boolean((a - b).abs() <= epsilon * scale))
+}
+
+/// The character at an index into a string, as a string of its own.
+pub (crate) fn char_at(args: &[Value], strings: &mut Strings) -> Result<Value, RunTimeErrKind> {
+    let ch = nth_char(&args[0], &args[1])?;
+    Ok(strings.alloc(ch.encode_utf8(&mut [0; 4])))
+}
+
+/// The Unicode scalar value of the character at an index into a string.
+pub (crate) fn code_point_at(args: &[Value], _strings: &mut Strings) -> Result<Value, RunTimeErrKind> {
+    let ch = nth_char(&args[0], &args[1])?;
+    Ok(Value::Number(ch as u32 as f64))
+}
+
+/// Indexes a string by character rather than by byte, so a multibyte character is never split.
+fn nth_char(string: &Value, index: &Value) -> Result<char, RunTimeErrKind> {
+    let string = string_arg(string)?;
+    let index = index.as_number().ok_or(RunTimeErrKind::WrongArgumentType { expected: "a number" })?;
+    if index.fract() != 0.0 {
+        return Err(RunTimeErrKind::WrongArgumentType { expected: "a whole number" });
+    }
+    let out_of_bounds = || RunTimeErrKind::IndexOutOfBounds { index, len: string.chars().count() };
+    if index < 0.0 {
+        return Err(out_of_bounds());
+    }
+    string.chars().nth(index as usize).ok_or_else(out_of_bounds)
+}
+
+/// Whether two strings are equal once both are lowercased, character by character and without
+/// making new strings. Lowercasing is Unicode-aware, so `É` matches `é`. `ß` is already
+/// lowercase, so it doesn't match `SS`, even though `ß` uppercases to `SS`.
+pub (crate) fn equals_ignore_case(args: &[Value], _strings: &mut Strings) -> Result<Value, RunTimeErrKind> {
+    let a = string_arg(&args[0])?.chars().flat_map(char::to_lowercase);
+    let b = string_arg(&args[1])?.chars().flat_map(char::to_lowercase);
+    Ok(Value::Boolean(a.eq(b)))
+}
+
+/// A template with each `{}` replaced by the string form of the next argument, and `{{` and `}}`
+/// written as literal braces. The template is parsed on every call, so it can be built at runtime.
+/// There must be exactly one argument per placeholder.
+pub (crate) fn format(args: &[Value], strings: &mut Strings) -> Result<Value, RunTimeErrKind> {
+    let template = string_arg(&args[0])?;
+    let arguments = &args[1..];
+    let stray_brace = || RunTimeErrKind::WrongArgumentType { expected: "a template whose only braces are {}, {{, and }}" };
+
+    let mut formatted = String::with_capacity(template.len());
+    let mut placeholders = 0;
+    let mut chars = template.chars();
+    while let Some(ch) = chars.next() {
+        match (ch, chars.clone().next()) {
+            ('{', Some('}')) => {
+                if let Some(argument) = arguments.get(placeholders) {
+                    formatted.push_str(&argument.to_string());
+                }
+                placeholders += 1;
+                chars.next();
+            }
+            ('{', Some('{')) | ('}', Some('}')) => {
+                formatted.push(ch);
+                chars.next();
+            }
+            ('{' | '}', _) => return Err(stray_brace()),
+            _ => formatted.push(ch),
+        }
+    }
+
+    if placeholders != arguments.len() {
+        return Err(RunTimeErrKind::FormatArgumentCount { placeholders, arguments: arguments.len() });
+    }
+    Ok(strings.alloc(&formatted))
+}
+
+/// A value's string form padded with spaces to a width, so columns of a table line up.
+/// Numbers are right aligned and everything else is left aligned. The width counts
+/// characters, and a string form longer than it is kept whole.
+pub (crate) fn pad(args: &[Value], strings: &mut Strings) -> Result<Value, RunTimeErrKind> {
+    let width = match args[1].as_number() {
+        Some(width) if width >= 0.0 && width.fract() == 0.0 => width as usize,
+        _ => return Err(RunTimeErrKind::WrongArgumentType { expected: "a whole number width of 0 or more" }),
+    };
+    let string = args[0].to_string();
+    let padded = match args[0] {
+        Value::Number(_) => format!("{:>width$}", string),
+        _ => format!("{:<width$}", string),
+    };
+    Ok(strings.alloc(&padded))
+}
+
+/// Whether two values are the same object, rather than just equal. Scalars have no
+/// identity of their own, so for them this is the same as `==`.
+pub (crate) fn same(args: &[Value], _strings: &mut Strings) -> Result<Value, RunTimeErrKind> {
+    let same = match (&args[0], &args[1]) {
+        (Value::Object(a), Value::Object(b)) => Rc::ptr_eq(a, b),
+        (a, b) => a == b,
+    };
+    Ok(Value::Boolean(same))
+}
+
+fn string_arg(value: &Value) -> Result<&str, RunTimeErrKind> {
+    value.as_string().ok_or(RunTimeErrKind::WrongArgumentType { expected: "a string" })
+}
+
+fn number_arg(value: &Value) -> Result<f64, RunTimeErrKind> {
+    value.as_number().ok_or(RunTimeErrKind::WrongArgumentType { expected: "a number" })
+}
@@ -1,48 +1,78 @@
-use std::{fmt::Debug, rc::Rc};
-use super::chunk::Chunk;
+use std::{fmt::{Debug, Display}, hash::{Hash, Hasher}, rc::Rc};
+use super::{chunk::Chunk, heap::Strings, value::Value, vm::RunTimeErrKind};
 
-#[derive(Debug, Eq, Hash)]
-pub (crate) enum Object {
+/// A value that lives behind a reference, like a string.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum Object {
     String(Rc<str>),
+    Native(Native),
+    Function(Function),
 }
 
-impl Object {
-    pub (crate) fn to_string(&self) -> String {
-        match self {
-            Object::String(string) => string.to_string(),
-        }
+/// A function implemented in Rust that Lox code can call.
+#[derive(Clone, Copy)]
+pub struct Native {
+    pub (crate) name: &'static str,
+    /// How many arguments it must be called with.
+    pub (crate) arity: u8,
+    /// How many more arguments it may be called with. It picks defaults for any left out.
+    pub (crate) optional: u8,
+    pub (crate) function: NativeFn,
+}
+
+/// Natives are called with their arguments and somewhere to make the strings they return.
+pub (crate) type NativeFn = fn(&[Value], &mut Strings) -> Result<Value, RunTimeErrKind>;
+
+/// Each native is registered under its own name, so the name identifies it.
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
     }
 }
 
-impl Clone for Object {
-    fn clone(&self) -> Self {
-        match self {
-            Object::String(string) => Object::String(string.clone()),
-        }
+impl Eq for Native {}
+
+impl Hash for Native {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
     }
 }
 
-impl PartialEq for Object {
-    fn eq(&self, other: &Self) -> bool {
+impl Debug for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
+}
+
+/// Every object kind needs a rendering here, so `print` can never fail on an object.
+impl Display for Object {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Object::String(a) => {
-                match other {
-                    Object::String(b) => *a == *b,
-                }
-            },
+            Object::String(string) => write!(f, "{}", string),
+            Object::Native(native) => write!(f, "<native fn {}>", native.name),
+            Object::Function(function) => write!(f, "<fn {}>", function.name),
         }
     }
+}
 
-    fn ne(&self, other: &Self) -> bool {
-        !self.eq(other)
+impl Clone for Object {
+    fn clone(&self) -> Self {
+        match self {
+            Object::String(string) => Object::String(string.clone()),
+            Object::Native(native) => Object::Native(*native),
+            Object::Function(function) => Object::Function(function.clone()),
+        }
     }
 }
 
-#[derive(Debug)]
-pub (crate) struct Function {
+/// A function declared in Lox. Its code reads constants from the pool of the program it was compiled in.
+#[derive(Clone, Debug)]
+pub struct Function {
     pub (crate) arity: u8,
+    #[allow(dead_code)] // Scaffolding for closures, which the compiler doesn't create yet.
     pub (crate) upvalue_count: u8,
-    pub (crate) chunks: Vec<Chunk>,
+    /// Shared with every call frame running the function, so calls don't copy the code.
+    pub (crate) chunks: Rc<[Chunk]>,
     pub (crate) name: String,
 }
 
@@ -51,8 +81,23 @@ impl Function {
         Function { 
             arity: 0, 
             upvalue_count: 0, 
-            chunks: Vec::new(), 
+            chunks: Rc::new([]), 
             name: "<no name>".to_string(),
         }
     }
+}
+
+/// Functions are equal only to themselves, so two declarations with the same body still differ.
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.chunks, &other.chunks)
+    }
+}
+
+impl Eq for Function {}
+
+impl Hash for Function {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::ptr::hash(Rc::as_ptr(&self.chunks), state);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,111 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use crate::chunk::OpCode;
+
+/// Tallies how many times each opcode is executed.
+#[derive(Default)]
+pub (crate) struct OpCodeProfile {
+    counts: HashMap<OpCode, usize>,
+}
+
+impl OpCodeProfile {
+    pub (crate) fn record(&mut self, op: OpCode) {
+        *self.counts.entry(op).or_insert(0) += 1;
+    }
+
+    /// The executed opcodes and their counts, most frequent first. 
+    /// Ties are broken by opcode value so the order is deterministic.
+    pub (crate) fn sorted(&self) -> Vec<(OpCode, usize)> {
+        let mut counts: Vec<(OpCode, usize)> = self.counts
+            .iter()
+            .map(|(op, count)| (*op, *count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then((a.0 as u8).cmp(&(b.0 as u8))));
+        counts
+    }
+
+    pub (crate) fn report(&self) -> String {
+        let mut report = String::from("== opcode profile ==\n");
+        for (op, count) in self.sorted() {
+            report.push_str(&format!("{:<16}{}\n", format!("{:?}", op), count));
+        }
+        report
+    }
+}
+
+/// Accumulates the wall-clock time spent executing each source line. Time is
+/// attributed to a line from its first instruction until the next instruction starts.
+#[derive(Default)]
+pub (crate) struct LineTimings {
+    totals: HashMap<usize, Duration>,
+    current: Option<(usize, Instant)>,
+}
+
+impl LineTimings {
+    /// Notes that an instruction from the given line started at `now`.
+    pub (crate) fn record(&mut self, line: usize, now: Instant) {
+        self.finish(now);
+        self.current = Some((line, now));
+    }
+
+    /// Attributes the time since the last instruction started to its line.
+    pub (crate) fn finish(&mut self, now: Instant) {
+        if let Some((line, started)) = self.current.take() {
+            *self.totals.entry(line).or_default() += now.saturating_duration_since(started);
+        }
+    }
+
+    /// The lines and the time spent on them, slowest first.
+    /// Ties are broken by line so the order is deterministic.
+    pub (crate) fn sorted(&self) -> Vec<(usize, Duration)> {
+        let mut totals: Vec<(usize, Duration)> = self.totals
+            .iter()
+            .map(|(line, total)| (*line, *total))
+            .collect();
+        totals.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        totals
+    }
+
+    pub (crate) fn report(&self) -> String {
+        let mut report = String::from("== line timings ==\n");
+        for (line, total) in self.sorted() {
+            report.push_str(&format!("line {:<8}{:?}\n", line, total));
+        }
+        report
+    }
+}
+
+#[test]
+fn line_timings_sort_by_total_time() {
+    let start = Instant::now();
+    let at = |millis: u64| start + Duration::from_millis(millis);
+
+    // Line 1 runs once, then line 2 runs three times like the body of a loop.
+    let mut timings = LineTimings::default();
+    timings.record(1, at(0));
+    for i in 0..3 {
+        timings.record(2, at(2 + i * 5));
+    }
+    timings.finish(at(17));
+
+    assert_eq!(vec![
+        (2, Duration::from_millis(15)),
+        (1, Duration::from_millis(2)),
+    ], timings.sorted());
+    assert_eq!("== line timings ==\nline 2       15ms\nline 1       2ms\n", timings.report());
+}
+
+#[test]
+fn profile_sorts_by_count() {
+    let mut profile = OpCodeProfile::default();
+    for op in [OpCode::Constant, OpCode::Add, OpCode::Constant, OpCode::Pop, OpCode::Constant] {
+        profile.record(op);
+    }
+
+    assert_eq!(vec![
+        (OpCode::Constant, 3),
+        (OpCode::Pop, 1),
+        (OpCode::Add, 1),
+    ], profile.sorted());
+    assert_eq!("== opcode profile ==\nConstant        3\nPop             1\nAdd             1\n", profile.report());
+}
@@ -0,0 +1,166 @@
+use std::rc::Rc;
+
+use tree_iterators_rs::prelude::BinaryTreeNode;
+
+use crate::{
+    compiler::{ExpressionBranch, ExpressionLeaf, ExpressionTreeNode, Operator},
+    object::Object,
+    value::Value,
+    vm::RunTimeErrKind,
+};
+
+/// Evaluates an expression tree directly, without compiling it. This is a reference for
+/// checking the VM against, so it follows the VM's semantics rather than the cleanest ones,
+/// and it uses the VM's default options.
+pub (crate) fn evaluate(expr: &BinaryTreeNode<ExpressionTreeNode>) -> Result<Value, RunTimeErrKind> {
+    match &expr.value {
+        ExpressionTreeNode::Leaf(leaf, _) => {
+            match leaf {
+                ExpressionLeaf::Value(value) => Ok(value.clone()),
+                ExpressionLeaf::Variable(_) | ExpressionLeaf::Native(_) | ExpressionLeaf::Error(_) => {
+                    panic!("The reference evaluator only handles constant expressions, but found {:?}", leaf)
+                }
+            }
+        }
+        ExpressionTreeNode::Branch(branch @ (ExpressionBranch::Call { .. } | ExpressionBranch::Argument), _) => {
+            panic!("The reference evaluator only handles constant expressions, but found {:?}", branch)
+        }
+        ExpressionTreeNode::Branch(ExpressionBranch::Operator(op), _) => {
+            // Operands are evaluated left to right, so errors surface in the same order as in the VM.
+            let left = match &expr.left {
+                None => None,
+                Some(left) => Some(evaluate(left)?),
+            };
+            let right = evaluate(expr.right.as_ref().expect("every operator to have a right operand"))?;
+            match left {
+                None => unary(op, right),
+                Some(left) => binary(op, left, right),
+            }
+        }
+    }
+}
+
+fn unary(op: &Operator, operand: Value) -> Result<Value, RunTimeErrKind> {
+    match (op, operand) {
+        (Operator::SignFlip, Value::Number(num)) => Ok(Value::Number(-num)),
+        (Operator::SignFlip, _) => Err(RunTimeErrKind::ArithmeticOnNonNumber),
+        (Operator::Not, Value::Nil) => Ok(Value::Boolean(true)),
+        (Operator::Not, Value::Boolean(bool)) => Ok(Value::Boolean(!bool)),
+        (Operator::Not, Value::Number(_)) => Err(RunTimeErrKind::BooleanOperationOnNumber),
+        (Operator::Not, Value::Object(_)) => Err(RunTimeErrKind::BooleanOperationOnObject),
+        (op, _) => panic!("{:?} is not a unary operator", op),
+    }
+}
+
+fn binary(op: &Operator, left: Value, right: Value) -> Result<Value, RunTimeErrKind> {
+    match op {
+        Operator::Equal => Ok(Value::Boolean(left == right)),
+        Operator::NotEqual => Ok(Value::Boolean(left != right)),
+        Operator::Add => {
+            match (left, right) {
+                (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+                (Value::Object(a), Value::Object(b)) => Ok(string(format!("{}{}", a, b))),
+                // The VM's default coercion policy doesn't mix strings with numbers.
+                (Value::Object(_), Value::Number(_)) | (Value::Number(_), Value::Object(_)) => Err(RunTimeErrKind::MixedConcatenation),
+                _ => Err(RunTimeErrKind::ArithmeticOnNonNumber),
+            }
+        }
+        Operator::Subtract | Operator::Multiply | Operator::Divide | Operator::Modulo => {
+            let (Value::Number(a), Value::Number(b)) = (left, right) else {
+                return Err(RunTimeErrKind::ArithmeticOnNonNumber);
+            };
+            match op {
+                Operator::Subtract => Ok(Value::Number(a - b)),
+                Operator::Multiply => Ok(Value::Number(a * b)),
+                _ if b == 0.0 => Err(RunTimeErrKind::DivisionByZero),
+                Operator::Modulo => Ok(Value::Number(a % b)),
+                _ => Ok(Value::Number(a / b)),
+            }
+        }
+        Operator::Greater | Operator::GreaterEqual | Operator::Less | Operator::LessEqual => {
+            let ordering = match (&left, &right) {
+                (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+                (Value::Object(a), Value::Object(b)) => Some(a.to_string().cmp(&b.to_string())),
+                _ => return Err(RunTimeErrKind::ComparisonOnNonNumber),
+            };
+            let Some(ordering) = ordering else {
+                return Ok(Value::Boolean(false));
+            };
+            match op {
+                Operator::Greater => Ok(Value::Boolean(ordering.is_gt())),
+                Operator::GreaterEqual => Ok(Value::Boolean(ordering.is_ge())),
+                Operator::Less => Ok(Value::Boolean(ordering.is_lt())),
+                _ => Ok(Value::Boolean(ordering.is_le())),
+            }
+        }
+        Operator::Assignment | Operator::And | Operator::Or | Operator::Coalesce | Operator::Not | Operator::SignFlip => {
+            panic!("The reference evaluator doesn't handle {:?} as a binary operator", op)
+        }
+    }
+}
+
+fn string(string: String) -> Value {
+    Value::Object(Rc::new(Object::String(string.into())))
+}
+
+/// A small xorshift generator, so the differential test is random but repeatable.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, max: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % max
+    }
+}
+
+/// Writes a random expression using every operator the compiler can emit. Every
+/// operation is parenthesized, so precedence never decides what is being tested.
+fn random_expression(rng: &mut Rng, depth: u32) -> String {
+    const LITERALS: &[&str] = &["0", "1", "2.5", "-3", "10", "true", "false", "nil", "\"a\"", "\"bc\"", "\"\""];
+    const UNARY: &[&str] = &["-", "!"];
+    const BINARY: &[&str] = &["+", "-", "*", "/", "%", "==", "!=", "<", "<=", ">", ">="];
+
+    if depth == 0 || rng.below(4) == 0 {
+        return LITERALS[rng.below(LITERALS.len() as u64) as usize].to_string();
+    }
+
+    if rng.below(5) == 0 {
+        let op = UNARY[rng.below(UNARY.len() as u64) as usize];
+        return format!("({}{})", op, random_expression(rng, depth - 1));
+    }
+
+    let op = BINARY[rng.below(BINARY.len() as u64) as usize];
+    let left = random_expression(rng, depth - 1);
+    let right = random_expression(rng, depth - 1);
+    format!("({} {} {})", left, op, right)
+}
+
+#[test]
+fn vm_matches_the_reference_evaluator() {
+    use crate::{compiler::Statement, repl::Repl, vm::{LoxError, VMErr, VMOptions}};
+
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for _ in 0..2000 {
+        let source = format!("{};", random_expression(&mut rng, 4));
+
+        let statements = crate::compiler::parse(&source, Default::default()).expect("generated expressions to parse");
+        let [Statement::Expression { expr, .. }] = &statements[..] else {
+            panic!("Expected one expression statement for {}", source);
+        };
+        let expected = evaluate(expr).map(|value| value.to_string()).map_err(|kind| kind.as_str());
+
+        // A fresh VM for each expression, so the constant pool never fills up.
+        let actual = match Repl::new(VMOptions::default()).eval_line(&source) {
+            Ok(value) => Ok(value.expect("the expression's value to be kept").to_string()),
+            Err(errs) => {
+                match &errs[..] {
+                    [LoxError::Run(VMErr::RuntimeErr(err))] => Err(err.kind().as_str()),
+                    _ => panic!("Expected {} to run or raise one runtime error, but found {:?}", source, errs),
+                }
+            }
+        };
+        assert_eq!(expected, actual, "{}", source);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::{
+    fixed_vec::FixedVec,
+    value::Value,
+    vm::{LoxError, VMOptions, VM},
+};
+
+/// The REPL without stdin and stdout. Each line runs against the same VM,
+/// so globals defined by one line are available to the lines after it.
+pub (crate) struct Repl {
+    vm: VM,
+}
+
+impl Repl {
+    pub (crate) fn new(options: VMOptions) -> Self {
+        Self {
+            vm: VM::new(Vec::new(), FixedVec::new()).with_options(options),
+        }
+    }
+
+    /// Runs one line. If the line ends with an expression, its value is returned.
+    pub (crate) fn eval_line(&mut self, line: &str) -> Result<Option<Value>, Vec<LoxError>> {
+        self.vm.evaluate(line)
+    }
+
+    /// The current value of a global variable defined by an earlier line.
+    #[cfg(test)]
+    pub (crate) fn global(&self, name: &str) -> Option<&Value> {
+        self.vm.global(name)
+    }
+}
+
+#[test]
+fn eval_line_returns_expression_values() {
+    let mut repl = Repl::new(VMOptions::default());
+
+    assert!(repl.eval_line("var a = 1, b = \"two\";").expect("declaration to run").is_none());
+    assert!(repl.eval_line("print 3;").expect("print to run").is_none());
+
+    let value = repl.eval_line("1 + 2;").expect("expression to run");
+    assert_eq!(Some(3.0), value.as_ref().and_then(Value::as_number));
+
+    let value = repl.eval_line("print 4; \"a\" + \"b\";").expect("statements to run");
+    assert_eq!("ab", value.expect("final expression to have a value").to_string());
+
+    assert_eq!(Some(1.0), repl.global("a").and_then(Value::as_number));
+    assert_eq!("two", repl.global("b").expect("b to be retained").to_string());
+}
+
+#[test]
+fn eval_line_recovers_from_errors() {
+    let mut repl = Repl::new(VMOptions::default());
+    assert!(repl.eval_line("var a = 1;").is_ok());
+
+    let errs = repl.eval_line("var = 2;").expect_err("missing name to be an error");
+    assert!(matches!(errs[0], LoxError::Compile(_)));
+
+    let errs = repl.eval_line("-\"text\";").expect_err("negating a string to be an error");
+    assert!(matches!(errs[0], LoxError::Run(_)));
+
+    let value = repl.eval_line("true;").expect("the repl to still work");
+    assert_eq!(Some(true), value.as_ref().and_then(Value::as_bool));
+    assert_eq!(Some(1.0), repl.global("a").and_then(Value::as_number));
+}
+
+#[test]
+fn functions_can_be_called_from_later_lines() {
+    let mut repl = Repl::new(VMOptions::default());
+    assert!(repl.eval_line("var unused = \"shifts the constants\";").is_ok());
+    assert!(repl.eval_line("fun greet(name) { return \"hi \" + name; }").is_ok());
+
+    // An error inside a call leaves its frame behind, which the next line must not run in.
+    let errs = repl.eval_line("greet(1);").expect_err("adding a string and a number to be an error");
+    assert!(matches!(errs[0], LoxError::Run(_)));
+
+    let value = repl.eval_line("greet(\"lox\");").expect("the call to run");
+    assert_eq!("hi lox", value.expect("the call to have a value").to_string());
+}
@@ -1,12 +1,93 @@
 use std::{
     str::CharIndices, 
-    iter::Peekable, fmt::Display, u8, ops::Range, error::Error
+    iter::Peekable, fmt::Display, ops::Range, error::Error
 };
 
+/// Lists every token in the source with its span, one per line.
+/// Lexing errors are listed in stream order alongside the tokens.
+pub (crate) fn dump_tokens(source: &str) -> String {
+    let mut dump = String::new();
+    for token_result in Tokenizer::new(source) {
+        match token_result {
+            Ok(token) => {
+                dump.push_str(&format!(
+                    "{:<14}[{}] - [{}]\n", 
+                    format!("{:?}", token.kind()), 
+                    token.get_start(), 
+                    token.get_end()
+                ));
+            }
+            Err(err) => {
+                dump.push_str(&format!("{}\n", err));
+            }
+        }
+    }
+    dump
+}
+
+/// Decodes the escape sequences in the contents of a string literal: \n, \t, \r, \0,
+/// \\, \", \xHH (ASCII only), and \u{H} through \u{HHHHHH}. A backslash that doesn't
+/// start one of these is kept as is. An invalid \x or \u escape gives the byte offset
+/// of its backslash.
+pub (crate) fn unescape(raw: &str) -> Result<String, usize> {
+    let mut result = String::with_capacity(raw.len());
+    let mut chars = raw.char_indices().peekable();
+    while let Some((index, ch)) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+
+        let (escaped, len) = match chars.peek().map(|(_, ch)| *ch) {
+            Some('n') => ('\n', 2),
+            Some('t') => ('\t', 2),
+            Some('r') => ('\r', 2),
+            Some('0') => ('\0', 2),
+            Some('\\') => ('\\', 2),
+            Some('"') => ('"', 2),
+            Some('x') => {
+                let digits = raw.get(index + 2..index + 4).ok_or(index)?;
+                if !digits.chars().all(|ch| ch.is_ascii_hexdigit()) {
+                    return Err(index);
+                }
+                let byte = u8::from_str_radix(digits, 16).map_err(|_| index)?;
+                if !byte.is_ascii() {
+                    return Err(index);
+                }
+                (byte as char, 4)
+            }
+            Some('u') => {
+                let rest = &raw[index + 2..];
+                if !rest.starts_with('{') {
+                    return Err(index);
+                }
+                let close = rest.find('}').ok_or(index)?;
+                let digits = &rest[1..close];
+                if digits.is_empty() || digits.len() > 6 || !digits.chars().all(|ch| ch.is_ascii_hexdigit()) {
+                    return Err(index);
+                }
+                let code_point = u32::from_str_radix(digits, 16).map_err(|_| index)?;
+                (char::from_u32(code_point).ok_or(index)?, 2 + close + 1)
+            }
+            _ => {
+                result.push('\\');
+                continue;
+            }
+        };
+
+        result.push(escaped);
+        while chars.next_if(|(next, _)| *next < index + len).is_some() {}
+    }
+
+    Ok(result)
+}
+
 pub (crate) struct Tokenizer<'i> {
     source: &'i str,
     chars: Peekable<CharIndices<'i>>,
     current_position: Position,
+    /// Where the source starts in the text its positions are reported against.
+    byte_offset: usize,
 }
 
 impl<'i> Tokenizer<'i> {
@@ -14,10 +95,30 @@ impl<'i> Tokenizer<'i> {
         Self {
             source,
             chars: source.char_indices().peekable(),
-            current_position: Position::default()
+            current_position: Position::default(),
+            byte_offset: 0,
+        }
+    }
+
+    /// Lexes a fragment of a larger text, such as the contents of a string literal,
+    /// reporting positions in that larger text. `start` is where the fragment's first
+    /// character is.
+    #[allow(dead_code)] // Nothing re-lexes a fragment yet outside of the tests.
+    pub (crate) fn new_at(fragment: &'i str, start: Position) -> Self {
+        Self {
+            source: fragment,
+            chars: fragment.char_indices().peekable(),
+            // The position just before the first character, like Position::default() is for new().
+            current_position: Position { line: start.line, col: start.col.saturating_sub(1), byte: start.byte },
+            byte_offset: start.byte,
         }
     }
 
+    /// The source between two byte positions, which include the offset.
+    fn slice(&self, start: usize, end: usize) -> &'i str {
+        &self.source[start - self.byte_offset..end - self.byte_offset]
+    }
+
     fn match_number(&mut self, start_pos: Position) -> Result<LoxToken, LoxParseErr> {
         self.match_char_while(|(_, ch)| ch.is_ascii_digit());
         if self.match_char('.') {
@@ -27,7 +128,7 @@ impl<'i> Tokenizer<'i> {
             self.match_char_while(|(_, ch)| ch.is_ascii_digit());
         }
 
-        return Ok(LoxToken { kind: TokenKind::Number, span: Span { start: start_pos, end: self.peek_position() } })
+        Ok(LoxToken { kind: TokenKind::Number, span: Span { start: start_pos, end: self.peek_position() } })
     }
 
     /// ASSUMES that the beginning quote was already consumed.
@@ -37,18 +138,12 @@ impl<'i> Tokenizer<'i> {
         self.match_char_while(|(_, ch)| {
             match ch {
                 '\\' => {
-                    previous_was_backslash = true;
+                    // An escaped backslash can't escape the next character.
+                    previous_was_backslash = !previous_was_backslash;
                     true
                 }
-                '"' => {
-                    if !previous_was_backslash {
-                        // break the loop
-                        false
-                    } else {
-                        previous_was_backslash = false;
-                        true    
-                    }
-                }
+                // break the loop
+                '"' if !previous_was_backslash => false,
                 _ => {
                     previous_was_backslash = false;
                     true
@@ -60,33 +155,33 @@ impl<'i> Tokenizer<'i> {
             return Err(LoxParseErr { kind: ErrKind::UnexpectedEOF, span: Span { start: start_pos, end: self.peek_position() } });
         }
 
-        return Ok(LoxToken { kind: TokenKind::String, span: Span { start: start_pos, end: self.peek_position() } });
+        let end_pos = self.peek_position();
+        let contents_start = start_pos.byte + 1;
+        let contents = self.slice(contents_start, end_pos.byte - 1);
+        if let Err(offset) = unescape(contents) {
+            let escape_start = start_pos.advance_over(self.slice(start_pos.byte, contents_start + offset));
+            return Err(LoxParseErr { kind: ErrKind::InvalidUnicodeEscape, span: Span { start: escape_start, end: end_pos } });
+        }
+
+        Ok(LoxToken { kind: TokenKind::String, span: Span { start: start_pos, end: end_pos } })
     }
 
     fn match_identifier(&mut self, start_pos: Position) -> LoxToken {
         let start = self.current_position;
         self.match_char_if(|(_, ch)| {
-            match ch {
-                'a'..='z'
-                | 'A'..='Z'
-                | '_' => true,
-                _ => false,
-            }
+            matches!(ch, 'a'..='z' | 'A'..='Z' | '_')
         });
         
         self.match_char_while(|(_, ch)| {
-            match ch {
-                'a'..='z'
-                | 'A'..='Z'
-                | '0'..='9'
-                | '_' => true,
-                _ => false,
-            }
+            matches!(ch, 'a'..='z' | 'A'..='Z' | '0'..='9' | '_')
         });
 
-        let ident = &self.source[start.byte..self.peek_position().byte];
+        let end = self.peek_position().byte;
+        let ident = self.slice(start.byte, end);
         let kind = match ident {
             "and" => TokenKind::And,
+            "break" => TokenKind::Break,
+            "catch" => TokenKind::Catch,
             "class" => TokenKind::Class,
             "else" => TokenKind::Else,
             "false" => TokenKind::False,
@@ -100,6 +195,7 @@ impl<'i> Tokenizer<'i> {
             "super" => TokenKind::Super,
             "this" => TokenKind::This,
             "true" => TokenKind::True,
+            "try" => TokenKind::Try,
             "var" => TokenKind::Var,
             "while" => TokenKind::While,
             _ => TokenKind::Identifier,
@@ -126,16 +222,12 @@ impl<'i> Tokenizer<'i> {
 
     fn match_char_if<P: FnMut(&(usize, char)) -> bool>(&mut self, mut predicate: P) -> bool {
         match self.chars.peek() {
-            None => false,
-            Some(ch_index) => {
-                if predicate(ch_index) {
-                    // consume it
-                    self.next_char();
-                    true
-                } else {
-                    false
-                }
+            Some(ch_index) if predicate(ch_index) => {
+                // consume it
+                self.next_char();
+                true
             },
+            _ => false,
         }
     }
 
@@ -147,7 +239,7 @@ impl<'i> Tokenizer<'i> {
                     // don't increment the line or column, since reporting a position that is off the page is confusing to users.
                     line: self.current_position.line,
                     col: self.current_position.col,
-                    byte: self.source.as_bytes().len(),
+                    byte: self.source.len() + self.byte_offset,
                 };
                 None
             }
@@ -157,7 +249,7 @@ impl<'i> Tokenizer<'i> {
                         self.current_position = Position {
                             line: self.current_position.line + 1,
                             col: 0,
-                            byte: ch_index.0
+                            byte: ch_index.0 + self.byte_offset
                         };
                         Some(ch_index)
                     }
@@ -165,7 +257,7 @@ impl<'i> Tokenizer<'i> {
                         self.current_position = Position {
                             line: self.current_position.line,
                             col: self.current_position.col + 1,
-                            byte: ch_index.0
+                            byte: ch_index.0 + self.byte_offset
                         };
                         Some(ch_index)
                     }
@@ -180,7 +272,7 @@ impl<'i> Tokenizer<'i> {
                 line: self.current_position.line,
                 // don't increment the line or column, since reporting a position that is off the page is confusing to users.
                 col: self.current_position.col, 
-                byte: self.source.as_bytes().len(),
+                byte: self.source.len() + self.byte_offset,
             },
             Some(ch_index) => {
                 match ch_index.1 {
@@ -188,14 +280,14 @@ impl<'i> Tokenizer<'i> {
                         Position {
                             line: self.current_position.line + 1,
                             col: 0,
-                            byte: ch_index.0
+                            byte: ch_index.0 + self.byte_offset
                         }
                     }
                     _ => {
                         Position {
                             line: self.current_position.line,
                             col: self.current_position.col + 1,
-                            byte: ch_index.0
+                            byte: ch_index.0 + self.byte_offset
                         }
                     }
                 }                
@@ -247,6 +339,7 @@ impl<'i> Iterator for Tokenizer<'i> {
                             }
                         }
                         '*' =>  return Some(Ok(LoxToken { kind: TokenKind::Star,        span: Span { start: token_start, end: self.peek_position() } })),
+                        '%' =>  return Some(Ok(LoxToken { kind: TokenKind::Percent,     span: Span { start: token_start, end: self.peek_position() } })),
                         '!' => {
                             if self.match_char('=') {
                                 return Some(Ok(LoxToken { kind: TokenKind::BangEqual,   span: Span { start: token_start, end: self.peek_position() } }))
@@ -268,6 +361,13 @@ impl<'i> Iterator for Tokenizer<'i> {
                                 return Some(Ok(LoxToken { kind: TokenKind::Greater,        span: Span { start: token_start, end: self.peek_position() } }))
                             }
                         }
+                        '?' => {
+                            if self.match_char('?') {
+                                return Some(Ok(LoxToken { kind: TokenKind::QuestionQuestion, span: Span { start: token_start, end: self.peek_position() } }))
+                            } else {
+                                return Some(Err(LoxParseErr { kind: ErrKind::InvalidChar, span: Span { start: token_start, end: self.peek_position() } }))
+                            }
+                        }
                         '<' => {
                             if self.match_char('=') {
                                 return Some(Ok(LoxToken { kind: TokenKind::LessEqual,   span: Span { start: token_start, end: self.peek_position() } }))
@@ -305,27 +405,52 @@ pub struct LoxToken {
 }
 
 impl LoxToken {
-    pub (crate) fn get_start(&self) -> Position {
+    pub fn get_start(&self) -> Position {
         self.span.start
     }
 
-    pub (crate) fn kind(&self) -> TokenKind {
+    pub fn get_end(&self) -> Position {
+        self.span.end
+    }
+
+    pub fn kind(&self) -> TokenKind {
         self.kind
     }
 
-    pub (crate) fn range(&self) -> Range<usize> {
+    /// The token's bytes in the source, so `&source[token.range()]` is how it was written.
+    pub fn range(&self) -> Range<usize> {
         self.span.start.byte..self.span.end.byte
     }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
 }
 
+/// The source between two positions. The end is exclusive.
 #[derive(Clone, Copy, Debug, Default)]
-struct Span {
+pub struct Span {
     start: Position,
     end: Position,
 }
 
+impl Span {
+    pub (crate) fn new(start: Position, end: Position) -> Self {
+        Self { start, end }
+    }
+
+    pub fn start(&self) -> Position {
+        self.start
+    }
+
+    pub fn end(&self) -> Position {
+        self.end
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub (crate) enum TokenKind {
+pub enum TokenKind {
     LeftParen = 0, // (
     RightParen = 1, // )
     LeftBrace = 2, // {
@@ -337,6 +462,7 @@ pub (crate) enum TokenKind {
     Semicolon = 8, // ;
     Slash = 9, // /
     Star = 10, // *
+    Percent = 40, // %
     Bang = 11, // !
     BangEqual = 12, // !=
     Equal = 36, // =
@@ -345,6 +471,7 @@ pub (crate) enum TokenKind {
     GreaterEqual = 14, // >=
     Less = 15, // <
     LessEqual = 16, // <=
+    QuestionQuestion = 41, // ??
     Identifier = 17,
     String = 18,
     Number = 19,
@@ -364,6 +491,9 @@ pub (crate) enum TokenKind {
     True = 33,
     Var = 34,
     While = 35,
+    Break = 39,
+    Try = 42,
+    Catch = 43,
     /// This value is never yielded by the tokenizer, but is useful for error reporting.
     EOF = 38,
 }
@@ -374,6 +504,60 @@ impl TokenKind {
     }
 }
 
+/// How the token is named in error messages: punctuation and keywords
+/// are quoted as written, and everything else is described.
+impl Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let spelling = match self {
+            TokenKind::LeftParen => "'('",
+            TokenKind::RightParen => "')'",
+            TokenKind::LeftBrace => "'{'",
+            TokenKind::RightBrace => "'}'",
+            TokenKind::Comma => "','",
+            TokenKind::Dot => "'.'",
+            TokenKind::Minus => "'-'",
+            TokenKind::Plus => "'+'",
+            TokenKind::Semicolon => "';'",
+            TokenKind::Slash => "'/'",
+            TokenKind::Star => "'*'",
+            TokenKind::Percent => "'%'",
+            TokenKind::Bang => "'!'",
+            TokenKind::BangEqual => "'!='",
+            TokenKind::Equal => "'='",
+            TokenKind::EqualEqual => "'=='",
+            TokenKind::Greater => "'>'",
+            TokenKind::GreaterEqual => "'>='",
+            TokenKind::Less => "'<'",
+            TokenKind::LessEqual => "'<='",
+            TokenKind::QuestionQuestion => "'??'",
+            TokenKind::Identifier => "identifier",
+            TokenKind::String => "string",
+            TokenKind::Number => "number",
+            TokenKind::And => "'and'",
+            TokenKind::Class => "'class'",
+            TokenKind::Else => "'else'",
+            TokenKind::False => "'false'",
+            TokenKind::Fun => "'fun'",
+            TokenKind::For => "'for'",
+            TokenKind::If => "'if'",
+            TokenKind::Nil => "'nil'",
+            TokenKind::Or => "'or'",
+            TokenKind::Print => "'print'",
+            TokenKind::Return => "'return'",
+            TokenKind::Super => "'super'",
+            TokenKind::This => "'this'",
+            TokenKind::True => "'true'",
+            TokenKind::Var => "'var'",
+            TokenKind::While => "'while'",
+            TokenKind::Break => "'break'",
+            TokenKind::Try => "'try'",
+            TokenKind::Catch => "'catch'",
+            TokenKind::EOF => "end of file",
+        };
+        write!(f, "{}", spelling)
+    }
+}
+
 impl TryFrom<u8> for TokenKind {
     type Error = u8;
 
@@ -415,22 +599,52 @@ impl TryFrom<u8> for TokenKind {
             33 => Ok(TokenKind::True),
             34 => Ok(TokenKind::Var),
             35 => Ok(TokenKind::While),
+            39 => Ok(TokenKind::Break),
+            40 => Ok(TokenKind::Percent),
+            41 => Ok(TokenKind::QuestionQuestion),
+            42 => Ok(TokenKind::Try),
+            43 => Ok(TokenKind::Catch),
             other => Err(other)
         }
     }
 }
 
+/// A place in the source. Lines count from 0 and columns from 1.
 #[derive(Clone, Copy, Debug, Default)]
-pub (crate) struct Position {
+pub struct Position {
     line: usize,
     col: usize,
     byte: usize
 }
 
 impl Position {
-    pub (crate) fn line(&self) -> usize {
+    pub fn line(&self) -> usize {
         self.line
     }
+
+    pub fn col(&self) -> usize {
+        self.col
+    }
+
+    /// The offset into the source in bytes.
+    pub fn byte(&self) -> usize {
+        self.byte
+    }
+
+    /// The position reached by moving from this one across the given text.
+    fn advance_over(&self, text: &str) -> Position {
+        let mut position = *self;
+        for ch in text.chars() {
+            if ch == '\n' {
+                position.line += 1;
+                position.col = 0;
+            } else {
+                position.col += 1;
+            }
+            position.byte += ch.len_utf8();
+        }
+        position
+    }
 }
 
 impl Display for Position {
@@ -439,16 +653,26 @@ impl Display for Position {
     }
 }
 
+/// Source that couldn't be split into tokens, like an unterminated string.
 #[derive(Clone, Copy, Debug)]
-pub (crate) struct LoxParseErr {
+pub struct LoxParseErr {
     kind: ErrKind,
     span: Span
 }
 
 impl LoxParseErr {
-    pub (crate) fn get_start(&self) -> Position {
+    pub fn get_start(&self) -> Position {
         self.span.start
     }
+
+    pub fn get_end(&self) -> Position {
+        self.span.end
+    }
+
+    /// Whether the source ran out before the token was finished, like an unterminated string.
+    pub (crate) fn is_unterminated(&self) -> bool {
+        matches!(self.kind, ErrKind::UnexpectedEOF)
+    }
 }
 
 impl Display for LoxParseErr {
@@ -464,4 +688,83 @@ enum ErrKind {
     /// This error signifies a number that ended in a period. This is invalid syntax.
     TrailingPeriod,
     UnexpectedEOF,
-}
\ No newline at end of file
+    /// A \x or \u escape in a string had bad digits or named a character that doesn't exist.
+    InvalidUnicodeEscape,
+}
+
+#[test]
+fn dump_tokens_lists_kinds_and_spans() {
+    assert_eq!(
+        "Print         [line: 0, column: 1] - [line: 0, column: 6]\n\
+         String        [line: 0, column: 7] - [line: 0, column: 11]\n\
+         Plus          [line: 0, column: 12] - [line: 0, column: 13]\n\
+         Number        [line: 0, column: 14] - [line: 0, column: 17]\n\
+         Semicolon     [line: 0, column: 17] - [line: 0, column: 17]\n",
+        dump_tokens("print \"ab\" + 1.5;")
+    );
+}
+
+#[test]
+fn dump_tokens_lists_errors_in_order() {
+    assert_eq!(
+        "Number        [line: 0, column: 1] - [line: 0, column: 2]\n\
+         [line: 0, column: 3] InvalidChar\n\
+         Semicolon     [line: 0, column: 4] - [line: 0, column: 4]\n",
+        dump_tokens("1 @;")
+    );
+}
+
+#[test]
+fn string_escapes() {
+    assert_eq!(Ok("A".to_string()), unescape("\\x41"));
+    assert_eq!(Ok("\u{1F600}!".to_string()), unescape("\\u{1F600}!"));
+    assert_eq!(Ok("a\n\t\"\\b".to_string()), unescape("a\\n\\t\\\"\\\\b"));
+    assert_eq!(Ok("C:\\dir".to_string()), unescape("C:\\dir"));
+}
+
+#[test]
+fn invalid_string_escapes() {
+    assert_eq!(Err(2), unescape("ab\\xZZ"));
+    assert_eq!(Err(0), unescape("\\xFF"));
+    assert_eq!(Err(0), unescape("\\u{110000}"));
+    assert_eq!(Err(0), unescape("\\u{D800}"));
+    assert_eq!(Err(0), unescape("\\u1F600"));
+
+    let err = Tokenizer::new("print \"ab\\u{FFFFFF}\";")
+        .find_map(|token| token.err())
+        .expect("invalid escape to be an error");
+    assert!(matches!(err.kind, ErrKind::InvalidUnicodeEscape));
+    assert_eq!(0, err.get_start().line());
+    assert_eq!(10, err.get_start().col());
+}
+
+#[test]
+fn fragments_report_positions_in_the_original_source() {
+    let source = "print 1;\nprint \"a + bc\";";
+    let literal = Tokenizer::new(source)
+        .map(|token| token.expect("source to lex"))
+        .find(|token| token.kind == TokenKind::String)
+        .expect("the source to contain a string");
+    let contents_start = literal.span.start.advance_over("\"");
+    let contents = &source[contents_start.byte..literal.span.end.byte - 1];
+
+    let tokens: Vec<LoxToken> = Tokenizer::new_at(contents, contents_start)
+        .map(|token| token.expect("fragment to lex"))
+        .collect();
+    let kinds: Vec<TokenKind> = tokens.iter().map(|token| token.kind).collect();
+    assert_eq!(vec![TokenKind::Identifier, TokenKind::Plus, TokenKind::Identifier], kinds);
+
+    // Positions and ranges point into the original source.
+    assert_eq!((1, 8), (tokens[0].span.start.line(), tokens[0].span.start.col()));
+    assert_eq!("bc", &source[tokens[2].range()]);
+    assert_eq!((1, 12), (tokens[2].span.start.line(), tokens[2].span.start.col()));
+
+    // Starting at the beginning is the same as lexing normally.
+    let from_start: Vec<LoxToken> = Tokenizer::new_at(source, Position { line: 0, col: 1, byte: 0 })
+        .map(|token| token.expect("source to lex"))
+        .collect();
+    let normal: Vec<LoxToken> = Tokenizer::new(source)
+        .map(|token| token.expect("source to lex"))
+        .collect();
+    assert_eq!(format!("{:?}", normal), format!("{:?}", from_start));
+}
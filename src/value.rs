@@ -1,9 +1,10 @@
-use std::rc::Rc;
+use std::{rc::Rc, cmp::Ordering, fmt::Display};
 
-use crate::object::Object;
+use crate::object::{Function, Object};
 
+/// A Lox value. Strings and functions are objects.
 #[derive(Debug, Default)]
-pub (crate) enum Value {
+pub enum Value {
     #[default]
     Nil,
     Boolean(bool),
@@ -11,24 +12,252 @@ pub (crate) enum Value {
     Object(Rc<Object>),
 }
 
+/// Controls how numbers are rendered when they are printed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub (crate) enum NumberFormat {
+    /// The shortest representation that round trips, e.g. 0.3333333333333333 or 5.
+    #[default]
+    General,
+    /// A fixed number of digits after the decimal point, e.g. 0.333 for Fixed(3).
+    Fixed(usize),
+    /// Scientific notation, e.g. 3.333333333333333e-1.
+    Scientific,
+}
+
+impl NumberFormat {
+    pub (crate) fn format(&self, num: f64) -> String {
+        match self {
+            NumberFormat::General => num.to_string(),
+            NumberFormat::Fixed(digits) => format!("{:.*}", digits, num),
+            NumberFormat::Scientific => format!("{:e}", num),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.format(NumberFormat::General))
+    }
+}
+
 impl Value {
-    pub (crate) fn to_string(&self) -> String {
+    /// Renders the value, using the given format for numbers.
+    pub (crate) fn format(&self, number_format: NumberFormat) -> String {
         match self {
             Value::Nil => "nil".to_string(),
             Value::Boolean(bool) => bool.to_string(),
-            Value::Number(num) => num.to_string(),
+            Value::Number(num) => number_format.format(*num),
             Value::Object(obj) => obj.to_string(),
         }
     }
+
+    /// nil and false are falsey. Every other value is truthy.
+    pub fn is_truthy(&self) -> bool {
+        !self.is_falsey()
+    }
+
+    /// nil and false are falsey. Every other value is truthy.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(num) => Some(*num),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Boolean(bool) => Some(*bool),
+            _ => None,
+        }
+    }
+
+    pub fn as_string(&self) -> Option<&str> {
+        match self {
+            Value::Object(obj) => {
+                match &**obj {
+                    Object::String(string) => Some(string),
+                    Object::Native(_) | Object::Function(_) => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// A total order over every value, used when sorting.
+    /// 
+    /// Values of different types are ordered nil < boolean < number < string < native < function.
+    /// Within a type, false < true, numbers are ordered numerically (with NaN
+    /// after every other number, as in f64::total_cmp), strings are ordered
+    /// lexicographically by code point, and natives and functions by name.
+    #[allow(dead_code)] // For sorting containers, which Lox doesn't have yet.
+    pub (crate) fn total_cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => Ordering::Equal,
+            (Value::Boolean(a), Value::Boolean(b)) => a.cmp(b),
+            (Value::Number(a), Value::Number(b)) => a.total_cmp(b),
+            (Value::Object(a), Value::Object(b)) => {
+                match (&**a, &**b) {
+                    (Object::String(a), Object::String(b)) => a.cmp(b),
+                    (Object::Native(a), Object::Native(b)) => a.name.cmp(b.name),
+                    (Object::Function(a), Object::Function(b)) => a.name.cmp(&b.name),
+                    (a, b) => object_rank(a).cmp(&object_rank(b)),
+                }
+            }
+            (a, b) => a.type_rank().cmp(&b.type_rank()),
+        }
+    }
+
+    #[allow(dead_code)] // Only total_cmp uses it.
+    fn type_rank(&self) -> u8 {
+        match self {
+            Value::Nil => 0,
+            Value::Boolean(_) => 1,
+            Value::Number(_) => 2,
+            Value::Object(_) => 3,
+        }
+    }
+
+    /// The function this value holds, if it is one.
+    pub (crate) fn as_function(&self) -> Option<&Function> {
+        match self {
+            Value::Object(obj) => {
+                match &**obj {
+                    Object::Function(function) => Some(function),
+                    Object::String(_) | Object::Native(_) => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[allow(dead_code)] // Only total_cmp uses it.
+fn object_rank(obj: &Object) -> u8 {
+    match obj {
+        Object::String(_) => 0,
+        Object::Native(_) => 1,
+        Object::Function(_) => 2,
+    }
 }
 
 impl Clone for Value {
     fn clone(&self) -> Self {
         match self {
             Self::Nil => Self::Nil,
-            Self::Boolean(arg0) => Self::Boolean(arg0.clone()),
-            Self::Number(arg0) => Self::Number(arg0.clone()),
+            Self::Boolean(arg0) => Self::Boolean(*arg0),
+            Self::Number(arg0) => Self::Number(*arg0),
             Self::Object(arg0) => Self::Object(arg0.clone()),
         }
     }
 }
+
+/// Lox equality. Values of different types are never equal, numbers follow f64
+/// (so NaN isn't equal to itself), and objects compare by contents.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Object(a), Value::Object(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+
+#[test]
+fn total_cmp_sorts_numbers() {
+    let mut values: Vec<Value> = [3.0, -1.5, 10.0, 0.0]
+        .into_iter()
+        .map(Value::Number)
+        .collect();
+    values.sort_by(Value::total_cmp);
+
+    let sorted: Vec<String> = values.iter().map(Value::to_string).collect();
+    assert_eq!(vec!["-1.5", "0", "3", "10"], sorted);
+}
+
+#[test]
+fn total_cmp_sorts_strings() {
+    let mut values: Vec<Value> = ["pear", "apple", "Zebra", "banana"]
+        .into_iter()
+        .map(|string| Value::Object(Rc::new(Object::String(string.into()))))
+        .collect();
+    values.sort_by(Value::total_cmp);
+
+    let sorted: Vec<String> = values.iter().map(Value::to_string).collect();
+    assert_eq!(vec!["Zebra", "apple", "banana", "pear"], sorted);
+}
+
+#[test]
+fn total_cmp_orders_across_types() {
+    let string = Value::Object(Rc::new(Object::String("a".into())));
+    let mut values = [
+        string,
+        Value::Number(-100.0),
+        Value::Boolean(true),
+        Value::Nil,
+        Value::Boolean(false),
+    ];
+    values.sort_by(Value::total_cmp);
+
+    let sorted: Vec<String> = values.iter().map(Value::to_string).collect();
+    assert_eq!(vec!["nil", "false", "true", "-100", "a"], sorted);
+}
+
+#[test]
+fn number_formats() {
+    let third = Value::Number(1.0 / 3.0);
+    assert_eq!("0.3333333333333333", third.format(NumberFormat::General));
+    assert_eq!("0.333", third.format(NumberFormat::Fixed(3)));
+    assert_eq!("0", third.format(NumberFormat::Fixed(0)));
+    assert_eq!("3.333333333333333e-1", third.format(NumberFormat::Scientific));
+
+    // Only numbers are affected by the format.
+    assert_eq!("true", Value::Boolean(true).format(NumberFormat::Fixed(3)));
+}
+
+#[test]
+fn truthiness() {
+    assert!(Value::Nil.is_falsey());
+    assert!(Value::Boolean(false).is_falsey());
+    assert!(Value::Boolean(true).is_truthy());
+    assert!(Value::Number(0.0).is_truthy());
+    assert!(Value::Object(Rc::new(Object::String("".into()))).is_truthy());
+}
+
+#[test]
+fn accessors() {
+    let string = Value::Object(Rc::new(Object::String("lox".into())));
+
+    assert_eq!(Some(1.5), Value::Number(1.5).as_number());
+    assert_eq!(None, Value::Nil.as_number());
+    assert_eq!(None, string.as_number());
+
+    assert_eq!(Some(false), Value::Boolean(false).as_bool());
+    assert_eq!(None, Value::Nil.as_bool());
+    assert_eq!(None, Value::Number(1.0).as_bool());
+
+    assert_eq!(Some("lox"), string.as_string());
+    assert_eq!(None, Value::Nil.as_string());
+    assert_eq!(None, Value::Boolean(true).as_string());
+}
+
+#[test]
+fn display_renders_every_value_type() {
+    let values = [
+        Value::Nil,
+        Value::Boolean(true),
+        Value::Boolean(false),
+        Value::Number(5.0),
+        Value::Number(2.5),
+        Value::Object(Rc::new(Object::String("lox".into()))),
+    ];
+    let rendered: Vec<String> = values.iter().map(|value| format!("{}", value)).collect();
+    assert_eq!(vec!["nil", "true", "false", "5", "2.5", "lox"], rendered);
+}
@@ -1,106 +1,597 @@
-use std::{process::ExitCode, fmt::Display, rc::Rc};
+use std::{process::ExitCode, fmt::Display, rc::Rc, cell::RefCell, cmp::Ordering, ops::RangeInclusive, time::Instant, io::{stdout, Write}};
 
 use crate::{ 
     fixed_vec::FixedVec, 
     chunk::{Chunk, OpCode}, 
     DEBUG_TRACE_EXECUTION, 
     DEBUG_DUMP_INSTRUCTIONS,
-    value::Value,
-    compiler::compile, 
-    object::Object,
-    heap::ObjectHeap,
+    value::{Value, NumberFormat},
+    compiler::{compile_with_interner, CompileOptions, CompileErr, Program}, 
+    object::{Function, Native, NativeFn, Object},
+    heap::{ObjectHeap, Strings},
+    natives,
+    interner::Interner,
+    globals::Globals,
+    profiler::{OpCodeProfile, LineTimings},
 };
 
-pub (crate) fn run<'i>(program: &'i str) -> ExitCode {
-    let result;
-    match compile(program) {
-        Err(errs) => {
-            for err in errs {
-                println!("{}", err);
-            }
-            return ExitCode::from(65);
-        }
-        Ok(chunks) => result = chunks,
+/// Runs a program, sending what it prints to the output.
+pub (crate) fn run(program: &str, options: VMOptions, output: Box<dyn Write>) -> ExitCode {
+    let mut vm = VM::new(Vec::new(), FixedVec::new())
+        .with_options(options)
+        .with_output(output);
+
+    if !options.profile && !options.time_lines {
+        return vm.interpret(program);
     }
-    match VM::new(result.0, result.1).run() {
-        Ok(()) => return ExitCode::SUCCESS,
-        Err(err) => {
-            match err {
-                VMErr::RuntimeErr(err) => {
-                    println!("{}", err);
-                    return ExitCode::from(70);
-                }
-                VMErr::Panic(_) => {
-                    println!("VM internally panicked");
-                    return ExitCode::FAILURE;
-                }
-                VMErr::OutOfIterations => {
-                    println!("VM exceeded 1 million operations while executing the program. Execution has been terminated.");
-                    return ExitCode::SUCCESS
-                }
+
+    let profile = Rc::new(RefCell::new(OpCodeProfile::default()));
+    let timings = Rc::new(RefCell::new(LineTimings::default()));
+    let profile_recorder = profile.clone();
+    let timings_recorder = timings.clone();
+    let exit_code = vm
+        .with_observer(Box::new(move |op, _, line| {
+            if options.profile {
+                profile_recorder.borrow_mut().record(op);
             }
-        }
+            if options.time_lines {
+                timings_recorder.borrow_mut().record(line, Instant::now());
+            }
+        }))
+        .interpret(program);
+    timings.borrow_mut().finish(Instant::now());
+
+    // Keep the reports out of the program's own output.
+    if options.profile {
+        eprint!("{}", profile.borrow().report());
     }
+    if options.time_lines {
+        eprint!("{}", timings.borrow().report());
+    }
+    exit_code
 }
 
 const FRAMES_MAX: usize = 64;
+/// The fewest objects the heap holds before it is collected.
+const GC_MIN_OBJECTS: usize = 1024;
 pub (crate) const STACK_MAX: usize = u8::MAX as usize; // FRAMES_MAX as usize * u8::MAX as usize;
-const U8_MAX: usize = u8::MAX as usize;
 
-pub (crate) enum VMErr {
+/// Settings that change how the VM executes a program and reports its errors.
+#[derive(Clone, Copy, Debug, Default)]
+pub (crate) struct VMOptions {
+    /// Include the instruction pointer, opcode, and value stack in runtime errors.
+    pub (crate) verbose_errors: bool,
+    /// How print statements render numbers.
+    pub (crate) number_format: NumberFormat,
+    /// Report how many times each opcode executed once the program finishes.
+    pub (crate) profile: bool,
+    /// Report the time spent on each source line once the program finishes.
+    pub (crate) time_lines: bool,
+    /// Refuse to run programs that compiled with warnings.
+    pub (crate) deny_warnings: bool,
+    /// Let line breaks end statements in place of semicolons.
+    pub (crate) implicit_semicolons: bool,
+    /// The longest string, in bytes, that concatenation may create. Unlimited when None.
+    pub (crate) max_string_length: Option<usize>,
+    /// How arithmetic treats a string mixed with a number.
+    pub (crate) coercion: CoercionPolicy,
+    /// Don't explain why a program was stopped for running too long.
+    pub (crate) quiet: bool,
+    /// Report the size of the compiled program before running it.
+    pub (crate) stats: bool,
+    /// What print statements end their output with.
+    pub (crate) line_ending: LineEnding,
+    /// Only define the natives that can't observe the host.
+    pub (crate) sandbox: bool,
+}
+
+/// How arithmetic treats a string mixed with a number, like \"5\" + 3.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub (crate) enum CoercionPolicy {
+    /// Mixing them is an error.
+    #[default]
+    Strict,
+    /// '+' writes the number out and concatenates, so \"5\" + 3 is \"53\". Other operators still need numbers.
+    StringConcat,
+    /// Strings that parse as numbers are used as numbers, so \"5\" + 3 is 8.
+    Numeric,
+}
+
+/// The line terminator print statements write, whatever platform the VM runs on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub (crate) enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub (crate) fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+/// Why the VM stopped before a program finished.
+#[derive(Debug)]
+pub enum VMErr {
     RuntimeErr(RunTimeErr),
     Panic(String),
     OutOfIterations,
+    /// The program's constants did not fit in what remains of the VM's constant pool.
+    TooManyConstants,
+}
+
+impl Display for VMErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VMErr::RuntimeErr(err) => write!(f, "{}", err),
+            VMErr::Panic(msg) => write!(f, "VM internally panicked: {}", msg),
+            VMErr::OutOfIterations => write!(f, "VM exceeded 1 million operations while executing the program. Execution has been terminated."),
+            VMErr::TooManyConstants => write!(f, "Error: Too many constants. The constant pool only has room for {} values.", STACK_MAX),
+        }
+    }
+}
+
+/// Anything that can stop a program from compiling or running to completion.
+#[derive(Debug)]
+pub enum LoxError {
+    Compile(CompileErr),
+    Run(VMErr),
 }
 
-pub (crate) struct RunTimeErr {
+impl LoxError {
+    /// Whether the program failed to compile only because it was cut off.
+    pub (crate) fn is_incomplete_input(&self) -> bool {
+        match self {
+            LoxError::Compile(err) => err.is_at_end_of_input(),
+            LoxError::Run(_) => false,
+        }
+    }
+}
+
+impl Display for LoxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoxError::Compile(err) => write!(f, "{}", err),
+            LoxError::Run(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// An error raised by the running program, and the line it was raised on.
+#[derive(Debug)]
+pub struct RunTimeErr {
     line: usize,
     kind: RunTimeErrKind,
+    /// Internal VM state at the time of the error. Only populated with verbose errors.
+    details: Option<String>,
+}
+
+impl RunTimeErr {
+    pub (crate) fn kind(&self) -> &RunTimeErrKind {
+        &self.kind
+    }
 }
 
 impl Display for RunTimeErr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "[line: {}] Error: {}", self.line, format!("{}", self.kind))
+        write!(f, "[line: {}] Error[{}]: {}", self.line, self.kind.as_str(), self.kind)?;
+        if let Some(details) = &self.details {
+            write!(f, "\n{}", details)?;
+        }
+        Ok(())
     }
 }
 
+#[derive(Debug)]
 pub (crate) enum RunTimeErrKind {
     ArithmeticOnNonNumber,
     ComparisonOnNonNumber,
     BooleanOperationOnObject,
     BooleanOperationOnNumber,
+    /// A concatenation would have created a string longer than the configured maximum.
+    StringTooLong,
+    /// '+' was used on a string and a number with strict arithmetic on.
+    MixedConcatenation,
+    /// A global was read before any var statement defined it.
+    UndefinedVariable(Rc<str>),
+    /// A number was divided by zero, with '/' or '%'.
+    DivisionByZero,
+    /// Something other than a function was called.
+    NotCallable,
+    /// A function was called with fewer arguments than `min` or more than `max`.
+    WrongArgumentCount { min: u8, max: u8, found: u8 },
+    /// A native was passed an argument of a type it doesn't take.
+    WrongArgumentType { expected: &'static str },
+    /// A string was indexed past its last character, or before its first.
+    IndexOutOfBounds { index: f64, len: usize },
+    /// A format template didn't have one placeholder for each argument after it.
+    FormatArgumentCount { placeholders: usize, arguments: usize },
+    /// A call needed more frames than the VM has, or a value didn't fit on the stack.
+    StackOverflow,
+}
+
+impl RunTimeErrKind {
+    /// The stable code for this kind of error. `--explain` describes each one.
+    pub (crate) fn as_str(&self) -> &'static str {
+        match self {
+            RunTimeErrKind::ArithmeticOnNonNumber => "E0101",
+            RunTimeErrKind::ComparisonOnNonNumber => "E0102",
+            RunTimeErrKind::BooleanOperationOnObject => "E0103",
+            RunTimeErrKind::BooleanOperationOnNumber => "E0104",
+            RunTimeErrKind::StringTooLong => "E0105",
+            RunTimeErrKind::MixedConcatenation => "E0106",
+            RunTimeErrKind::UndefinedVariable(_) => "E0107",
+            RunTimeErrKind::DivisionByZero => "E0108",
+            RunTimeErrKind::NotCallable => "E0109",
+            RunTimeErrKind::WrongArgumentCount { .. } => "E0110",
+            RunTimeErrKind::WrongArgumentType { .. } => "E0112",
+            RunTimeErrKind::IndexOutOfBounds { .. } => "E0113",
+            RunTimeErrKind::FormatArgumentCount { .. } => "E0114",
+            RunTimeErrKind::StackOverflow => "E0111",
+        }
+    }
 }
 
 impl Display for RunTimeErrKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let msg = match self {
             RunTimeErrKind::ArithmeticOnNonNumber => "Attempted to perform arithmetic/math operations on a non-number.",
-            RunTimeErrKind::ComparisonOnNonNumber => "Attempted to perform comparison operations on a non-number.",
+            RunTimeErrKind::ComparisonOnNonNumber => "Attempted to compare values that aren't both numbers or both strings.",
             RunTimeErrKind::BooleanOperationOnObject => "Attempted to perform boolean (and/or) operations on an object.",
             RunTimeErrKind::BooleanOperationOnNumber => "Attempted to perform boolean (and/or) operations on a number.",
+            RunTimeErrKind::StringTooLong => "Attempted to create a string longer than the maximum string length.",
+            RunTimeErrKind::MixedConcatenation => "Attempted to add a string and a number, which the coercion policy doesn't allow.",
+            RunTimeErrKind::DivisionByZero => "Attempted to divide by zero.",
+            RunTimeErrKind::NotCallable => "Attempted to call something that isn't a function.",
+            RunTimeErrKind::StackOverflow => "Stack overflow.",
+            RunTimeErrKind::WrongArgumentCount { min, max, found } if min == max => {
+                return write!(f, "Expected {} arguments but got {}.", min, found);
+            }
+            RunTimeErrKind::WrongArgumentCount { min, max, found } => {
+                return write!(f, "Expected {} to {} arguments but got {}.", min, max, found);
+            }
+            RunTimeErrKind::WrongArgumentType { expected } => return write!(f, "Expected {} as an argument.", expected),
+            RunTimeErrKind::IndexOutOfBounds { index, len } => {
+                return write!(f, "Index {} is out of bounds for a string of {} characters.", index, len);
+            }
+            RunTimeErrKind::FormatArgumentCount { placeholders, arguments } => {
+                return write!(f, "The format template has {} placeholders but was given {} arguments.", placeholders, arguments);
+            }
+            RunTimeErrKind::UndefinedVariable(name) => return write!(f, "Undefined variable '{}'.", name),
         };
 
         write!(f, "{}", msg)
     }
 }
 
-struct VM {
-    code: Vec<Chunk>,
+/// Where the VM was in its execution, so it can be unwound back there later.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub (crate) struct VmState {
+    ip: usize,
+    stack_depth: usize,
+    /// How many calls were waiting on the running one.
+    frame_depth: usize,
+}
+
+/// Where a GetGlobal found its variable. It is only used while the generation still matches.
+#[derive(Clone, Copy)]
+struct CachedGlobal {
+    slot: usize,
+    generation: u64,
+}
+
+/// A call that is waiting on the function it called to return.
+struct CallFrame {
+    /// The code of the function that made the call.
+    code: Rc<[Chunk]>,
+    /// Where to carry on once the call returns.
+    ip: usize,
+    /// The stack slot its local slot 0 is at.
+    base: usize,
+}
+
+/// Invoked before each instruction with its opcode, instruction pointer, and line.
+type Observer = Box<dyn FnMut(OpCode, usize, usize)>;
+
+pub (crate) struct VM {
+    /// The code of the running function.
+    code: Rc<[Chunk]>,
     ip: usize,
+    /// The stack slot the running function's local slot 0 is at.
+    base: usize,
+    /// The calls waiting on the running function, innermost last.
+    frames: Vec<CallFrame>,
+    /// Where to unwind to when a runtime error is raised inside a try statement, innermost last.
+    /// Each one's instruction pointer is the start of its handler.
+    handlers: Vec<VmState>,
     compiled_values: FixedVec<Value, STACK_MAX>,
     runtime_values: FixedVec<Value, STACK_MAX>,
     runtime_heap: ObjectHeap,
+    /// How many objects the heap can hold before the next collection.
+    next_gc: usize,
+    /// Shared with the compiler, so strings made at runtime reuse equal constants.
+    interner: Rc<RefCell<Interner>>,
+    globals: Globals,
+    /// The slot each GetGlobal resolved its name to, by the index of the name in the
+    /// constant pool, along with the globals' generation when it was resolved.
+    global_cache: Vec<Option<CachedGlobal>>,
+    observer: Option<Observer>,
+    /// Where execution is traced to, if anywhere.
+    trace: Option<Box<dyn Write>>,
+    /// Where print statements write to.
+    output: Box<dyn Write>,
+    options: VMOptions,
 }
 
 impl VM {
-    fn new(code: Vec<Chunk>, values: FixedVec<Value, STACK_MAX>) -> Self {
-        Self {
-            code,
+    pub (crate) fn new(code: Vec<Chunk>, values: FixedVec<Value, STACK_MAX>) -> Self {
+        let mut vm = Self {
+            code: code.into(),
             ip: 0,
-            compiled_values: values,
+            base: 0,
+            frames: Vec::new(),
+            handlers: Vec::new(),
+            compiled_values: FixedVec::new(),
             runtime_values: FixedVec::<Value, STACK_MAX>::new(),
             runtime_heap: ObjectHeap::new(),
+            next_gc: GC_MIN_OBJECTS,
+            interner: Rc::new(RefCell::new(Interner::new())),
+            globals: Globals::new(),
+            // One entry for every operand a GetGlobal can have.
+            global_cache: vec![None; u8::MAX as usize + 1],
+            observer: None,
+            trace: if DEBUG_TRACE_EXECUTION { Some(Box::new(stdout())) } else { None },
+            output: Box::new(stdout()),
+            options: VMOptions::default(),
+        };
+        for value in values {
+            let value = vm.share_string(value);
+            vm.compiled_values
+                .push(value)
+                .expect("constant pool to have room for a pool of the same size");
+        }
+        for native in natives::PURE.iter().chain(natives::IMPURE) {
+            vm.define_native(native.name, native.arity.clone(), native.function);
+        }
+        vm
+    }
+
+    /// Puts a string constant on the heap, so a string made at runtime is the same object
+    /// as an equal constant. Other values are returned as they are.
+    fn share_string(&mut self, value: Value) -> Value {
+        match value {
+            Value::Object(obj) if matches!(*obj, Object::String(_)) => {
+                Value::Object(self.runtime_heap.add(Rc::unwrap_or_clone(obj)))
+            }
+            value => value,
+        }
+    }
+
+    /// Defines a native function as a global, so Lox code can call it by name.
+    /// It can be called with any number of arguments in the range.
+    fn define_native(&mut self, name: &'static str, arity: RangeInclusive<u8>, function: NativeFn) {
+        let native = Object::Native(Native {
+            name,
+            arity: *arity.start(),
+            optional: arity.end() - arity.start(),
+            function,
+        });
+        let name = self.interner.borrow_mut().intern(name);
+        self.globals.insert(name, Value::Object(Rc::new(native)));
+    }
+
+    /// Frees runtime objects that nothing on the stack, in the constants, or in a global refers to.
+    pub (crate) fn collect_garbage(&mut self) {
+        let roots: Vec<&Value> = self.runtime_values.iter()
+            .chain(self.compiled_values.iter())
+            .chain(self.globals.values())
+            .collect();
+        self.runtime_heap.collect_garbage(&roots);
+    }
+
+    /// Collects once the heap has doubled since the last collection, so the cost of
+    /// collecting is spread over the allocations that made it necessary.
+    fn collect_garbage_if_due(&mut self) {
+        if self.runtime_heap.len() >= self.next_gc {
+            self.collect_garbage();
+            self.next_gc = (self.runtime_heap.len() * 2).max(GC_MIN_OBJECTS);
+        }
+    }
+
+    /// A sandboxed VM leaves out the natives that observe the host, so calling one
+    /// is an undefined variable like any other name that isn't defined.
+    pub (crate) fn with_options(mut self, options: VMOptions) -> Self {
+        self.options = options;
+        if options.sandbox {
+            for native in natives::IMPURE {
+                self.globals.remove(native.name);
+            }
+        }
+        self
+    }
+
+    /// Compiles and runs a program, reporting any errors. The VM keeps its globals
+    /// and constants afterwards, so interpret can be called once per REPL line.
+    pub (crate) fn interpret(&mut self, program: &str) -> ExitCode {
+        let compile_options = CompileOptions { 
+            implicit_semicolons: self.options.implicit_semicolons,
+            keep_final_expression: false,
+        };
+        let program = match compile_with_interner(program, compile_options, self.interner.clone()) {
+            Err(errs) => {
+                for err in errs {
+                    println!("{}", err);
+                }
+                return ExitCode::from(65);
+            }
+            Ok(program) => program,
+        };
+        if self.options.stats {
+            eprint!("{}", program.stats(&self.interner.borrow()).report());
+        }
+        let Program { code, values, warnings } = program;
+
+        for warning in warnings.iter() {
+            eprintln!("{}", warning);
+        }
+        if self.options.deny_warnings && !warnings.is_empty() {
+            return ExitCode::from(65);
+        }
+
+        let result = match self.load(code, values) {
+            Ok(()) => self.run(),
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                if !(self.options.quiet && matches!(err, VMErr::OutOfIterations)) {
+                    println!("{}", err);
+                }
+                match err {
+                    VMErr::RuntimeErr(_) => ExitCode::from(70),
+                    VMErr::Panic(_) => ExitCode::FAILURE,
+                    // The same code timeout(1) uses, so scripts can tell a program ran too long.
+                    VMErr::OutOfIterations => ExitCode::from(124),
+                    VMErr::TooManyConstants => ExitCode::from(65),
+                }
+            }
+        }
+    }
+
+    /// Compiles and runs a program without printing anything but the program's own output.
+    /// If the program ends with an expression statement, its value is returned.
+    pub (crate) fn evaluate(&mut self, program: &str) -> Result<Option<Value>, Vec<LoxError>> {
+        let compile_options = CompileOptions { 
+            implicit_semicolons: self.options.implicit_semicolons,
+            keep_final_expression: true,
+        };
+        let Program { code, values, warnings } = compile_with_interner(program, compile_options, self.interner.clone())
+            .map_err(|errs| errs.into_iter().map(LoxError::Compile).collect::<Vec<_>>())?;
+
+        if self.options.deny_warnings && !warnings.is_empty() {
+            return Err(warnings.into_iter().map(LoxError::Compile).collect());
+        }
+
+        self.load(code, values)
+            .and_then(|()| self.run())
+            .map_err(|err| vec![LoxError::Run(err)])?;
+
+        Ok(self.runtime_values.pop())
+    }
+
+    /// The current value of a global variable.
+    #[cfg(test)]
+    pub (crate) fn global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(name)
+    }
+
+    /// Calls the function in a global variable with arguments from the host, once the
+    /// program that defined it has run. The call runs through a Call instruction of its own,
+    /// so it is checked and executed like a call made from Lox.
+    pub (crate) fn call_global(&mut self, name: &str, args: &[Value]) -> Result<Value, VMErr> {
+        let Ok(arg_count) = u8::try_from(args.len()) else {
+            return Err(VMErr::Panic(format!("a call can pass at most {} arguments, but {} were given", u8::MAX, args.len())));
+        };
+        let line = self.code.last().map(|chunk| chunk.line).unwrap_or_default();
+        self.code = Rc::new([
+            Chunk { line, op: OpCode::Call as u8 },
+            Chunk { line, op: arg_count },
+            Chunk { line, op: OpCode::Return as u8 },
+        ]);
+        self.ip = 0;
+        self.base = 0;
+        self.frames.clear();
+        self.handlers.clear();
+        self.runtime_values.truncate(0);
+
+        let Some(callee) = self.globals.get(name).cloned() else {
+            return Err(self.runtime_err(RunTimeErrKind::UndefinedVariable(name.into())));
+        };
+        self.push_value(callee)?;
+        for arg in args {
+            self.push_value(arg.clone())?;
+        }
+        self.run()?;
+        Ok(self.pop_value())
+    }
+
+    /// Makes newly compiled code the next code to run. Its constants are appended to the
+    /// constant pool rather than replacing it, and its constant operands are offset to match,
+    /// so constants from previously loaded code stay valid. The code of the functions
+    /// among its constants reads the same pool, so it is offset too.
+    fn load(&mut self, mut code: Vec<Chunk>, values: FixedVec<Value, STACK_MAX>) -> Result<(), VMErr> {
+        let offset = self.compiled_values.len();
+        if offset + values.len() > self.compiled_values.capacity() {
+            return Err(VMErr::TooManyConstants);
+        }
+
+        offset_constants(&mut code, offset)?;
+        for value in values {
+            let value = match value.as_function() {
+                None => self.share_string(value),
+                Some(function) => {
+                    let mut chunks = function.chunks.to_vec();
+                    offset_constants(&mut chunks, offset)?;
+                    let function = Function { chunks: chunks.into(), ..function.clone() };
+                    Value::Object(Rc::new(Object::Function(function)))
+                }
+            };
+            self.compiled_values
+                .push(value)
+                .expect("constant pool to have room after checking its capacity");
+        }
+
+        self.code = code.into();
+        self.ip = 0;
+        // A runtime error can leave values and frames behind on the stack.
+        self.base = 0;
+        self.frames.clear();
+        self.handlers.clear();
+        self.runtime_values.truncate(0);
+        Ok(())
+    }
+
+    /// Captures the current execution state, so it can be restored once a runtime error is handled.
+    fn snapshot(&self) -> VmState {
+        VmState { ip: self.ip, stack_depth: self.runtime_values.len(), frame_depth: self.frames.len() }
+    }
+
+    /// Unwinds the VM back to a snapshot, dropping any values and calls pushed since it was taken.
+    fn restore(&mut self, state: VmState) {
+        self.runtime_values.truncate(state.stack_depth);
+        // The first call made since the snapshot saved the code that was running then.
+        if let Some(CallFrame { code, base, .. }) = self.frames.drain(state.frame_depth..).next() {
+            self.code = code;
+            self.base = base;
         }
+        self.ip = state.ip;
+    }
+
+    /// Registers a callback that observes every instruction the VM executes.
+    /// This is the hook used for instrumentation like profilers and visualizers.
+    fn with_observer(mut self, observer: Observer) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Sends what print statements write to the writer instead of stdout.
+    pub (crate) fn with_output(mut self, output: Box<dyn Write>) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Traces the stack and every instruction the VM executes to the writer,
+    /// along with the value each instruction produced.
+    #[cfg(test)]
+    fn with_trace(mut self, trace: Box<dyn Write>) -> Self {
+        self.trace = Some(trace);
+        self
     }
 
     fn run(&mut self) -> Result<(), VMErr> {
@@ -108,7 +599,27 @@ impl VM {
             Chunk::disassemble_code(&self.code, &self.compiled_values, "code");
         }
 
-        for _ in 0..1_000_000 { // put an upper limit of 1M iterations
+        // put an upper limit of 1M iterations, shared with the code run after a caught error
+        let mut iterations = 1_000_000;
+        loop {
+            match self.execute(&mut iterations) {
+                Err(VMErr::RuntimeErr(err)) => {
+                    let Some(handler) = self.handlers.pop() else {
+                        return Err(VMErr::RuntimeErr(err));
+                    };
+                    self.restore(handler);
+                    let message = self.alloc_string(&err.kind().to_string());
+                    self.push_value(message)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs instructions until the program ends, raises an error, or uses up its iterations.
+    fn execute(&mut self, iterations: &mut usize) -> Result<(), VMErr> {
+        while *iterations > 0 {
+            *iterations -= 1;
             let op_result = OpCode::try_from(self.code[self.ip].op);
             match op_result {
                 Err(msg) => {
@@ -116,121 +627,145 @@ impl VM {
                     return Err(VMErr::Panic(msg));
                 }
                 Ok(op) => {
-                    if DEBUG_TRACE_EXECUTION {
-                        let mut stack_str = String::new();
-                        stack_str.push_str("          ");
-                        for slot in self.runtime_values.iter() {
-                            stack_str.push_str("[ ");
-                            stack_str.push_str(&slot.to_string());
-                            stack_str.push_str(" ]");
+                    if self.trace.is_some() {
+                        let stack = self.stack_snapshot();
+                        let (instruction, _) = Chunk::format_instruction(&self.code, self.ip, &self.compiled_values);
+                        if let Some(trace) = self.trace.as_mut() {
+                            writeln!(trace, "          {}\n{}", stack, instruction).expect("Failed to write the trace");
                         }
-                        println!("{}", stack_str);
-                        Chunk::disassemble_instruction(&self.code, self.ip, &self.compiled_values);
+                    }
+
+                    if let Some(observer) = self.observer.as_mut() {
+                        observer(op, self.ip, self.code[self.ip].line);
                     }
 
                     match op {
                         OpCode::Constant => {
                             self.ip += 1;
-                            self.read_constant();
+                            self.read_constant()?;
+                        },
+                        OpCode::Nil => {
+                            self.push_value(Value::Nil)?;
+                            self.ip += 1;
+                        },
+                        OpCode::True => {
+                            self.push_value(Value::Boolean(true))?;
+                            self.ip += 1;
+                        },
+                        OpCode::False => {
+                            self.push_value(Value::Boolean(false))?;
+                            self.ip += 1;
                         },
-                        OpCode::Nil => todo!(),
-                        OpCode::True => todo!(),
-                        OpCode::False => todo!(),
                         OpCode::Pop => {
                             self.pop_value();
                             self.ip += 1;
                         },
-                        OpCode::GetLocal => todo!(),
-                        OpCode::SetLocal => todo!(),
-                        OpCode::GetGlobal => todo!(),
-                        OpCode::DefineGlobal => todo!(),
-                        OpCode::SetGlobal => todo!(),
+                        OpCode::GetLocal => {
+                            let slot = self.base + self.read_slot();
+                            let value = self.runtime_values
+                                .get(slot)
+                                .expect("local slots to be on the stack")
+                                .clone();
+                            self.push_value(value)?;
+                        },
+                        OpCode::SetLocal => {
+                            // Assignment is an expression, so the value stays on the stack.
+                            let slot = self.base + self.read_slot();
+                            let value = self.runtime_values
+                                .get(self.runtime_values.len() - 1)
+                                .expect("SetLocal to have a value to assign")
+                                .clone();
+                            *self.runtime_values
+                                .get_mut(slot)
+                                .expect("local slots to be on the stack") = value;
+                        },
+                        OpCode::GetGlobal => {
+                            let start = self.ip;
+                            let index = self.code[self.ip + 1].op as usize;
+                            let generation = self.globals.generation();
+                            let slot = match self.global_cache[index] {
+                                Some(cached) if cached.generation == generation => cached.slot,
+                                _ => {
+                                    self.ip += 1;
+                                    let name = self.read_string()?;
+                                    let Some(slot) = self.globals.slot(&name) else {
+                                        // Report the error at the instruction rather than the one after it.
+                                        self.ip = start;
+                                        return Err(self.runtime_err(RunTimeErrKind::UndefinedVariable(name)));
+                                    };
+                                    self.global_cache[index] = Some(CachedGlobal { slot, generation });
+                                    slot
+                                }
+                            };
+                            let value = self.globals.at(slot).expect("a cached slot to hold its variable").clone();
+                            self.push_value(value)?;
+                            self.ip = start + 2;
+                        },
+                        OpCode::DefineGlobal => {
+                            self.ip += 1;
+                            let name = self.read_string()?;
+                            let value = self.pop_value();
+                            self.globals.insert(name, value);
+                        },
+                        OpCode::SetGlobal => {
+                            let start = self.ip;
+                            self.ip += 1;
+                            let name = self.read_string()?;
+                            // Assignment is an expression, so the value stays on the stack.
+                            let value = self.runtime_values
+                                .get(self.runtime_values.len() - 1)
+                                .expect("SetGlobal to have a value to assign")
+                                .clone();
+                            match self.globals.get_mut(&name) {
+                                Some(global) => *global = value,
+                                None => {
+                                    // Assigning doesn't declare a variable.
+                                    self.ip = start;
+                                    return Err(self.runtime_err(RunTimeErrKind::UndefinedVariable(name)));
+                                }
+                            }
+                        },
                         OpCode::GetUpValue => todo!(),
                         OpCode::SetUpValue => todo!(),
                         OpCode::GetProperty => todo!(),
                         OpCode::SetProperty => todo!(),
                         OpCode::GetSuper => todo!(),
                         OpCode::Equal => {
-                            match self.pop_value() {
-                                Value::Nil => {
-                                    if let Value::Nil = self.pop_value() {
-                                        self.push_value(Value::Boolean(true))
-                                    } else {
-                                        self.push_value(Value::Boolean(false))
-                                    }
-                                },
-                                Value::Boolean(b) => {
-                                    if let Value::Boolean(a) = self.pop_value() {
-                                        self.push_value(Value::Boolean(a == b))
-                                    } else {
-                                        self.push_value(Value::Boolean(false))
-                                    }
-                                },
-                                Value::Number(b) => {
-                                    if let Value::Number(a) = self.pop_value() {
-                                        self.push_value(Value::Boolean(a == b))
-                                    } else {
-                                        self.push_value(Value::Boolean(false))
-                                    }
-                                },
-                                Value::Object(b) => {
-                                    if let Value::Object(a) = self.pop_value() {
-                                        self.push_value(Value::Boolean(*a == *b));
-                                    }
-                                },
-                            }
+                            let b = self.pop_value();
+                            let a = self.pop_value();
+                            self.push_value(Value::Boolean(a == b))?;
                             self.ip += 1;
                         },
                         OpCode::Greater => {
-                            if let Value::Number(b) = self.pop_value() {
-                                if let Value::Number(a) = self.pop_value() {
-                                    self.push_value(Value::Boolean(a > b))
-                                } else {
-                                    return Err(self.runtime_err(RunTimeErrKind::ComparisonOnNonNumber));
-                                }
-                            } else {
-                                return Err(self.runtime_err(RunTimeErrKind::ComparisonOnNonNumber));
-                            }
+                            self.compare(Ordering::is_gt)?;
                             self.ip += 1;
                         },
                         OpCode::Less => {
-                            if let Value::Number(b) = self.pop_value() {
-                                if let Value::Number(a) = self.pop_value() {
-                                    self.push_value(Value::Boolean(a < b))
-                                } else {
-                                    return Err(self.runtime_err(RunTimeErrKind::ComparisonOnNonNumber));
-                                }
-                            } else {
-                                return Err(self.runtime_err(RunTimeErrKind::ComparisonOnNonNumber));
-                            }
+                            self.compare(Ordering::is_lt)?;
+                            self.ip += 1;
+                        },
+                        OpCode::NotEqual => {
+                            let b = self.pop_value();
+                            let a = self.pop_value();
+                            self.push_value(Value::Boolean(a != b))?;
+                            self.ip += 1;
+                        },
+                        OpCode::GreaterEqual => {
+                            self.compare(Ordering::is_ge)?;
+                            self.ip += 1;
+                        },
+                        OpCode::LessEqual => {
+                            self.compare(Ordering::is_le)?;
                             self.ip += 1;
                         },
                         OpCode::Add => {
-                            match self.pop_value() {
-                                Value::Number(b) => {
-                                    if let Value::Number(a) = self.pop_value() {
-                                        self.push_value(Value::Number(a + b));
-                                    } else {
-                                        return Err(self.runtime_err(RunTimeErrKind::ArithmeticOnNonNumber));
-                                    }
-                                }
-                                Value::Object(b) => {
-                                    if let Object::String(b) = &*(b.clone()) {
-                                        if let Value::Object(a) = self.pop_value() {
-                                            if let Object::String(a) = &*(a.clone()) {
-                                                let mut new_str = a.to_string();
-                                                new_str.push_str(&b);
-                                                let heap_ptr = self.runtime_heap.add(
-                                                    Object::String(
-                                                        new_str.into()
-                                                    ));
-                                                self.push_value(
-                                                    Value::Object(heap_ptr)
-                                                )
-                                            }
-                                        }
-                                    }
-                                }
+                            let b = self.pop_value();
+                            let a = self.pop_value();
+                            match (&a, &b, a.as_string(), b.as_string()) {
+                                (Value::Number(a), Value::Number(b), _, _) => self.push_value(Value::Number(a + b))?,
+                                (_, _, Some(a), Some(b)) => self.concatenate(a, b)?,
+                                (_, Value::Number(b), Some(a), None) => self.add_mixed(a, *b, false)?,
+                                (Value::Number(a), _, None, Some(b)) => self.add_mixed(b, *a, true)?,
                                 _ => {
                                     return Err(self.runtime_err(RunTimeErrKind::ArithmeticOnNonNumber));
                                 }
@@ -238,45 +773,35 @@ impl VM {
                             self.ip += 1;
                         },
                         OpCode::Subtract => {
-                            if let Value::Number(b) = self.pop_value() {
-                                if let Value::Number(a) = self.pop_value() {
-                                    self.push_value(Value::Number(a - b));
-                                } else {
-                                    return Err(self.runtime_err(RunTimeErrKind::ArithmeticOnNonNumber));
-                                }
-                            } else {
-                                return Err(self.runtime_err(RunTimeErrKind::ArithmeticOnNonNumber));
-                            }
+                            let (a, b) = self.number_operands()?;
+                            self.push_value(Value::Number(a - b))?;
                             self.ip += 1;
                         },
                         OpCode::Multiply => {
-                            if let Value::Number(b) = self.pop_value() {
-                                if let Value::Number(a) = self.pop_value() {
-                                    self.push_value(Value::Number(a * b));
-                                } else {
-                                    return Err(self.runtime_err(RunTimeErrKind::ArithmeticOnNonNumber));
-                                }
-                            } else {
-                                return Err(self.runtime_err(RunTimeErrKind::ArithmeticOnNonNumber));
-                            }
+                            let (a, b) = self.number_operands()?;
+                            self.push_value(Value::Number(a * b))?;
                             self.ip += 1;
                         },
                         OpCode::Divide => {
-                            if let Value::Number(b) = self.pop_value() {
-                                if let Value::Number(a) = self.pop_value() {
-                                    self.push_value(Value::Number(a / b));
-                                } else {
-                                    return Err(self.runtime_err(RunTimeErrKind::ArithmeticOnNonNumber));
-                                }
-                            } else {
-                                return Err(self.runtime_err(RunTimeErrKind::ArithmeticOnNonNumber));
+                            let (a, b) = self.number_operands()?;
+                            if b == 0.0 {
+                                return Err(self.runtime_err(RunTimeErrKind::DivisionByZero));
                             }
+                            self.push_value(Value::Number(a / b))?;
+                            self.ip += 1;
+                        },
+                        OpCode::Modulo => {
+                            let (a, b) = self.number_operands()?;
+                            if b == 0.0 {
+                                return Err(self.runtime_err(RunTimeErrKind::DivisionByZero));
+                            }
+                            self.push_value(Value::Number(a % b))?;
                             self.ip += 1;
                         },
                         OpCode::Not => {
                             match self.pop_value() {
-                                Value::Nil => self.push_value(Value::Boolean(true)),
-                                Value::Boolean(bool) => self.push_value(Value::Boolean(!bool)),
+                                Value::Nil => self.push_value(Value::Boolean(true))?,
+                                Value::Boolean(bool) => self.push_value(Value::Boolean(!bool))?,
                                 other => {
                                     match other {
                                         Value::Number(_) => return Err(self.runtime_err(RunTimeErrKind::BooleanOperationOnNumber)),
@@ -289,71 +814,329 @@ impl VM {
                         },
                         OpCode::Negate => {
                             if let Value::Number(num) = self.pop_value() {
-                                self.push_value(Value::Number(-num));
+                                self.push_value(Value::Number(-num))?;
                             } else {
                                 return Err(self.runtime_err(RunTimeErrKind::ArithmeticOnNonNumber));
                             }
                             self.ip += 1;
                         },
                         OpCode::Print => {
-                            println!("{}", self.pop_value().to_string());
+                            let value = self.pop_value().format(self.options.number_format);
+                            write!(self.output, "{}{}", value, self.options.line_ending.as_str())
+                                .expect("Failed to write the program's output");
                             self.ip += 1;
                         },
-                        OpCode::Jump => todo!(),
-                        OpCode::JumpIfFalse => todo!(),
-                        OpCode::Loop => todo!(),
-                        OpCode::Call => todo!(),
+                        OpCode::Jump => {
+                            let distance = self.read_jump_distance()?;
+                            self.ip += distance;
+                        },
+                        OpCode::JumpIfFalse => {
+                            let distance = self.read_jump_distance()?;
+                            // The condition stays on the stack. The compiler pops it on both paths.
+                            let condition = self.runtime_values
+                                .get(self.runtime_values.len() - 1)
+                                .expect("JumpIfFalse to have a condition to check");
+                            if condition.is_falsey() {
+                                self.ip += distance;
+                            }
+                        },
+                        OpCode::JumpIfNotNil => {
+                            let distance = self.read_jump_distance()?;
+                            let value = self.runtime_values
+                                .get(self.runtime_values.len() - 1)
+                                .expect("JumpIfNotNil to have a value to check");
+                            if !matches!(value, Value::Nil) {
+                                self.ip += distance;
+                            }
+                        },
+                        OpCode::Loop => {
+                            let distance = self.read_jump_distance()?;
+                            self.ip -= distance;
+                        },
+                        OpCode::PushHandler => {
+                            let distance = self.read_jump_distance()?;
+                            let mut handler = self.snapshot();
+                            handler.ip += distance;
+                            self.handlers.push(handler);
+                        },
+                        OpCode::PopHandler => {
+                            self.handlers.pop();
+                            self.ip += 1;
+                        },
+                        OpCode::Call => {
+                            let arg_count = self.code[self.ip + 1].op;
+                            self.call(arg_count)?;
+                        },
+                        OpCode::TailCall => {
+                            let arg_count = self.code[self.ip + 1].op;
+                            self.tail_call(arg_count)?;
+                        },
                         OpCode::Invoke => todo!(),
                         OpCode::SuperInvoke => todo!(),
                         OpCode::Closure => todo!(),
                         OpCode::CloseUpValue => todo!(),
                         OpCode::Return => {
-                            return Ok(())
+                            // Returning from the script ends the program.
+                            let Some(caller) = self.frames.pop() else {
+                                return Ok(());
+                            };
+                            // Returning from inside a try leaves its handler behind.
+                            while self.handlers.last().is_some_and(|handler| handler.frame_depth > self.frames.len()) {
+                                self.handlers.pop();
+                            }
+                            // The callee and its arguments and locals are replaced by the result.
+                            let result = self.pop_value();
+                            self.runtime_values.truncate(self.base);
+                            self.push_value(result)?;
+                            self.code = caller.code;
+                            self.ip = caller.ip;
+                            self.base = caller.base;
                         },
                         OpCode::Class => todo!(),
                         OpCode::Inherit => todo!(),
                         OpCode::Method => todo!(),
                         OpCode::Unknown => panic!("Found unknown OpCode"),
                     }
+
+                    if let Some(trace) = self.trace.as_mut() {
+                        if matches!(op.stack_effect(), Some((_, pushes)) if pushes > 0) {
+                            let result = self.runtime_values.get(self.runtime_values.len() - 1).expect("a value to have been pushed");
+                            writeln!(trace, "          {:?} -> {}", op, result).expect("Failed to write the trace");
+                        }
+                    }
                 }
             }
         }
 
-        return Err(VMErr::OutOfIterations);
+        Err(VMErr::OutOfIterations)
     }
 
-    fn read_constant(&mut self) {
-        let value = 
-            self.compiled_values
-                .get(self.code[self.ip].op as usize)
-                .expect("Value pointed to to be populated.")
-                .clone();
-
-        if let Value::Object(obj) = &value {
-            if let Object::String(string) = &**obj {
-                self.runtime_values.push(
-                    Value::Object(Rc::new(
-                        Object::String(
-                            (**string).into()
-                        )
-                    ))
-                ).expect("There to never be too many values at runtime");
-                self.ip += 1;
-                return;
+    /// Calls the value below the arguments on the stack. A native's result replaces it and the
+    /// arguments straight away, while a function starts running in a new frame based at it.
+    /// Either way, the instruction pointer moves past the call.
+    fn call(&mut self, arg_count: u8) -> Result<(), VMErr> {
+        let args_start = self.runtime_values.len() - arg_count as usize;
+        let callee = self.runtime_values
+            .get(args_start - 1)
+            .expect("the callee to be below its arguments")
+            .clone();
+        let Value::Object(obj) = callee else {
+            return Err(self.runtime_err(RunTimeErrKind::NotCallable));
+        };
+
+        match &*obj {
+            Object::Native(native) => {
+                let max = native.arity + native.optional;
+                if !(native.arity..=max).contains(&arg_count) {
+                    return Err(self.runtime_err(RunTimeErrKind::WrongArgumentCount { min: native.arity, max, found: arg_count }));
+                }
+                let args: Vec<Value> = self.runtime_values.iter().skip(args_start).cloned().collect();
+                self.collect_garbage_if_due();
+                let mut strings = Strings { interner: &self.interner, heap: &mut self.runtime_heap };
+                let result = (native.function)(&args, &mut strings).map_err(|kind| self.runtime_err(kind))?;
+                self.runtime_values.truncate(args_start - 1);
+                self.push_value(result)?;
+                self.ip += 2;
+                Ok(())
             }
+            Object::Function(function) => {
+                if arg_count != function.arity {
+                    return Err(self.runtime_err(RunTimeErrKind::WrongArgumentCount { min: function.arity, max: function.arity, found: arg_count }));
+                }
+                // The running function takes a frame too, even though it isn't saved in frames.
+                if self.frames.len() + 1 == FRAMES_MAX {
+                    return Err(self.runtime_err(RunTimeErrKind::StackOverflow));
+                }
+                let caller = CallFrame {
+                    code: std::mem::replace(&mut self.code, function.chunks.clone()),
+                    ip: self.ip + 2,
+                    base: self.base,
+                };
+                self.frames.push(caller);
+                self.ip = 0;
+                self.base = args_start - 1;
+                Ok(())
+            }
+            Object::String(_) => Err(self.runtime_err(RunTimeErrKind::NotCallable)),
         }
+    }
 
-        self.runtime_values
-            .push(value)
-            .expect("There to never be too many values at runtime");
+    /// Like call, but a function takes over the running function's frame, since the running
+    /// function returns whatever it does. The callee and its arguments are moved down over
+    /// the running function's slots, which discards its locals. Anything else is called as
+    /// usual, and the Return after the TailCall returns its result.
+    fn tail_call(&mut self, arg_count: u8) -> Result<(), VMErr> {
+        let args_start = self.runtime_values.len() - arg_count as usize;
+        let callee = self.runtime_values
+            .get(args_start - 1)
+            .expect("the callee to be below its arguments")
+            .clone();
+        let Some(function) = callee.as_function() else {
+            return self.call(arg_count);
+        };
+        if arg_count != function.arity {
+            return Err(self.runtime_err(RunTimeErrKind::WrongArgumentCount { min: function.arity, max: function.arity, found: arg_count }));
+        }
+
+        let moved: Vec<Value> = self.runtime_values.iter().skip(args_start - 1).cloned().collect();
+        self.runtime_values.truncate(self.base);
+        for value in moved {
+            self.push_value(value)?;
+        }
+        self.code = function.chunks.clone();
+        self.ip = 0;
+        Ok(())
+    }
+
+    /// Pops two operands and pushes whether their ordering passes the test. Numbers
+    /// compare numerically and strings lexicographically. NaN compares false to everything.
+    fn compare(&mut self, test: fn(Ordering) -> bool) -> Result<(), VMErr> {
+        let b = self.pop_value();
+        let a = self.pop_value();
+        let ordering = match (&a, &b) {
+            (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+            _ => {
+                match (a.as_string(), b.as_string()) {
+                    (Some(a), Some(b)) => Some(a.cmp(b)),
+                    _ => return Err(self.runtime_err(RunTimeErrKind::ComparisonOnNonNumber)),
+                }
+            }
+        };
+        self.push_value(Value::Boolean(ordering.is_some_and(test)))?;
+        Ok(())
+    }
+
+    /// Reads the stack slot operand of a local variable instruction,
+    /// leaving the instruction pointer at the next instruction.
+    fn read_slot(&mut self) -> usize {
+        let slot = self.code[self.ip + 1].op as usize;
+        self.ip += 2;
+        slot
+    }
+
+    /// Reads the 16 bit distance after a jump, leaving the instruction
+    /// pointer at the end of the jump instruction, where distances are counted from.
+    fn read_jump_distance(&mut self) -> Result<usize, VMErr> {
+        let (Some(high), Some(low)) = (self.code.get(self.ip + 1), self.code.get(self.ip + 2)) else {
+            return Err(VMErr::Panic("missing jump distance".to_string()));
+        };
+
+        let distance = u16::from_be_bytes([high.op, low.op]);
+        self.ip += 3;
+        Ok(distance as usize)
+    }
+
+    /// Pushes the concatenation of two strings.
+    fn concatenate(&mut self, a: &str, b: &str) -> Result<(), VMErr> {
+        if let Some(max) = self.options.max_string_length {
+            if a.len() + b.len() > max {
+                return Err(self.runtime_err(RunTimeErrKind::StringTooLong));
+            }
+        }
+
+        let mut new_str = a.to_string();
+        new_str.push_str(b);
+        let new_str = self.alloc_string(&new_str);
+        self.push_value(new_str)?;
+        Ok(())
+    }
+
+    /// Makes a string at runtime. It is interned, so it shares any equal constant,
+    /// and tracked by the heap, so it can be collected.
+    fn alloc_string(&mut self, string: &str) -> Value {
+        self.collect_garbage_if_due();
+        Strings { interner: &self.interner, heap: &mut self.runtime_heap }.alloc(string)
+    }
+
+    /// Adds a string and a number as the coercion policy says to. When concatenating,
+    /// the number is written out the way print shows it by default.
+    fn add_mixed(&mut self, string: &str, num: f64, number_first: bool) -> Result<(), VMErr> {
+        match self.options.coercion {
+            CoercionPolicy::Strict => Err(self.runtime_err(RunTimeErrKind::MixedConcatenation)),
+            CoercionPolicy::StringConcat => {
+                let num = NumberFormat::General.format(num);
+                if number_first {
+                    self.concatenate(&num, string)
+                } else {
+                    self.concatenate(string, &num)
+                }
+            }
+            CoercionPolicy::Numeric => {
+                let Ok(parsed) = string.parse::<f64>() else {
+                    return Err(self.runtime_err(RunTimeErrKind::ArithmeticOnNonNumber));
+                };
+                self.push_value(Value::Number(parsed + num))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Pops the operands of an arithmetic operator other than '+', which must be
+    /// numbers, or strings that parse as numbers under the numeric coercion policy.
+    fn number_operands(&mut self) -> Result<(f64, f64), VMErr> {
+        let b = self.pop_value();
+        let a = self.pop_value();
+        match (self.coerce_to_number(&a), self.coerce_to_number(&b)) {
+            (Some(a), Some(b)) => Ok((a, b)),
+            _ => Err(self.runtime_err(RunTimeErrKind::ArithmeticOnNonNumber)),
+        }
+    }
+
+    fn coerce_to_number(&self, value: &Value) -> Option<f64> {
+        match value {
+            Value::Number(num) => Some(*num),
+            Value::Object(_) if self.options.coercion == CoercionPolicy::Numeric => {
+                value.as_string()?.parse().ok()
+            }
+            _ => None,
+        }
+    }
+
+    fn read_constant(&mut self) -> Result<(), VMErr> {
+        // Strings are interned, so the constant is shared rather than copied.
+        let value = self.constant_at_operand()?.clone();
+
+        self.push_value(value)?;
 
         self.ip += 1;
+        Ok(())
     }
 
-    fn push_value(&mut self, val: Value) {
-        self.runtime_values
-            .push(val)
-            .expect("Runtime to never have too many values");
+    /// Reads the string constant pointed to by the current operand.
+    fn read_string(&mut self) -> Result<Rc<str>, VMErr> {
+        let string = match self.constant_at_operand()? {
+            Value::Object(obj) => {
+                match &**obj {
+                    Object::String(string) => string.clone(),
+                    Object::Native(_) | Object::Function(_) => return Err(VMErr::Panic("Expected operand to point to a string constant".to_string())),
+                }
+            }
+            _ => return Err(VMErr::Panic("Expected operand to point to a string constant".to_string())),
+        };
+
+        self.ip += 1;
+        Ok(string)
+    }
+
+    /// Looks up the constant the current operand points to. Malformed
+    /// bytecode can point past the end of the pool, so this is checked.
+    fn constant_at_operand(&self) -> Result<&Value, VMErr> {
+        let index = match self.code.get(self.ip) {
+            Some(chunk) => chunk.op as usize,
+            None => return Err(VMErr::Panic("missing constant operand".to_string())),
+        };
+
+        self.compiled_values
+            .get(index)
+            .ok_or_else(|| VMErr::Panic(format!("invalid constant index {}", index)))
+    }
+
+    /// Pushes a value onto the stack. Deep enough calls can fill it, which is a stack overflow.
+    fn push_value(&mut self, val: Value) -> Result<(), VMErr> {
+        if self.runtime_values.push(val).is_err() {
+            return Err(self.runtime_err(RunTimeErrKind::StackOverflow));
+        }
+        Ok(())
     }
 
     fn pop_value(&mut self) -> Value {
@@ -362,12 +1145,1044 @@ impl VM {
             .expect("Popped value to be populated.")
     }
 
+    fn stack_snapshot(&self) -> String {
+        let mut stack_str = String::new();
+        for slot in self.runtime_values.iter() {
+            stack_str.push_str("[ ");
+            stack_str.push_str(&slot.to_string());
+            stack_str.push_str(" ]");
+        }
+        stack_str
+    }
+
     fn runtime_err(&self, kind: RunTimeErrKind) -> VMErr {
+        let details = if self.options.verbose_errors {
+            let op = match OpCode::try_from(self.code[self.ip].op) {
+                Ok(op) => format!("{:?}", op),
+                Err(msg) => msg,
+            };
+            Some(format!("    ip: {}, op: {}\n    stack: {}", self.ip, op, self.stack_snapshot()))
+        } else {
+            None
+        };
+
         VMErr::RuntimeErr(
             RunTimeErr { 
                 line: self.code[self.ip].line, 
                 kind,
+                details,
             }
         )
     }
-}
\ No newline at end of file
+}
+
+/// Adds the offset to every constant operand in the code.
+fn offset_constants(code: &mut [Chunk], offset: usize) -> Result<(), VMErr> {
+    let mut constant_operands = Vec::new();
+    for instruction in Chunk::decode(code) {
+        let instruction = instruction.map_err(VMErr::Panic)?;
+        if instruction.op.has_constant_operand() {
+            constant_operands.push(instruction.offset + 1);
+        }
+    }
+
+    for index in constant_operands {
+        // load's capacity check guarantees this still fits in a byte.
+        code[index].op += offset as u8;
+    }
+    Ok(())
+}
+
+#[test]
+fn multiple_var_declarations() {
+    let Program { code, values, .. } = crate::compiler::compile("var a = 1, b = \"two\", c;").expect("program to compile");
+    let mut vm = VM::new(code, values);
+    assert!(vm.run().is_ok());
+
+    assert!(matches!(vm.globals.get("a"), Some(Value::Number(num)) if *num == 1.0));
+    assert_eq!("two", vm.globals.get("b").expect("b to be defined").to_string());
+    assert!(matches!(vm.globals.get("c"), Some(Value::Nil)));
+}
+
+#[test]
+fn uninitialized_var_declarations_are_nil() {
+    let Program { code, values, .. } = crate::compiler::compile("var a = 1, b;").expect("program to compile");
+    let mut vm = VM::new(code, values);
+    assert!(vm.run().is_ok());
+
+    assert!(matches!(vm.globals.get("a"), Some(Value::Number(num)) if *num == 1.0));
+    assert!(matches!(vm.globals.get("b"), Some(Value::Nil)));
+}
+
+#[test]
+fn observer_sees_each_instruction() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let seen_by_observer = seen.clone();
+
+    let Program { code, values, .. } = crate::compiler::compile("1 + 2;").expect("program to compile");
+    let mut vm = VM::new(code, values)
+        .with_observer(Box::new(move |op, ip, _line| {
+            seen_by_observer.borrow_mut().push((op, ip));
+        }));
+    assert!(vm.run().is_ok());
+
+    assert_eq!(vec![
+        (OpCode::Constant, 0),
+        (OpCode::Constant, 2),
+        (OpCode::Add, 4),
+        (OpCode::Pop, 5),
+        (OpCode::Return, 6),
+    ], *seen.borrow());
+}
+
+/// A writer tests can read back after handing it to the VM.
+#[cfg(test)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn trace_shows_the_value_each_instruction_produced() {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let Program { code, values, .. } = crate::compiler::compile("1 + 2;").expect("program to compile");
+    let mut vm = VM::new(code, values)
+        .with_trace(Box::new(SharedBuffer(output.clone())));
+    assert!(vm.run().is_ok());
+
+    let trace = String::from_utf8(output.borrow().clone()).expect("trace to be utf-8");
+    let add = trace.find("Add\n").expect("the trace to show Add");
+    assert!(trace[add..].starts_with("Add\n          Add -> 3\n"), "{}", trace);
+    assert!(trace.contains("Constant -> 1\n") && trace.contains("[ 1 ][ 2 ]\n"));
+}
+
+#[test]
+fn try_catches_runtime_errors_and_resumes() {
+    let (result, globals) = run_source("
+        var caught;
+        var after;
+        try { var a = 1; print a / 0; caught = \"no\"; } catch (e) { caught = e; }
+        after = \"resumed\";
+
+        fun divide(a, b) { return a / b; }
+        var fromCall;
+        try { divide(1, 0); } catch (e) { fromCall = e; }
+
+        var inner;
+        var outer;
+        try {
+            try { 1 / 0; } catch (e) { inner = e; }
+            -\"x\";
+        } catch (e) { outer = e; }
+    ");
+    assert!(result.is_ok());
+    assert_eq!(Some("Attempted to divide by zero."), globals.get("caught").and_then(Value::as_string));
+    assert_eq!(Some("resumed"), globals.get("after").and_then(Value::as_string));
+    assert_eq!(Some("Attempted to divide by zero."), globals.get("fromCall").and_then(Value::as_string));
+    assert_eq!(Some("Attempted to divide by zero."), globals.get("inner").and_then(Value::as_string));
+    assert!(globals.get("outer").and_then(Value::as_string).is_some());
+
+    // Leaving a try by break or return leaves its handler behind, so later errors aren't caught.
+    for source in [
+        "while (true) { try { break; } catch (e) {} } 1 / 0;",
+        "fun f() { try { return 1; } catch (e) {} } f(); 1 / 0;",
+    ] {
+        let (result, _) = run_source(source);
+        assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::DivisionByZero)), "{}", source);
+    }
+}
+
+#[test]
+fn restore_unwinds_to_the_snapshot() {
+    let Program { code, values, .. } = crate::compiler::compile("1;").expect("program to compile");
+    let mut vm = VM::new(code, values);
+    vm.push_value(Value::Number(1.0)).expect("the stack to have room");
+    let state = vm.snapshot();
+
+    vm.push_value(Value::Number(2.0)).expect("the stack to have room");
+    vm.push_value(Value::Nil).expect("the stack to have room");
+    vm.ip = 2;
+    vm.restore(state);
+
+    assert_eq!(state, vm.snapshot());
+    assert_eq!(1, vm.runtime_values.len());
+    assert!(matches!(vm.runtime_values.get(0), Some(Value::Number(num)) if *num == 1.0));
+}
+
+#[test]
+fn literal_opcodes_push_their_values() {
+    let Program { code, values, .. } = crate::compiler::compile("var a = true, b = false, c = nil, d;").expect("program to compile");
+    let mut vm = VM::new(code, values);
+    assert!(vm.run().is_ok());
+
+    assert!(matches!(vm.globals.get("a"), Some(Value::Boolean(true))));
+    assert!(matches!(vm.globals.get("b"), Some(Value::Boolean(false))));
+    assert!(matches!(vm.globals.get("c"), Some(Value::Nil)));
+    assert!(matches!(vm.globals.get("d"), Some(Value::Nil)));
+}
+
+#[test]
+fn verbose_errors_include_vm_state() {
+    let program = "print 1; print -\"one\";";
+
+    let Program { code, values, .. } = crate::compiler::compile(program).expect("program to compile");
+    let terse = match VM::new(code, values).run() {
+        Err(VMErr::RuntimeErr(err)) => err.to_string(),
+        _ => panic!("Expected a runtime error"),
+    };
+    assert_eq!("[line: 0] Error[E0101]: Attempted to perform arithmetic/math operations on a non-number.", terse);
+
+    let Program { code, values, .. } = crate::compiler::compile(program).expect("program to compile");
+    let options = VMOptions { verbose_errors: true, ..Default::default() };
+    let verbose = match VM::new(code, values).with_options(options).run() {
+        Err(VMErr::RuntimeErr(err)) => err.to_string(),
+        _ => panic!("Expected a runtime error"),
+    };
+    assert!(verbose.starts_with(&terse));
+    assert!(verbose.contains("ip: 5, op: Negate"));
+    assert!(verbose.contains("stack: "));
+}
+
+#[test]
+fn loaded_constants_accumulate() {
+    let mut vm = VM::new(Vec::new(), FixedVec::new());
+    let lines = [
+        "var a = \"one\";",
+        "var b = \"two\";",
+        "var c = \"three\", d = \"four\";",
+    ];
+    for line in lines {
+        let Program { code, values, .. } = crate::compiler::compile(line).expect("line to compile");
+        assert!(vm.load(code, values).is_ok());
+        assert!(vm.run().is_ok());
+    }
+
+    assert_eq!(8, vm.compiled_values.len());
+    let constants: Vec<String> = vm.compiled_values.iter().map(Value::to_string).collect();
+    assert_eq!(vec!["a", "one", "b", "two", "c", "three", "d", "four"], constants);
+
+    assert_eq!("one", vm.globals.get("a").expect("a to be defined").to_string());
+    assert_eq!("two", vm.globals.get("b").expect("b to be defined").to_string());
+    assert_eq!("three", vm.globals.get("c").expect("c to be defined").to_string());
+    assert_eq!("four", vm.globals.get("d").expect("d to be defined").to_string());
+}
+
+#[test]
+fn loaded_constants_overflowing_the_pool_are_rejected() {
+    let mut vm = VM::new(Vec::new(), FixedVec::new());
+    let line = "1; 2; 3; 4; 5; 6; 7; 8; 9; 10;";
+    for _ in 0..(STACK_MAX / 10) {
+        let Program { code, values, .. } = crate::compiler::compile(line).expect("line to compile");
+        assert!(vm.load(code, values).is_ok());
+        assert!(vm.run().is_ok());
+    }
+
+    let Program { code, values, .. } = crate::compiler::compile(line).expect("line to compile");
+    assert!(matches!(vm.load(code, values), Err(VMErr::TooManyConstants)));
+}
+
+#[test]
+fn profile_counts_executed_opcodes() {
+    let profile = Rc::new(RefCell::new(OpCodeProfile::default()));
+    let recorder = profile.clone();
+
+    let Program { code, values, .. } = crate::compiler::compile("1 + 2; print 3 + 4 + 5;").expect("program to compile");
+    let mut vm = VM::new(code, values)
+        .with_observer(Box::new(move |op, _, _| recorder.borrow_mut().record(op)));
+    assert!(vm.run().is_ok());
+
+    assert_eq!(vec![
+        (OpCode::Constant, 5),
+        (OpCode::Add, 3),
+        (OpCode::Pop, 1),
+        (OpCode::Print, 1),
+        (OpCode::Return, 1),
+    ], profile.borrow().sorted());
+}
+
+#[test]
+fn invalid_constant_indexes_are_reported() {
+    let code = vec![
+        Chunk { line: 0, op: OpCode::Constant as u8 },
+        Chunk { line: 0, op: 3 },
+        Chunk { line: 0, op: OpCode::Return as u8 },
+    ];
+    let mut values = FixedVec::new();
+    values.push(Value::Number(1.0)).expect("value to fit in the pool");
+
+    let mut vm = VM::new(code, values);
+    match vm.run() {
+        Err(VMErr::Panic(msg)) => assert_eq!("invalid constant index 3", msg),
+        _ => panic!("Expected an invalid constant index panic"),
+    }
+}
+
+#[test]
+fn combined_comparisons() {
+    let source = "var a = 1 != 2, b = \"x\" != \"x\", c = 2 >= 2, d = 1 >= 2, e = 2 <= 2, f = 3 <= 2;";
+    let Program { code, values, .. } = crate::compiler::compile(source).expect("comparisons to compile");
+    let mut vm = VM::new(code, values);
+    assert!(vm.run().is_ok());
+
+    let results: Vec<String> = ["a", "b", "c", "d", "e", "f"].iter()
+        .map(|name| vm.globals.get(name).expect("global to be defined").to_string())
+        .collect();
+    assert_eq!(vec!["true", "false", "true", "false", "true", "false"], results);
+}
+
+#[test]
+fn equality_across_types_is_false() {
+    let source = "var a = 1 == \"1\", b = nil == \"\", c = \"a\" == \"a\", d = true == 1;";
+    let Program { code, values, .. } = crate::compiler::compile(source).expect("comparisons to compile");
+    let mut vm = VM::new(code, values);
+    assert!(vm.run().is_ok());
+
+    let results: Vec<String> = ["a", "b", "c", "d"].iter()
+        .map(|name| vm.globals.get(name).expect("global to be defined").to_string())
+        .collect();
+    assert_eq!(vec!["false", "false", "true", "false"], results);
+}
+
+#[test]
+fn string_length_limit() {
+    let source = "var s = \"ab\" + \"ab\" + \"ab\" + \"ab\";";
+    let options = VMOptions { max_string_length: Some(6), ..Default::default() };
+
+    let Program { code, values, .. } = crate::compiler::compile(source).expect("program to compile");
+    let message = match VM::new(code, values).with_options(options).run() {
+        Err(VMErr::RuntimeErr(err)) => err.to_string(),
+        _ => panic!("Expected the concatenation to be too long"),
+    };
+    assert!(message.contains("maximum string length"));
+
+    let Program { code, values, .. } = crate::compiler::compile(source).expect("program to compile");
+    let mut vm = VM::new(code, values);
+    assert!(vm.run().is_ok());
+    assert_eq!("abababab", vm.globals.get("s").expect("s to be defined").to_string());
+}
+
+#[test]
+fn coercion_policies_decide_how_strings_mix_with_numbers() {
+    let run = |source: &str, coercion: CoercionPolicy| {
+        let Program { code, values, .. } = crate::compiler::compile(source).expect("program to compile");
+        let mut vm = VM::new(code, values).with_options(VMOptions { coercion, ..Default::default() });
+        let result = vm.run();
+        (result, vm.globals)
+    };
+    let mixed_err = |result: Result<(), VMErr>| match result {
+        Err(VMErr::RuntimeErr(err)) => err.kind().as_str(),
+        _ => panic!("Expected a runtime error"),
+    };
+
+    assert_eq!(CoercionPolicy::Strict, VMOptions::default().coercion);
+    let (result, _) = run("var s = \"5\" + 3;", CoercionPolicy::Strict);
+    assert_eq!("E0106", mixed_err(result));
+    let (result, globals) = run("var s = \"a\" + \"b\";", CoercionPolicy::Strict);
+    assert!(result.is_ok());
+    assert_eq!("ab", globals.get("s").expect("s to be defined").to_string());
+
+    let (result, globals) = run("var s = \"5\" + 3; var t = \"a\" + 1 + \"b\"; var u = 2.5 + \"c\";", CoercionPolicy::StringConcat);
+    assert!(result.is_ok());
+    assert_eq!("53", globals.get("s").expect("s to be defined").to_string());
+    assert_eq!("a1b", globals.get("t").expect("t to be defined").to_string());
+    assert_eq!("2.5c", globals.get("u").expect("u to be defined").to_string());
+    let (result, _) = run("var s = \"5\" - 3;", CoercionPolicy::StringConcat);
+    assert_eq!("E0101", mixed_err(result));
+
+    let (result, globals) = run("var s = \"5\" + 3; var t = 3 * \"2.5\"; var u = \"5\" + \"3\";", CoercionPolicy::Numeric);
+    assert!(result.is_ok());
+    assert_eq!(Some(&Value::Number(8.0)), globals.get("s"));
+    assert_eq!(Some(&Value::Number(7.5)), globals.get("t"));
+    // Two strings are still concatenated.
+    assert_eq!("53", globals.get("u").expect("u to be defined").to_string());
+    let (result, _) = run("var s = \"five\" + 3;", CoercionPolicy::Numeric);
+    assert_eq!("E0101", mixed_err(result));
+
+    for coercion in [CoercionPolicy::Strict, CoercionPolicy::StringConcat, CoercionPolicy::Numeric] {
+        let (result, _) = run("var s = \"a\" + true;", coercion);
+        assert_eq!("E0101", mixed_err(result));
+    }
+}
+
+#[test]
+fn globals_can_be_read_after_they_are_defined() {
+    let Program { code, values, .. } = crate::compiler::compile("var a = 1; var b; var c = a + 2; print b;").expect("program to compile");
+    let mut vm = VM::new(code, values);
+    assert!(vm.run().is_ok());
+    assert!(matches!(vm.globals.get("c"), Some(Value::Number(num)) if *num == 3.0));
+
+    let Program { code, values, .. } = crate::compiler::compile("var a = 1;\nvar b = c;").expect("program to compile");
+    let err = match VM::new(code, values).run() {
+        Err(VMErr::RuntimeErr(err)) => err,
+        _ => panic!("Expected reading c to be an error"),
+    };
+    assert!(matches!(&err.kind, RunTimeErrKind::UndefinedVariable(name) if &**name == "c"));
+    assert_eq!("[line: 1] Error[E0107]: Undefined variable 'c'.", err.to_string());
+}
+
+#[test]
+fn if_statements_run_one_branch() {
+    let source = "
+        if (1 < 2) var a = 1; else var b = 2;
+        if (nil) var c = 3; else var d = 4;
+        if (true) if (false) var e = 5; else var f = 6;
+        if (false) if (true) var g = 7; else var h = 8;
+        if (false) var i = 9;
+    ";
+    let Program { code, values, .. } = crate::compiler::compile(source).expect("program to compile");
+    let mut vm = VM::new(code, values);
+    assert!(vm.run().is_ok());
+
+    let mut defined: Vec<&str> = vm.globals.iter()
+        .filter(|(_, value)| !matches!(value, Value::Object(obj) if matches!(**obj, Object::Native(_))))
+        .map(|(name, _)| &**name)
+        .collect();
+    defined.sort();
+    // The dangling elses bind to the nearest if.
+    assert_eq!(vec!["a", "d", "f"], defined);
+    // Each condition was popped on whichever path was taken.
+    assert_eq!(0, vm.runtime_values.len());
+}
+
+#[test]
+fn while_loops_run_until_their_condition_is_false() {
+    // Redefining a global is the only way to update one until assignment exists.
+    let source = "var i = 0; while (i < 5) var i = i + 1; while (false) print 1;";
+    let Program { code, values, .. } = crate::compiler::compile(source).expect("program to compile");
+    let prints = Rc::new(RefCell::new(0));
+    let counted = prints.clone();
+    let mut vm = VM::new(code, values)
+        .with_observer(Box::new(move |op, _, _| {
+            if op == OpCode::Print {
+                *counted.borrow_mut() += 1;
+            }
+        }));
+    assert!(vm.run().is_ok());
+
+    assert!(matches!(vm.globals.get("i"), Some(Value::Number(num)) if *num == 5.0));
+    assert_eq!(0, *prints.borrow());
+    assert_eq!(0, vm.runtime_values.len());
+}
+
+#[test]
+fn for_loops_run_until_their_condition_is_false() {
+    // The loop variables are locals, so they are gone once their loops end.
+    let source = "for (var i = 0; i > 0;) print i; for (var j = 0; false; j) print j; var after = 1;";
+    let Program { code, values, .. } = crate::compiler::compile(source).expect("program to compile");
+    let mut vm = VM::new(code, values);
+    assert!(vm.run().is_ok());
+
+    assert!(!vm.globals.contains_key("i") && !vm.globals.contains_key("j"));
+    assert!(matches!(vm.globals.get("after"), Some(Value::Number(num)) if *num == 1.0));
+    assert_eq!(0, vm.runtime_values.len());
+}
+
+#[test]
+fn locals_are_read_and_written_by_stack_slot() {
+    let chunk = |op: u8| Chunk { line: 0, op };
+    let mut values = FixedVec::new();
+    values.push(Value::Number(1.0)).expect("value to fit in the pool");
+    values.push(Value::Number(2.0)).expect("value to fit in the pool");
+    let code = vec![
+        chunk(OpCode::Constant as u8), chunk(0),
+        chunk(OpCode::GetLocal as u8), chunk(0),
+        chunk(OpCode::Constant as u8), chunk(1),
+        chunk(OpCode::Add as u8),
+        chunk(OpCode::SetLocal as u8), chunk(0),
+        chunk(OpCode::Pop as u8),
+        chunk(OpCode::Return as u8),
+    ];
+
+    let mut vm = VM::new(code, values);
+    assert!(vm.run().is_ok());
+    assert_eq!(1, vm.runtime_values.len());
+    assert!(matches!(vm.runtime_values.get(0), Some(Value::Number(num)) if *num == 3.0));
+}
+
+#[test]
+fn blocks_leave_the_stack_as_they_found_it() {
+    let source = "var a = 1; { var a = 2; { var c = a + 10; print c; } print a; } var b = a;";
+    let Program { code, values, .. } = crate::compiler::compile(source).expect("program to compile");
+    let mut vm = VM::new(code, values);
+    assert!(vm.run().is_ok());
+
+    assert!(matches!(vm.globals.get("b"), Some(Value::Number(num)) if *num == 1.0));
+    assert_eq!(0, vm.runtime_values.len());
+}
+
+/// Compiles and runs a program, returning how the run ended and the globals it left behind.
+/// A run that succeeds must also leave nothing on the value stack.
+#[cfg(test)]
+fn run_source(source: &str) -> (Result<(), VMErr>, Globals) {
+    let Program { code, values, .. } = crate::compiler::compile(source).expect("program to compile");
+    let mut vm = VM::new(code, values);
+    let result = vm.run();
+    if result.is_ok() {
+        assert_eq!(0, vm.runtime_values.len(), "{}", source);
+    }
+    (result, vm.globals)
+}
+
+#[test]
+fn assignment_updates_globals_and_locals() {
+    let (result, globals) = run_source("var a; var b = a = 3; { var c = 1; c = c + a; a = c; }");
+    assert!(result.is_ok());
+    assert!(matches!(globals.get("a"), Some(Value::Number(num)) if *num == 4.0));
+    assert!(matches!(globals.get("b"), Some(Value::Number(num)) if *num == 3.0));
+
+    // A parenthesized assignment is an operand like any other.
+    let (result, globals) = run_source("var a; var b; var c = (a = 2) == 2; print (b = 3);");
+    assert!(result.is_ok());
+    assert_eq!(Some(&Value::Number(2.0)), globals.get("a"));
+    assert_eq!(Some(&Value::Number(3.0)), globals.get("b"));
+    assert_eq!(Some(&Value::Boolean(true)), globals.get("c"));
+
+    let (result, globals) = run_source("undefined = 1;");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::UndefinedVariable(_))));
+    assert!(!globals.contains_key("undefined"));
+}
+
+#[test]
+fn for_loops_count_with_their_increment() {
+    let source = "var sum = 0; for (var i = 0; i < 4; i = i + 1) sum = sum + i; \
+        var last; for (var i = 0;; i = i + 1) { if (i > 2) break; last = i; }";
+    let Program { code, values, .. } = crate::compiler::compile(source).expect("program to compile");
+    let mut vm = VM::new(code, values);
+    assert!(vm.run().is_ok());
+
+    assert!(matches!(vm.globals.get("sum"), Some(Value::Number(num)) if *num == 6.0));
+    assert!(matches!(vm.globals.get("last"), Some(Value::Number(num)) if *num == 2.0));
+    assert_eq!(0, vm.runtime_values.len());
+}
+
+#[test]
+fn break_leaves_the_innermost_loop() {
+    let source = "for (;;) { var a = 1; break; } \
+        var n = 0; while (true) { for (var i = 0;;) { if (i < 3) break; print i; } var inner = 2; break; } \
+        var after = n;";
+    let Program { code, values, .. } = crate::compiler::compile(source).expect("program to compile");
+    let mut vm = VM::new(code, values);
+    assert!(vm.run().is_ok());
+
+    assert!(matches!(vm.globals.get("after"), Some(Value::Number(num)) if *num == 0.0));
+    assert_eq!(0, vm.runtime_values.len());
+}
+
+#[test]
+fn and_only_evaluates_its_right_operand_when_the_left_is_truthy() {
+    // The right operand would raise an undefined variable error if it ran.
+    let (result, globals) = run_source("var a = false and sideEffect; var b = nil and -\"x\";");
+    assert!(result.is_ok());
+    assert!(matches!(globals.get("a"), Some(Value::Boolean(false))));
+    assert!(matches!(globals.get("b"), Some(Value::Nil)));
+
+    let (result, globals) = run_source("var a = true and 2; var b = true and false and 3;");
+    assert!(result.is_ok());
+    assert!(matches!(globals.get("a"), Some(Value::Number(num)) if *num == 2.0));
+    assert!(matches!(globals.get("b"), Some(Value::Boolean(false))));
+
+    let (result, _) = run_source("true and sideEffect;");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::UndefinedVariable(_))));
+}
+
+#[test]
+fn or_only_evaluates_its_right_operand_when_the_left_is_falsey() {
+    // The right operand would raise an undefined variable error if it ran.
+    let (result, globals) = run_source("var a = true or explode; var b = false or nil or 3; var c = false and explode or 4;");
+    assert!(result.is_ok());
+    assert!(matches!(globals.get("a"), Some(Value::Boolean(true))));
+    assert!(matches!(globals.get("b"), Some(Value::Number(num)) if *num == 3.0));
+    assert!(matches!(globals.get("c"), Some(Value::Number(num)) if *num == 4.0));
+
+    let (result, _) = run_source("nil or explode;");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::UndefinedVariable(_))));
+}
+
+#[test]
+fn coalesce_only_evaluates_its_right_operand_when_the_left_is_nil() {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = VM::new(Vec::new(), FixedVec::new())
+        .with_output(Box::new(SharedBuffer(output.clone())));
+    // boom is never defined, so calling it would raise an undefined variable error.
+    let exit_code = vm.interpret("var x; print x ?? 5; print 3 ?? boom(); print false ?? 1;");
+    assert_eq!(ExitCode::SUCCESS, exit_code);
+    assert_eq!(b"5\n3\nfalse\n", &output.borrow()[..]);
+
+    // '??' binds tighter than 'or' but looser than 'and'.
+    let (result, globals) = run_source("var a = false ?? 1 or 2; var b = 1 ?? nil and 2; var c = nil ?? nil ?? 3;");
+    assert!(result.is_ok());
+    assert_eq!(Some(&Value::Number(2.0)), globals.get("a"));
+    assert_eq!(Some(&Value::Number(1.0)), globals.get("b"));
+    assert_eq!(Some(&Value::Number(3.0)), globals.get("c"));
+
+    let (result, _) = run_source("nil ?? boom();");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::UndefinedVariable(_))));
+}
+
+#[test]
+fn runtime_strings_share_compiled_constants() {
+    let mut vm = VM::new(Vec::new(), FixedVec::new());
+    assert!(vm.evaluate("var a = \"ab\"; var b = \"a\" + \"b\";").is_ok());
+
+    let string = |name: &str| match vm.globals.get(name) {
+        Some(Value::Object(obj)) => match &**obj {
+            Object::String(string) => string.clone(),
+            other => panic!("Expected {} to be a string, but found {:?}", name, other),
+        },
+        other => panic!("Expected {} to be a string, but found {:?}", name, other),
+    };
+    assert!(Rc::ptr_eq(&string("a"), &string("b")));
+}
+
+#[test]
+fn concatenation_allocates_through_the_heap() {
+    let mut vm = VM::new(Vec::new(), FixedVec::new());
+    assert!(vm.evaluate("var a = \"x\" + \"y\"; var b = \"x\" + \"y\";").is_ok());
+
+    // The heap hands back the object it already has for an equal string.
+    match (vm.globals.get("a"), vm.globals.get("b")) {
+        (Some(Value::Object(a)), Some(Value::Object(b))) => assert!(Rc::ptr_eq(a, b)),
+        other => panic!("Expected two strings, but found {:?}", other),
+    }
+}
+
+#[test]
+fn running_out_of_iterations_is_not_a_success() {
+    let options = VMOptions { quiet: true, ..Default::default() };
+    let mut vm = VM::new(Vec::new(), FixedVec::new()).with_options(options);
+    assert_eq!(ExitCode::from(124), vm.interpret("while (true) ;"));
+}
+
+#[test]
+fn strings_nothing_refers_to_are_collected_as_the_heap_grows() {
+    let mut vm = VM::new(Vec::new(), FixedVec::new());
+    let program = "var s = \"\"; for (var i = 0; i < 3000; i = i + 1) s = s + \"x\";";
+    assert!(vm.evaluate(program).is_ok());
+    assert_eq!(Some(3000), vm.global("s").and_then(Value::as_string).map(str::len));
+    // Only the last string is still reachable, so the heap never holds much more
+    // than the threshold it is collected at.
+    assert!(vm.runtime_heap.len() <= GC_MIN_OBJECTS, "{} objects survived", vm.runtime_heap.len());
+}
+
+#[test]
+fn cached_global_reads_see_redefinitions() {
+    let mut vm = VM::new(Vec::new(), FixedVec::new());
+    assert!(vm.evaluate("var g = 1; fun read() { return g; } var first = read(); var again = read();").is_ok());
+    assert_eq!(Some(&Value::Number(1.0)), vm.global("again"));
+    assert!(vm.global_cache.iter().flatten().count() > 0, "the reads to have been cached");
+
+    assert!(vm.evaluate("var g = 2; var second = read();").is_ok());
+    assert_eq!(Some(&Value::Number(2.0)), vm.global("second"));
+    assert!(vm.evaluate("g = 3; var third = read();").is_ok());
+    assert_eq!(Some(&Value::Number(3.0)), vm.global("third"));
+
+    // A removed variable that is defined again gets a new slot, which the cache has to miss.
+    vm.globals.remove("g");
+    let errs = vm.evaluate("read();").expect_err("g to be undefined");
+    assert!(matches!(&errs[..], [LoxError::Run(VMErr::RuntimeErr(err))] if matches!(err.kind(), RunTimeErrKind::UndefinedVariable(_))));
+    assert!(vm.evaluate("var g = 4; var fourth = read();").is_ok());
+    assert_eq!(Some(&Value::Number(4.0)), vm.global("fourth"));
+}
+
+/// Not run by default. `cargo test --release global_reads -- --ignored --nocapture` prints the timing.
+#[test]
+#[ignore]
+fn benchmark_global_reads() {
+    let program = "
+        var g = 1;
+        fun f() {
+            var sum = 0;
+            for (var i = 0; i < 100000; i = i + 1) sum = sum + g + g + g + g + g + g + g + g + g + g;
+            return sum;
+        }
+        var total = f();
+    ";
+    let Program { code, values, .. } = crate::compiler::compile(program).expect("program to compile");
+    let mut vm = VM::new(Vec::new(), FixedVec::new());
+    vm.load(code, values).expect("program to load");
+    // A million reads take more instructions than run allows.
+    let mut iterations = usize::MAX;
+    let start = std::time::Instant::now();
+    vm.execute(&mut iterations).expect("program to run");
+    println!("1000000 global reads in {:?}", start.elapsed());
+    assert_eq!(Some(&Value::Number(1_000_000.0)), vm.global("total"));
+}
+
+#[test]
+fn strings_compare_lexicographically() {
+    let (result, globals) = run_source("
+        var less = \"apple\" < \"banana\";
+        var greater = \"apple\" > \"banana\";
+        var prefix = \"app\" < \"apple\";
+        var lessEqual = \"apple\" <= \"apple\";
+        var greaterEqual = \"banana\" >= \"apple\";
+        var notGreaterEqual = \"apple\" >= \"banana\";
+    ");
+    assert!(result.is_ok());
+    for (name, expected) in [
+        ("less", true),
+        ("greater", false),
+        ("prefix", true),
+        ("lessEqual", true),
+        ("greaterEqual", true),
+        ("notGreaterEqual", false),
+    ] {
+        assert!(matches!(globals.get(name), Some(Value::Boolean(actual)) if *actual == expected), "{}", name);
+    }
+
+    for source in ["\"a\" < 1;", "1 >= \"a\";", "nil <= nil;"] {
+        let (result, _) = run_source(source);
+        assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::ComparisonOnNonNumber)), "{}", source);
+    }
+}
+
+#[test]
+fn modulo_takes_the_remainder_of_numbers() {
+    // '%' binds like '*', so this is 1 + (7 % 3) * 2.
+    let (result, globals) = run_source("var a = 1 + 7 % 3 * 2; var b = -7 % 3; var c = 5.5 % 2;");
+    assert!(result.is_ok());
+    assert!(matches!(globals.get("a"), Some(Value::Number(num)) if *num == 3.0));
+    assert!(matches!(globals.get("b"), Some(Value::Number(num)) if *num == -1.0));
+    assert!(matches!(globals.get("c"), Some(Value::Number(num)) if *num == 1.5));
+
+    let (result, _) = run_source("5 % 0;");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::DivisionByZero)));
+    let (result, _) = run_source("\"a\" % 2;");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::ArithmeticOnNonNumber)));
+}
+
+#[test]
+fn values_of_different_types_are_never_equal() {
+    let (result, globals) = run_source("
+        var boolNumber = true == 1;
+        var nilFalse = nil != false;
+        var strings = \"a\" == \"a\";
+        var stringNumber = \"1\" == 1;
+    ");
+    assert!(result.is_ok());
+    assert_eq!(Some(&Value::Boolean(false)), globals.get("boolNumber"));
+    assert_eq!(Some(&Value::Boolean(true)), globals.get("nilFalse"));
+    assert_eq!(Some(&Value::Boolean(true)), globals.get("strings"));
+    assert_eq!(Some(&Value::Boolean(false)), globals.get("stringNumber"));
+}
+
+#[test]
+fn print_writes_to_the_injected_output() {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = VM::new(Vec::new(), FixedVec::new())
+        .with_output(Box::new(SharedBuffer(output.clone())));
+    let exit_code = vm.interpret("print 1 + 2; print \"a\" + \"b\"; print nil;");
+
+    assert_eq!(ExitCode::SUCCESS, exit_code);
+    assert_eq!(b"3\nab\nnil\n", &output.borrow()[..]);
+}
+
+#[test]
+fn printing_a_function_shows_its_name() {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = VM::new(Vec::new(), FixedVec::new())
+        .with_output(Box::new(SharedBuffer(output.clone())));
+    let exit_code = vm.interpret("print clock; fun f() {} print f;");
+
+    assert_eq!(ExitCode::SUCCESS, exit_code);
+    assert_eq!(b"<native fn clock>\n<fn f>\n", &output.borrow()[..]);
+}
+
+#[test]
+fn natives_are_called_through_globals() {
+    let (result, globals) = run_source("var a = clock(); var b = clock();");
+    assert!(result.is_ok());
+    let (Some(Value::Number(a)), Some(Value::Number(b))) = (globals.get("a"), globals.get("b")) else {
+        panic!("Expected clock to return numbers, but found {:?}", globals);
+    };
+    assert!(b >= a);
+
+    let (result, _) = run_source("\"a\"();");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::NotCallable)));
+    let (result, _) = run_source("clock(1);");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(
+        err.kind(),
+        RunTimeErrKind::WrongArgumentCount { min: 0, max: 0, found: 1 }
+    )));
+}
+
+#[test]
+fn functions_run_in_their_own_frames() {
+    let (result, globals) = run_source("
+        fun add(a, b) { return a + b; }
+        fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+        fun nothing() { var a = 1; }
+        var sum = add(1, 2);
+        var fibs = fib(10);
+        var none = nothing();
+        var local;
+        { var x = 10; fun twice(n) { return n * 2; } local = twice(x) + x; }
+    ");
+    assert!(result.is_ok());
+    assert_eq!(Some(&Value::Number(3.0)), globals.get("sum"));
+    assert_eq!(Some(&Value::Number(55.0)), globals.get("fibs"));
+    assert_eq!(Some(&Value::Nil), globals.get("none"));
+    assert_eq!(Some(&Value::Number(30.0)), globals.get("local"));
+    assert_eq!("<fn add>", globals.get("add").expect("add to be defined").to_string());
+
+    let (result, _) = run_source("fun add(a, b) { return a + b; } add(1);");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(
+        err.kind(),
+        RunTimeErrKind::WrongArgumentCount { min: 2, max: 2, found: 1 }
+    )));
+    let (result, _) = run_source("fun forever(n) { return 1 + forever(n + 1); } forever(0);");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::StackOverflow)));
+    // Enough locals in each call fill the value stack before the frames run out.
+    let (result, _) = run_source("fun deep() { var a; var b; var c; var d; var e; var f; deep(); } deep();");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::StackOverflow)));
+}
+
+#[test]
+fn tail_calls_reuse_the_frame() {
+    let depth = FRAMES_MAX * 100;
+    let (result, globals) = run_source(&format!("
+        fun count(n, total) {{
+            var next = n - 1;
+            if (n == 0) return total;
+            return count(next, total + 1);
+        }}
+        fun first(s) {{ return charAt(s, 0); }}
+        var counted = count({}, 0);
+        var letter = first(\"lox\");
+    ", depth));
+    assert!(result.is_ok());
+    assert_eq!(Some(&Value::Number(depth as f64)), globals.get("counted"));
+    assert_eq!(Some("l"), globals.get("letter").and_then(Value::as_string));
+
+    let (result, _) = run_source("fun count(n) { return count(n, 1); } count(1);");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(
+        err.kind(),
+        RunTimeErrKind::WrongArgumentCount { min: 1, max: 1, found: 2 }
+    )));
+    // Inside a try the call keeps its own frame, so the handler still catches its errors.
+    let (result, globals) = run_source("
+        fun fail() { return 1 / 0; }
+        fun guarded() { try { return fail(); } catch (e) { return e; } }
+        var caught = guarded();
+    ");
+    assert!(result.is_ok());
+    assert!(globals.get("caught").and_then(Value::as_string).is_some());
+}
+
+#[test]
+fn sandboxed_programs_can_only_call_pure_natives() {
+    let options = VMOptions { sandbox: true, ..Default::default() };
+    let mut vm = VM::new(Vec::new(), FixedVec::new()).with_options(options);
+    let errs = vm.evaluate("var start = clock();").expect_err("clock to be left out of the sandbox");
+    assert!(matches!(&errs[..], [LoxError::Run(VMErr::RuntimeErr(err))]
+        if matches!(err.kind(), RunTimeErrKind::UndefinedVariable(name) if &**name == "clock")));
+
+    for native in natives::PURE {
+        assert!(vm.global(native.name).is_some(), "{} to be defined", native.name);
+    }
+    // The name is free for the program to use.
+    assert!(vm.evaluate("fun clock() { return 0; } var start = clock();").is_ok());
+    assert_eq!(Some(&Value::Number(0.0)), vm.global("start"));
+
+    let vm = VM::new(Vec::new(), FixedVec::new()).with_options(VMOptions::default());
+    assert!(natives::IMPURE.iter().all(|native| vm.global(native.name).is_some()));
+}
+
+#[test]
+fn approx_equal_tolerates_rounding_errors() {
+    let (result, globals) = run_source("
+        var exact = 0.1 + 0.2 == 0.3;
+        var approx = approxEqual(0.1 + 0.2, 0.3);
+        var large = approxEqual(100000000000000000000, 100000000000000000000 + 100000);
+        var loose = approxEqual(1, 1.5, 0.5);
+        var apart = approxEqual(1, 1.001);
+    ");
+    assert!(result.is_ok());
+    assert_eq!(Some(&Value::Boolean(false)), globals.get("exact"));
+    assert_eq!(Some(&Value::Boolean(true)), globals.get("approx"));
+    assert_eq!(Some(&Value::Boolean(true)), globals.get("large"));
+    assert_eq!(Some(&Value::Boolean(true)), globals.get("loose"));
+    assert_eq!(Some(&Value::Boolean(false)), globals.get("apart"));
+
+    let (result, _) = run_source("approxEqual(\"a\", 1);");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::WrongArgumentType { .. })));
+    let (result, _) = run_source("approxEqual(1);");
+    let Err(VMErr::RuntimeErr(err)) = result else {
+        panic!("Expected one argument to be too few, but found {:?}", result);
+    };
+    assert_eq!("Expected 2 to 3 arguments but got 1.", err.kind().to_string());
+}
+
+#[test]
+fn strings_are_indexed_by_character() {
+    let (result, globals) = run_source("
+        var accented = charAt(\"héllo😀\", 1);
+        var after = charAt(\"héllo😀\", 2);
+        var emoji = codePointAt(\"héllo😀\", 5);
+    ");
+    assert!(result.is_ok());
+    assert_eq!(Some("é"), globals.get("accented").and_then(Value::as_string));
+    assert_eq!(Some("l"), globals.get("after").and_then(Value::as_string));
+    assert_eq!(Some(&Value::Number(0x1F600 as f64)), globals.get("emoji"));
+
+    let (result, _) = run_source("charAt(\"héllo😀\", 6);");
+    let Err(VMErr::RuntimeErr(err)) = result else {
+        panic!("Expected the index to be out of bounds, but found {:?}", result);
+    };
+    assert_eq!("Index 6 is out of bounds for a string of 6 characters.", err.kind().to_string());
+    let (result, _) = run_source("codePointAt(\"abc\", -1);");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::IndexOutOfBounds { .. })));
+    for source in ["codePointAt(\"abc\", 0.5);", "charAt(1, 0);"] {
+        let (result, _) = run_source(source);
+        assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::WrongArgumentType { .. })), "{}", source);
+    }
+
+    // The character is made like any other runtime string, so it shares an equal constant.
+    let mut vm = VM::new(Vec::new(), FixedVec::new());
+    assert!(vm.evaluate("var a = charAt(\"ab\", 1); var b = \"b\";").is_ok());
+    let (Some(a), Some(b)) = (vm.global("a").and_then(Value::as_string), vm.global("b").and_then(Value::as_string)) else {
+        panic!("Expected a and b to be strings");
+    };
+    assert!(std::ptr::eq(a, b));
+}
+
+#[test]
+fn equals_ignore_case_lowercases_both_strings() {
+    let (result, globals) = run_source("
+        var ascii = equalsIgnoreCase(\"Hello, World\", \"hELLO, wORLD\");
+        var accented = equalsIgnoreCase(\"ÉCOLE\", \"école\");
+        var different = equalsIgnoreCase(\"abc\", \"abd\");
+        var longer = equalsIgnoreCase(\"abc\", \"abcd\");
+        var sharpS = equalsIgnoreCase(\"straße\", \"STRASSE\");
+    ");
+    assert!(result.is_ok());
+    assert_eq!(Some(&Value::Boolean(true)), globals.get("ascii"));
+    assert_eq!(Some(&Value::Boolean(true)), globals.get("accented"));
+    assert_eq!(Some(&Value::Boolean(false)), globals.get("different"));
+    assert_eq!(Some(&Value::Boolean(false)), globals.get("longer"));
+    // ß is its own lowercase, so it never matches SS.
+    assert_eq!(Some(&Value::Boolean(false)), globals.get("sharpS"));
+
+    let (result, _) = run_source("equalsIgnoreCase(\"1\", 1);");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::WrongArgumentType { .. })));
+}
+
+#[test]
+fn pad_aligns_numbers_right_and_everything_else_left() {
+    let (result, globals) = run_source("
+        var number = pad(42, 5);
+        var string = pad(\"ab\", 5);
+        var boolean = pad(true, 6);
+        var accented = pad(\"é\", 3);
+        var long = pad(\"abcdef\", 3);
+        var exact = pad(123, 3);
+    ");
+    assert!(result.is_ok());
+    assert_eq!(Some("   42"), globals.get("number").and_then(Value::as_string));
+    assert_eq!(Some("ab   "), globals.get("string").and_then(Value::as_string));
+    assert_eq!(Some("true  "), globals.get("boolean").and_then(Value::as_string));
+    assert_eq!(Some("é  "), globals.get("accented").and_then(Value::as_string));
+    // Values longer than the width aren't cut short.
+    assert_eq!(Some("abcdef"), globals.get("long").and_then(Value::as_string));
+    assert_eq!(Some("123"), globals.get("exact").and_then(Value::as_string));
+
+    for source in ["pad(1, -1);", "pad(1, 2.5);", "pad(1, \"2\");"] {
+        let (result, _) = run_source(source);
+        assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::WrongArgumentType { .. })), "{}", source);
+    }
+}
+
+#[test]
+fn format_fills_placeholders_in_order() {
+    let (result, globals) = run_source("
+        var message = format(\"{} + {} = {}\", 1, 2, 1 + 2);
+        var mixed = format(\"{}: {}, {}\", \"name\", nil, true);
+        var escaped = format(\"{{}} {{{}}} }}{{\", \"x\");
+        var template = \"{}\" + \"!\";
+        var dynamic = format(template, \"hi\");
+        var plain = format(\"no placeholders\");
+    ");
+    assert!(result.is_ok());
+    assert_eq!(Some("1 + 2 = 3"), globals.get("message").and_then(Value::as_string));
+    assert_eq!(Some("name: nil, true"), globals.get("mixed").and_then(Value::as_string));
+    // Doubled braces are written once and aren't placeholders.
+    assert_eq!(Some("{} {x} }{"), globals.get("escaped").and_then(Value::as_string));
+    assert_eq!(Some("hi!"), globals.get("dynamic").and_then(Value::as_string));
+    assert_eq!(Some("no placeholders"), globals.get("plain").and_then(Value::as_string));
+}
+
+#[test]
+fn format_rejects_a_placeholder_count_that_does_not_match() {
+    let (result, _) = run_source("format(\"{} and {}\", 1);");
+    let Err(VMErr::RuntimeErr(err)) = result else {
+        panic!("Expected a runtime error, but found {:?}", result);
+    };
+    assert!(matches!(err.kind(), RunTimeErrKind::FormatArgumentCount { placeholders: 2, arguments: 1 }));
+    assert_eq!("The format template has 2 placeholders but was given 1 arguments.", err.kind().to_string());
+
+    let (result, _) = run_source("format(\"{{}}\", 1);");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::FormatArgumentCount { placeholders: 0, arguments: 1 })));
+
+    for source in ["format(\"{\");", "format(\"a } b\");", "format(1);"] {
+        let (result, _) = run_source(source);
+        assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::WrongArgumentType { .. })), "{}", source);
+    }
+}
+
+#[test]
+fn number_methods_call_the_math_natives() {
+    let (result, globals) = run_source("
+        var floored = (2.9).floor();
+        var negative = (-2).abs();
+        var root = (16).sqrt();
+        var chained = (-2.5).abs().floor();
+        var x = 7.5;
+        var variable = x.floor();
+        var expression = (x - 10).abs();
+    ");
+    assert!(result.is_ok());
+    assert_eq!(Some(&Value::Number(2.0)), globals.get("floored"));
+    assert_eq!(Some(&Value::Number(2.0)), globals.get("negative"));
+    assert_eq!(Some(&Value::Number(4.0)), globals.get("root"));
+    assert_eq!(Some(&Value::Number(2.0)), globals.get("chained"));
+    assert_eq!(Some(&Value::Number(7.0)), globals.get("variable"));
+    assert_eq!(Some(&Value::Number(2.5)), globals.get("expression"));
+
+    // A local with the native's name doesn't change what the method calls.
+    let (result, globals) = run_source("var a; { var floor = 1; a = (2.9).floor(); }");
+    assert!(result.is_ok());
+    assert_eq!(Some(&Value::Number(2.0)), globals.get("a"));
+
+    let (result, _) = run_source("(\"a\").floor();");
+    assert!(matches!(result, Err(VMErr::RuntimeErr(err)) if matches!(err.kind(), RunTimeErrKind::WrongArgumentType { .. })));
+}
+
+#[test]
+fn same_compares_objects_by_identity() {
+    let (result, globals) = run_source("
+        var numbers = same(1, 1);
+        var mixed = same(1, \"1\");
+        var interned = same(\"ab\", \"a\" + \"b\");
+        fun f() {}
+        var first = f;
+        fun f() {}
+        var functions = same(first, f);
+        var itself = same(f, f);
+    ");
+    assert!(result.is_ok());
+    assert_eq!(Some(&Value::Boolean(true)), globals.get("numbers"));
+    assert_eq!(Some(&Value::Boolean(false)), globals.get("mixed"));
+    // Equal strings are interned, so a constant and a concatenation share one object.
+    assert_eq!(Some(&Value::Boolean(true)), globals.get("interned"));
+    // Each declaration makes a new function, even when the bodies match.
+    assert_eq!(Some(&Value::Boolean(false)), globals.get("functions"));
+    assert_eq!(Some(&Value::Boolean(true)), globals.get("itself"));
+}
@@ -0,0 +1,107 @@
+use std::{cell::RefCell, io::Write, rc::Rc};
+
+use rslox::{interpret_and_call, interpret_with_output, parse, CompileErrKind, LoxError, Object, Statement, TokenKind, VMErr, Value};
+
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn interpret(source: &str) -> (Result<(), Vec<LoxError>>, String) {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let result = interpret_with_output(source, Box::new(SharedBuffer(output.clone())));
+    let printed = String::from_utf8(output.borrow().clone()).expect("output to be utf-8");
+    (result, printed)
+}
+
+#[test]
+fn interpret_writes_what_the_program_prints() {
+    let (result, printed) = interpret("var a = \"lox\"; print a + \"!\"; print 1 + 2;");
+    assert!(result.is_ok());
+    assert_eq!("lox!\n3\n", printed);
+}
+
+#[test]
+fn interpret_returns_every_compile_error() {
+    let (result, printed) = interpret("print 1 +; print;");
+    let errs = result.expect_err("the program not to compile");
+    assert_eq!(2, errs.len());
+    assert!(errs.iter().all(|err| matches!(err, LoxError::Compile(_))));
+    assert!(printed.is_empty());
+}
+
+#[test]
+fn interpret_returns_runtime_errors_after_earlier_output() {
+    let (result, printed) = interpret("print 1; print -\"two\";");
+    let errs = result.expect_err("negating a string to fail");
+    assert!(matches!(&errs[..], [LoxError::Run(VMErr::RuntimeErr(_))]));
+    assert!(errs[0].to_string().contains("E0101"), "{}", errs[0]);
+    assert_eq!("1\n", printed);
+}
+
+#[test]
+fn interpret_and_call_returns_what_the_entry_point_returns() {
+    let source = "var greeting = \"hello \"; fun main(name, times) { return greeting + name + \"!\"; }";
+    let name = Value::Object(Rc::new(Object::String("lox".into())));
+    let value = interpret_and_call(source, "main", &[name, Value::Number(2.0)]).expect("main to run");
+    assert_eq!("hello lox!", value.to_string());
+
+    let errs = interpret_and_call(source, "missing", &[]).expect_err("an undefined entry point to fail");
+    assert!(errs[0].to_string().contains("E0107"), "{}", errs[0]);
+    let errs = interpret_and_call(source, "main", &[]).expect_err("main to need its arguments");
+    assert!(errs[0].to_string().contains("E0110"), "{}", errs[0]);
+    let errs = interpret_and_call(source, "greeting", &[]).expect_err("a string to not be callable");
+    assert!(errs[0].to_string().contains("E0109"), "{}", errs[0]);
+}
+
+#[test]
+fn compile_errors_expose_their_kind_and_the_unexpected_token() {
+    let (result, _) = interpret("print 1;\nvar = 2;");
+    let errs = result.expect_err("a var without a name to fail");
+    let LoxError::Compile(err) = &errs[0] else { panic!("expected a compile error, found {}", errs[0]) };
+    assert!(matches!(err.kind(), CompileErrKind::UnexpectedToken(_)));
+    assert_eq!("E0002", err.kind().as_str());
+
+    let unexpected = err.unexpected().expect("the error to be about a token");
+    assert_eq!(TokenKind::Equal, unexpected.actual());
+    assert_eq!(&[TokenKind::Identifier], unexpected.expected());
+    assert_eq!(1, unexpected.location().line());
+    assert_eq!(5, unexpected.location().col());
+    assert_eq!(1, err.location().line());
+
+    let (result, _) = interpret("break;");
+    let errs = result.expect_err("a break outside a loop to fail");
+    let LoxError::Compile(err) = &errs[0] else { panic!("expected a compile error, found {}", errs[0]) };
+    assert!(matches!(err.kind(), CompileErrKind::BreakOutsideLoop));
+    assert!(err.unexpected().is_none());
+}
+
+#[test]
+fn parse_returns_the_programs_statements() {
+    let source = "var a = 1, b; fun add(x, y) { var sum = x + y; return sum; } print add(a, 2); ;";
+    let statements = parse(source).expect("the program to parse");
+    // The lone semicolon is an empty statement, which has no node.
+    assert_eq!(3, statements.len());
+
+    let Statement::Var(declarations) = &statements[0] else { panic!("expected a var statement, found {:?}", statements[0]) };
+    let names: Vec<&str> = declarations.iter().map(|declaration| &source[declaration.name().range()]).collect();
+    assert_eq!(vec!["a", "b"], names);
+    assert!(declarations[0].initializer().is_some());
+    assert!(declarations[1].initializer().is_none());
+
+    let Statement::Function(function) = &statements[1] else { panic!("expected a function, found {:?}", statements[1]) };
+    assert_eq!("add", &source[function.name().range()]);
+    assert_eq!(2, function.params().len());
+    assert_eq!(2, function.body().len());
+    assert!(matches!(statements[2], Statement::Print(_)));
+
+    let errs = parse("print 1 +; var;").expect_err("the program not to parse");
+    assert_eq!(2, errs.len());
+}
@@ -0,0 +1,86 @@
+use std::process::{Command, Output};
+
+fn rslox(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rslox"))
+        .args(args)
+        .output()
+        .expect("the binary to run")
+}
+
+#[test]
+fn eval_runs_the_program_it_is_given() {
+    let output = rslox(&["-e", "var a = 1 + 2; print a;"]);
+    assert_eq!("3\n", String::from_utf8_lossy(&output.stdout));
+    assert_eq!(Some(0), output.status.code());
+
+    let output = rslox(&["--eval", "print 1 +;"]);
+    assert_eq!(Some(65), output.status.code());
+
+    let output = rslox(&["-e", "print -\"one\";"]);
+    assert_eq!(Some(70), output.status.code());
+}
+
+#[test]
+fn eval_exits_like_a_script_that_runs_too_long() {
+    let output = rslox(&["--quiet", "-e", "while (true) ;"]);
+    assert_eq!(Some(124), output.status.code());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn stats_go_to_stderr() {
+    let output = rslox(&["--stats", "-e", "print 1 + 2;"]);
+    assert_eq!("3\n", String::from_utf8_lossy(&output.stdout));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("bytecode bytes    7\n"), "{}", stderr);
+    assert!(stderr.contains("constants         2 of 255\n"), "{}", stderr);
+}
+
+#[test]
+fn print_ends_lines_as_configured() {
+    let program = "print 1; print \"two\";";
+    assert_eq!(b"1\ntwo\n", &rslox(&["-e", program]).stdout[..]);
+    assert_eq!(b"1\ntwo\n", &rslox(&["--line-ending", "lf", "-e", program]).stdout[..]);
+    assert_eq!(b"1\r\ntwo\r\n", &rslox(&["--line-ending", "crlf", "-e", program]).stdout[..]);
+}
+
+#[test]
+fn dividing_by_zero_is_a_runtime_error() {
+    let output = rslox(&["-e", "print 1 / 0;"]);
+    assert_eq!(Some(70), output.status.code());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Error[E0108]: Attempted to divide by zero."), "{}", stdout);
+    assert!(!stdout.contains("inf"), "{}", stdout);
+}
+
+#[test]
+fn dump_disassembles_every_function() {
+    let output = rslox(&["--dump", "-e", "fun a() { return 1; } fun b() { return a(); }"]);
+    assert_eq!(Some(0), output.status.code());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("== script ==\n"), "{}", stdout);
+    assert!(stdout.contains("\n    == fn a ==\n") && stdout.contains("\n    == fn b ==\n"), "{}", stdout);
+    assert!(stdout.contains("TailCall 0"), "{}", stdout);
+}
+
+#[test]
+fn sandbox_leaves_out_natives_that_observe_the_host() {
+    let output = rslox(&["--sandbox", "-e", "print clock();"]);
+    assert_eq!(Some(70), output.status.code());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("E0107"), "{}", stdout);
+
+    let output = rslox(&["--sandbox", "-e", "print charAt(\"lox\", 0);"]);
+    assert_eq!("l\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn dump_honours_implicit_semicolons() {
+    let output = rslox(&["--implicit-semicolons", "--dump", "-e", "print 1\nprint 2"]);
+    assert_eq!(Some(0), output.status.code());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(2, stdout.matches("Print").count(), "{}", stdout);
+
+    let output = rslox(&["--implicit-semicolons", "--dump-hex", "-e", "print 1\nprint 2"]);
+    assert_eq!(Some(0), output.status.code());
+}